@@ -0,0 +1,683 @@
+// Static analysis of a parsed program, without running it: basic blocks,
+// loops, per-region complexity, summary statistics and dataflow over the
+// registers. The dataflow passes assume no timer interrupt, which could run
+// handler code between any two instructions.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::emulator::CycleCosts;
+use crate::parser::{Instruction, Operand, Program};
+
+fn target(instruction: &Instruction) -> Option<&String> {
+    match instruction {
+        Instruction::Jmp(l)
+        | Instruction::Jez(l)
+        | Instruction::Jnz(l)
+        | Instruction::Jgz(l)
+        | Instruction::Jlz(l) => Some(l),
+        _ => None,
+    }
+}
+
+fn is_conditional(instruction: &Instruction) -> bool {
+    target(instruction).is_some() && !matches!(instruction, Instruction::Jmp(_))
+}
+
+// Whether control never falls through to the next line after `instruction`
+// when it is the last of a block
+fn ends_block(instruction: &Instruction) -> bool {
+    target(instruction).is_some() || matches!(instruction, Instruction::Ret | Instruction::Reti)
+}
+
+// The line index of the first instruction executed: the first at or after
+// the `.entry` label, or else in the program
+fn entry(program: &Program) -> Option<usize> {
+    let start = program
+        .entry_label()
+        .and_then(|label| program.labels.get(label))
+        .copied()
+        .unwrap_or(0);
+    next_instruction(program, start)
+}
+
+// The line index of the first instruction at or after `idx`
+fn next_instruction(program: &Program, idx: usize) -> Option<usize> {
+    (idx..program.lines.len()).find(|&i| program.lines[i].instruction.is_some())
+}
+
+// Where control can go after the instruction at line index `idx`: the line
+// index of the next instruction, or `None` where the program stops (falling
+// off the end, `ret`, or a jump to an unknown label)
+#[must_use]
+pub fn successors(program: &Program, idx: usize) -> Vec<Option<usize>> {
+    let Some(instruction) = &program.lines[idx].instruction else {
+        return Vec::new();
+    };
+    let jump = |label: &String| {
+        program
+            .labels
+            .get(label)
+            .and_then(|&target| next_instruction(program, target))
+    };
+    match instruction {
+        Instruction::Jmp(label) => vec![jump(label)],
+        Instruction::Jez(label)
+        | Instruction::Jnz(label)
+        | Instruction::Jgz(label)
+        | Instruction::Jlz(label) => vec![next_instruction(program, idx + 1), jump(label)],
+        Instruction::Ret => vec![None],
+        // Only valid in the interrupt handler, which returns to wherever the
+        // interrupt was taken
+        Instruction::Reti => Vec::new(),
+        _ => vec![next_instruction(program, idx + 1)],
+    }
+}
+
+// Whether `instruction` uses the value of `acc`. `swp` counts, since the
+// value lives on in `bak`, and so does printing it with `dbg` or using it
+// as a `lookup` index.
+fn reads_acc(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Mov(src, _)
+        | Instruction::Out(src)
+        | Instruction::Outc(src)
+        | Instruction::Slp(src) => *src == Operand::Acc,
+        Instruction::Swp
+        | Instruction::Save
+        | Instruction::Add(_)
+        | Instruction::Dbg
+        | Instruction::Lookup(_) => true,
+        _ => target(instruction).is_some(),
+    }
+}
+
+// Line indexes of instructions whose only effect is to write `acc` with a
+// value that is overwritten before anything reads it. The final value counts
+// as read, since it is reported when the program stops, including at an `in`
+// that runs out of input.
+#[must_use]
+pub fn dead_stores(program: &Program) -> Vec<usize> {
+    let lines = &program.lines;
+    let succs: Vec<Vec<Option<usize>>> = (0..lines.len()).map(|i| successors(program, i)).collect();
+    // Whether `acc` is read before being overwritten, from each line onwards
+    let mut live_in = vec![false; lines.len()];
+    let live_out =
+        |live_in: &[bool], idx: usize| succs[idx].iter().any(|s| s.is_none_or(|s| live_in[s]));
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for idx in (0..lines.len()).rev() {
+            let Some(instruction) = &lines[idx].instruction else {
+                continue;
+            };
+            let kills = matches!(instruction, Instruction::Mov(_, Operand::Acc));
+            // An `in` may find the input exhausted and end the program, or
+            // leave it waiting, with the old value of acc as the final one
+            let ends = matches!(instruction, Instruction::In(_));
+            let live = reads_acc(instruction) || ends || (!kills && live_out(&live_in, idx));
+            if live != live_in[idx] {
+                live_in[idx] = live;
+                changed = true;
+            }
+        }
+    }
+    (0..lines.len())
+        .filter(|&idx| match &lines[idx].instruction {
+            Some(Instruction::Mov(src, Operand::Acc)) if *src != Operand::Acc => {
+                !live_out(&live_in, idx)
+            }
+            Some(Instruction::Add(_)) => !live_out(&live_in, idx),
+            _ => false,
+        })
+        .collect()
+}
+
+// Line indexes of `save` instructions that run only when `bak` already holds
+// the value of `acc`, because it was copied there and neither has changed
+#[must_use]
+pub fn redundant_saves(program: &Program) -> Vec<usize> {
+    let lines = &program.lines;
+    // Whether bak is known to equal acc before each line, or `None` if the
+    // line has not been reached yet. Registers may be preset before a run,
+    // so nothing is known at the start.
+    let mut equal_in: Vec<Option<bool>> = vec![None; lines.len()];
+    let Some(entry) = entry(program) else {
+        return Vec::new();
+    };
+    equal_in[entry] = Some(false);
+    let mut work = vec![entry];
+    while let Some(idx) = work.pop() {
+        let (Some(instruction), Some(equal)) = (&lines[idx].instruction, equal_in[idx]) else {
+            continue;
+        };
+        let equal = match instruction {
+            Instruction::Save | Instruction::Mov(Operand::Bak, Operand::Acc) => true,
+            Instruction::Mov(Operand::Acc, Operand::Acc) | Instruction::Add(Operand::Imm(0)) => {
+                equal
+            }
+            Instruction::Mov(_, Operand::Acc)
+            | Instruction::Add(_)
+            | Instruction::In(_)
+            | Instruction::Lookup(_) => false,
+            _ => equal,
+        };
+        for succ in successors(program, idx).into_iter().flatten() {
+            let merged = Some(equal_in[succ].unwrap_or(true) && equal);
+            if merged != equal_in[succ] {
+                equal_in[succ] = merged;
+                work.push(succ);
+            }
+        }
+    }
+    (0..lines.len())
+        .filter(|&idx| {
+            matches!(lines[idx].instruction, Some(Instruction::Save)) && equal_in[idx] == Some(true)
+        })
+        .collect()
+}
+
+// An inclusive range of register values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub lo: i32,
+    pub hi: i32,
+}
+
+impl Interval {
+    pub const FULL: Interval = Interval { lo: -999, hi: 999 };
+
+    fn exactly(value: i32) -> Interval {
+        let value = value.clamp(-999, 999);
+        Interval {
+            lo: value,
+            hi: value,
+        }
+    }
+
+    fn intersect(self, lo: i32, hi: i32) -> Option<Interval> {
+        let (lo, hi) = (self.lo.max(lo), self.hi.min(hi));
+        (lo <= hi).then_some(Interval { lo, hi })
+    }
+
+    fn union(self, other: Interval) -> Interval {
+        Interval {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+}
+
+// The possible values of `acc` and `bak` before an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ranges {
+    pub acc: Interval,
+    pub bak: Interval,
+}
+
+impl Ranges {
+    fn value(self, operand: Operand) -> Interval {
+        match operand {
+            Operand::Acc => self.acc,
+            Operand::Bak => self.bak,
+            Operand::Clk => Interval { lo: 0, hi: 999 },
+            Operand::Imm(value) => Interval::exactly(value),
+        }
+    }
+
+    fn union(self, other: Ranges) -> Ranges {
+        Ranges {
+            acc: self.acc.union(other.acc),
+            bak: self.bak.union(other.bak),
+        }
+    }
+}
+
+// For a conditional jump, the values of `acc` for which it is taken, and
+// those for which it falls through
+fn split(instruction: &Instruction, acc: Interval) -> (Option<Interval>, Option<Interval>) {
+    let except_zero = |acc: Interval| match (acc.lo, acc.hi) {
+        (0, 0) => None,
+        (0, hi) => Some(Interval { lo: 1, hi }),
+        (lo, 0) => Some(Interval { lo, hi: -1 }),
+        _ => Some(acc),
+    };
+    match instruction {
+        Instruction::Jez(_) => (acc.intersect(0, 0), except_zero(acc)),
+        Instruction::Jnz(_) => (except_zero(acc), acc.intersect(0, 0)),
+        Instruction::Jgz(_) => (acc.intersect(1, 999), acc.intersect(-999, 0)),
+        Instruction::Jlz(_) => (acc.intersect(-999, -1), acc.intersect(0, 999)),
+        _ => (None, Some(acc)),
+    }
+}
+
+// Visits to a line that widen its ranges before they are widened to the
+// full range, so loops reach a fixed point quickly
+const WIDEN_AFTER: usize = 3;
+
+// The possible register values before each line, or `None` for lines that
+// cannot be reached. Registers may be preset before a run, so anything is
+// possible at the start.
+pub fn value_ranges(program: &Program) -> Vec<Option<Ranges>> {
+    let lines = &program.lines;
+    let mut ranges: Vec<Option<Ranges>> = vec![None; lines.len()];
+    let mut visits = vec![0; lines.len()];
+    let Some(entry) = entry(program) else {
+        return ranges;
+    };
+    ranges[entry] = Some(Ranges {
+        acc: Interval::FULL,
+        bak: Interval::FULL,
+    });
+    let mut work = vec![entry];
+    while let Some(idx) = work.pop() {
+        let (Some(instruction), Some(state)) = (&lines[idx].instruction, ranges[idx]) else {
+            continue;
+        };
+        let mut next = state;
+        let mut edges = Vec::new();
+        let succs = successors(program, idx);
+        match instruction {
+            Instruction::Mov(src, Operand::Acc) => next.acc = state.value(*src),
+            Instruction::In(_) => next.acc = Interval::FULL,
+            Instruction::Lookup(name) => {
+                next.acc = match program.table(name) {
+                    Some(values) => values
+                        .iter()
+                        .map(|&value| Interval::exactly(value))
+                        .reduce(Interval::union)
+                        .unwrap_or(Interval::FULL),
+                    None => continue,
+                };
+            }
+            // Writing anywhere else is a runtime error
+            Instruction::Mov(..) => continue,
+            Instruction::Swp => (next.acc, next.bak) = (state.bak, state.acc),
+            Instruction::Save => next.bak = state.acc,
+            Instruction::Add(src) => {
+                let src = state.value(*src);
+                next.acc = Interval {
+                    lo: (state.acc.lo + src.lo).clamp(-999, 999),
+                    hi: (state.acc.hi + src.hi).clamp(-999, 999),
+                };
+            }
+            Instruction::Jez(_)
+            | Instruction::Jnz(_)
+            | Instruction::Jgz(_)
+            | Instruction::Jlz(_) => {
+                let (taken, fallthrough) = split(instruction, state.acc);
+                if let (Some(acc), Some(Some(succ))) = (fallthrough, succs.first()) {
+                    edges.push((*succ, Ranges { acc, ..state }));
+                }
+                if let (Some(acc), Some(Some(succ))) = (taken, succs.get(1)) {
+                    edges.push((*succ, Ranges { acc, ..state }));
+                }
+            }
+            _ => {}
+        }
+        if !is_conditional(instruction) {
+            edges.extend(succs.into_iter().flatten().map(|succ| (succ, next)));
+        }
+        for (succ, incoming) in edges {
+            let merged = match ranges[succ] {
+                Some(old) => {
+                    let mut merged = old.union(incoming);
+                    if merged != old {
+                        visits[succ] += 1;
+                        if visits[succ] > WIDEN_AFTER {
+                            let widen = |old: Interval, new: Interval| Interval {
+                                lo: if new.lo < old.lo { -999 } else { old.lo },
+                                hi: if new.hi > old.hi { 999 } else { old.hi },
+                            };
+                            merged = Ranges {
+                                acc: widen(old.acc, merged.acc),
+                                bak: widen(old.bak, merged.bak),
+                            };
+                        }
+                    }
+                    merged
+                }
+                None => incoming,
+            };
+            if ranges[succ] != Some(merged) {
+                ranges[succ] = Some(merged);
+                work.push(succ);
+            }
+        }
+    }
+    ranges
+}
+
+// Something the value ranges prove about an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Certainty {
+    // An `add` whose result is always outside -999..999
+    AlwaysClamps,
+    // A conditional jump that is always taken
+    AlwaysTaken,
+    // A conditional jump that is never taken
+    NeverTaken,
+}
+
+// Instructions whose behavior is the same on every run, by line index
+#[must_use]
+pub fn certainties(program: &Program) -> Vec<(usize, Certainty)> {
+    let ranges = value_ranges(program);
+    let mut found = Vec::new();
+    for (idx, line) in program.lines.iter().enumerate() {
+        let (Some(instruction), Some(state)) = (&line.instruction, ranges[idx]) else {
+            continue;
+        };
+        if let Instruction::Add(src) = instruction {
+            let src = state.value(*src);
+            if state.acc.lo + src.lo > 999 || state.acc.hi + src.hi < -999 {
+                found.push((idx, Certainty::AlwaysClamps));
+            }
+        } else if is_conditional(instruction) {
+            match split(instruction, state.acc) {
+                (_, None) => found.push((idx, Certainty::AlwaysTaken)),
+                (None, _) => found.push((idx, Certainty::NeverTaken)),
+                _ => {}
+            }
+        }
+    }
+    found
+}
+
+// The basic blocks of `program` as ranges of line indexes. A block starts at
+// the first instruction, at a label and after any jump, and each range ends
+// after its last instruction.
+#[must_use]
+pub fn blocks(program: &Program) -> Vec<Range<usize>> {
+    let mut blocks = Vec::new();
+    // The start of the open block and its last instruction so far
+    let mut start = None;
+    let mut last = None;
+    for (idx, line) in program.lines.iter().enumerate() {
+        if line.label.is_some() {
+            if let (Some(s), Some(l)) = (start, last) {
+                blocks.push(s..l + 1);
+                last = None;
+                start = None;
+            }
+            // Consecutive labels all introduce the same block
+            start = start.or(Some(idx));
+        }
+        let Some(instruction) = &line.instruction else {
+            continue;
+        };
+        let s = *start.get_or_insert(idx);
+        last = Some(idx);
+        if ends_block(instruction) {
+            blocks.push(s..idx + 1);
+            start = None;
+            last = None;
+        }
+    }
+    if let (Some(s), Some(l)) = (start, last) {
+        blocks.push(s..l + 1);
+    }
+    blocks
+}
+
+// A jump back to a label at or before it. `lines` runs from the label to the
+// jump and `cycles`, the worst case for one iteration, is the cost of
+// executing every instruction in it once; inner loops are not multiplied in.
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub label: String,
+    pub lines: Range<usize>,
+    pub cycles: u64,
+}
+
+// The loops of `program`, one per backward jump, in source order
+pub fn loops(program: &Program, costs: &CycleCosts) -> Vec<Loop> {
+    let mut loops = Vec::new();
+    for (idx, line) in program.lines.iter().enumerate() {
+        let Some(label) = line.instruction.as_ref().and_then(target) else {
+            continue;
+        };
+        let Some(&start) = program.labels.get(label).filter(|&&start| start <= idx) else {
+            continue;
+        };
+        let cycles = program.lines[start..=idx]
+            .iter()
+            .filter_map(|line| line.instruction.as_ref())
+            .map(|instruction| costs.cost(instruction))
+            .sum();
+        loops.push(Loop {
+            label: label.clone(),
+            lines: start..idx + 1,
+            cycles,
+        });
+    }
+    loops
+}
+
+// The lines from a label up to the next one, or from the start of the
+// program to the first label (`label` is then `None`). `complexity` is the
+// cyclomatic complexity: one more than the number of conditional jumps.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub label: Option<String>,
+    pub lines: Range<usize>,
+    pub complexity: usize,
+}
+
+impl Region {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        self.label.as_deref().unwrap_or("(start)")
+    }
+}
+
+// The labeled regions of `program` in source order. Code before the first
+// label forms a region only if it has instructions.
+#[must_use]
+pub fn regions(program: &Program) -> Vec<Region> {
+    let mut starts: Vec<(usize, Option<&String>)> = program
+        .lines
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, line)| Some((idx, Some(&line.label.as_ref()?.0))))
+        .collect();
+    if starts.first().is_none_or(|&(idx, _)| idx > 0) {
+        starts.insert(0, (0, None));
+    }
+    let mut regions = Vec::new();
+    for (i, &(start, label)) in starts.iter().enumerate() {
+        let end = starts
+            .get(i + 1)
+            .map_or(program.lines.len(), |&(idx, _)| idx);
+        let instructions: Vec<&Instruction> = program.lines[start..end]
+            .iter()
+            .filter_map(|line| line.instruction.as_ref())
+            .collect();
+        if label.is_none() && instructions.is_empty() {
+            continue;
+        }
+        let decisions = instructions.iter().filter(|i| is_conditional(i)).count();
+        regions.push(Region {
+            label: label.cloned(),
+            lines: start..end,
+            complexity: decisions + 1,
+        });
+    }
+    regions
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub instructions: usize,
+    // Instruction count by mnemonic
+    pub histogram: BTreeMap<&'static str, usize>,
+    pub labels: usize,
+    // Jump instructions, conditional or not
+    pub jumps: usize,
+    // The range of the block with the most instructions, and that count
+    pub longest_block: Option<(Range<usize>, usize)>,
+    pub loops: Vec<Loop>,
+    pub regions: Vec<Region>,
+}
+
+impl Stats {
+    #[must_use]
+    pub fn new(program: &Program, costs: &CycleCosts) -> Stats {
+        let instructions = || {
+            program
+                .lines
+                .iter()
+                .filter_map(|line| line.instruction.as_ref())
+        };
+        let mut histogram = BTreeMap::new();
+        for instruction in instructions() {
+            *histogram.entry(instruction.mnemonic()).or_insert(0) += 1;
+        }
+        let longest_block = blocks(program)
+            .into_iter()
+            .map(|block| {
+                let count = program.lines[block.clone()]
+                    .iter()
+                    .filter(|line| line.instruction.is_some())
+                    .count();
+                (block, count)
+            })
+            .max_by_key(|(block, count)| (*count, std::cmp::Reverse(block.start)));
+        Stats {
+            instructions: instructions().count(),
+            histogram,
+            labels: program.labels.len(),
+            jumps: instructions().filter(|i| target(i).is_some()).count(),
+            longest_block,
+            loops: loops(program, costs),
+            regions: regions(program),
+        }
+    }
+
+    // The fraction of instructions that are jumps. Instruction counts are
+    // far below 2^52, where f64 starts rounding.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn jump_density(&self) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            self.jumps as f64 / self.instructions as f64
+        }
+    }
+
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut out = format!("instructions: {}\n", self.instructions);
+        for (mnemonic, count) in &self.histogram {
+            let _ = writeln!(out, "  {mnemonic:<5} {count}");
+        }
+        let _ = writeln!(out, "labels: {}", self.labels);
+        let _ = writeln!(
+            out,
+            "jumps: {} ({:.1}% of instructions)",
+            self.jumps,
+            self.jump_density() * 100.0
+        );
+        if let Some((block, count)) = &self.longest_block {
+            let _ = writeln!(
+                out,
+                "longest basic block: {} instructions (lines {}-{})",
+                count,
+                block.start + 1,
+                block.end
+            );
+        }
+        let _ = writeln!(out, "loops: {}", self.loops.len());
+        for lp in &self.loops {
+            let _ = writeln!(
+                out,
+                "  {} (lines {}-{}): {} cycles per iteration, worst case",
+                lp.label,
+                lp.lines.start + 1,
+                lp.lines.end,
+                lp.cycles
+            );
+        }
+        out.push_str("complexity by region:\n");
+        for region in &self.regions {
+            let _ = writeln!(
+                out,
+                "  {} (lines {}-{}): {}",
+                region.name(),
+                region.lines.start + 1,
+                region.lines.end,
+                region.complexity
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn program(source: &[&str]) -> Program {
+        parser::parse(source).unwrap()
+    }
+
+    #[test]
+    fn finds_stores_overwritten_before_a_read() {
+        let program = program(&["mov 1, acc", "mov 2, acc", "add 3", "mov 4, acc", "out acc"]);
+        assert_eq!(dead_stores(&program), [0, 2]);
+    }
+
+    #[test]
+    fn final_and_branch_read_values_are_live() {
+        let program = program(&["mov 1, acc", "jez skip", "mov 2, acc", "skip: add 1"]);
+        assert_eq!(dead_stores(&program), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn a_store_before_in_stays_live() {
+        // The `in` may end the program with acc still 5
+        let program = program(&["mov 5, acc", "in acc", "out acc"]);
+        assert_eq!(dead_stores(&program), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn finds_a_save_of_a_value_already_in_bak() {
+        let program = program(&["mov 3, acc", "save", "swp", "swp", "save", "add 1", "save"]);
+        assert_eq!(redundant_saves(&program), [4]);
+    }
+
+    #[test]
+    fn a_save_reached_with_bak_unknown_is_needed() {
+        let program = program(&["top: save", "add 1", "jmp top"]);
+        assert_eq!(redundant_saves(&program), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn proves_clamping_adds_and_fixed_jumps() {
+        let program = program(&[
+            "mov 500, acc",
+            "add 600",
+            "jlz neg",
+            "jgz done",
+            "neg: ret",
+            "done: ret",
+        ]);
+        assert_eq!(
+            certainties(&program),
+            [
+                (1, Certainty::AlwaysClamps),
+                (2, Certainty::NeverTaken),
+                (3, Certainty::AlwaysTaken),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_input_proves_nothing() {
+        let program = program(&["in acc", "add 5", "jgz pos", "pos: ret"]);
+        assert_eq!(certainties(&program), []);
+    }
+}
@@ -0,0 +1,152 @@
+// Heat-map source listings, like `perf annotate`. `Annotate` counts how often
+// each line executes and the cycles spent on it, and when the program halts
+// writes the source with those numbers beside each line and a bar scaled
+// against the hottest line, followed by the line coverage.
+
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::emulator::RuntimeError;
+use crate::observer::Observer;
+use crate::parser::Program;
+
+// Width of the heat bar of the hottest line, in characters
+const BAR_WIDTH: usize = 8;
+// Partial blocks for the fractional end of a bar, in eighths
+const EIGHTHS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+// 256-color palette entries from cold to hot
+const HEAT: [u8; 6] = [33, 44, 48, 226, 208, 196];
+
+// Observer that collects per-line counts and writes the listing to `out` on
+// halt, colored with terminal escapes if `color` is set
+pub struct Annotate<W: Write + Send> {
+    out: W,
+    color: bool,
+    source: Vec<String>,
+    // Whether each line holds an instruction
+    instructions: Vec<bool>,
+    counts: Vec<u64>,
+    cycles: Vec<u64>,
+    // The line executing and the cycle it started at
+    current: Option<(usize, u64)>,
+}
+
+impl<W: Write + Send> Annotate<W> {
+    // `program` is the parse of `source`
+    pub fn new<S: AsRef<str>>(program: &Program, source: &[S], color: bool, out: W) -> Self {
+        Annotate {
+            out,
+            color,
+            source: source.iter().map(|l| l.as_ref().to_string()).collect(),
+            instructions: program
+                .lines
+                .iter()
+                .map(|l| l.instruction.is_some())
+                .collect(),
+            counts: vec![0; source.len()],
+            cycles: vec![0; source.len()],
+            current: None,
+        }
+    }
+
+    fn finish_line(&mut self, end: u64) {
+        if let Some((pc, start)) = self.current.take()
+            && let Some(cycles) = self.cycles.get_mut(pc)
+        {
+            *cycles += end - start;
+        }
+    }
+
+    // A bar `BAR_WIDTH` characters wide, filled in proportion to `value`
+    // out of `max`
+    fn bar(&self, value: u64, max: u64) -> String {
+        // `value / max` of `steps`, rounded to the nearest step or down
+        let scale = |steps: usize, half: u64| {
+            usize::try_from((value * steps as u64 + half) / max).unwrap_or(steps)
+        };
+        let eighths = scale(BAR_WIDTH * 8, max / 2);
+        let mut bar = "█".repeat(eighths / 8);
+        if !eighths.is_multiple_of(8) {
+            bar.push(EIGHTHS[eighths % 8]);
+        }
+        let bar = format!("{bar:<BAR_WIDTH$}");
+        if !self.color || eighths == 0 {
+            return bar;
+        }
+        let heat = HEAT[scale(HEAT.len(), 0).min(HEAT.len() - 1)];
+        format!("\x1b[38;5;{heat}m{bar}\x1b[0m")
+    }
+
+    fn render(&self) -> String {
+        let hottest = self.cycles.iter().copied().max().unwrap_or(0).max(1);
+        let gutter = self.source.len().to_string().len().max("line".len());
+        let mut out = format!(
+            "{:>10} {:>10}  {:<bar$}  {:>gutter$} | source\n",
+            "count",
+            "cycles",
+            "heat",
+            "line",
+            bar = BAR_WIDTH,
+            gutter = gutter
+        );
+        for (idx, text) in self.source.iter().enumerate() {
+            let (count, cycles, bar) = if self.instructions.get(idx) == Some(&true) {
+                (
+                    self.counts[idx].to_string(),
+                    self.cycles[idx].to_string(),
+                    self.bar(self.cycles[idx], hottest),
+                )
+            } else {
+                ("-".to_string(), "-".to_string(), " ".repeat(BAR_WIDTH))
+            };
+            let _ = writeln!(
+                out,
+                "{:>10} {:>10}  {}  {:>gutter$} | {}",
+                count,
+                cycles,
+                bar,
+                idx + 1,
+                text,
+                gutter = gutter
+            );
+        }
+        let total = self.instructions.iter().filter(|&&i| i).count();
+        let executed = (0..self.source.len())
+            .filter(|&idx| self.instructions[idx] && self.counts[idx] > 0)
+            .count();
+        // Instruction counts are far below 2^52, where f64 starts rounding
+        #[allow(clippy::cast_precision_loss)]
+        let percent = if total == 0 {
+            100.0
+        } else {
+            executed as f64 / total as f64 * 100.0
+        };
+        let _ = writeln!(
+            out,
+            "{executed} of {total} instructions executed ({percent:.1}%)"
+        );
+        out
+    }
+}
+
+impl<W: Write + Send> Observer for Annotate<W> {
+    fn step(&mut self, pc: usize, cycle: u64) {
+        self.finish_line(cycle);
+        if let Some(count) = self.counts.get_mut(pc) {
+            *count += 1;
+        }
+        self.current = Some((pc, cycle));
+    }
+
+    fn halt(&mut self, cycles: u64, _error: Option<&RuntimeError>) {
+        self.finish_line(cycles);
+        let listing = self.render();
+        if let Err(e) = self
+            .out
+            .write_all(listing.as_bytes())
+            .and_then(|()| self.out.flush())
+        {
+            eprintln!("Could not write listing: {e}");
+        }
+    }
+}
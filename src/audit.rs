@@ -0,0 +1,89 @@
+// Append-only audit log of the runs a daemon performs, one compact JSON
+// object per line: when the run ended, the session, a hash of the program,
+// the registers it started from, the cycles it took and how it ended. Once
+// the file would grow past `max_bytes` it is renamed to `<path>.1`,
+// replacing any earlier one, and a new file is started.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache;
+use crate::json;
+
+// One run: a `step` or `run` request against a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub session: &'a str,
+    // The loaded program's source, if any; only its hash is logged
+    pub source: Option<&'a str>,
+    // acc and bak when the run started, as sessions have no input stream
+    pub acc: i32,
+    pub bak: i32,
+    pub cycles: u64,
+    // The response sent back, such as `halted` or the error message
+    pub result: &'a str,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    file: File,
+    len: u64,
+}
+
+impl AuditLog {
+    // Append to the log at `path`, rotating it once it would exceed
+    // `max_bytes`, if given
+    pub fn open(path: &Path, max_bytes: Option<u64>) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            max_bytes,
+            file,
+            len,
+        })
+    }
+
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let program = record.source.map_or("null".to_string(), |source| {
+            format!("\"{:016x}\"", cache::hash(source.as_bytes()))
+        });
+        let line = format!(
+            "{{\"time\":{},\"session\":{},\"program\":{},\"acc\":{},\"bak\":{},\"cycles\":{},\"result\":{}}}\n",
+            time,
+            json::string(record.session),
+            program,
+            record.acc,
+            record.bak,
+            record.cycles,
+            json::string(record.result)
+        );
+        if let Some(max) = self.max_bytes
+            && self.len > 0
+            && self.len + line.len() as u64 > max
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,425 @@
+// On-disk cache of parsed programs, keyed by a hash of the source text.
+// Entries live under $XDG_CACHE_HOME/tis31337 (or ~/.cache/tis31337) and
+// are rebuilt whenever the source, or the version of this tool, changes.
+// Each entry records the hash and length of the source it was built from,
+// and is only used if both still match. Entries are read into memory rather
+// than memory-mapped, as mapping a file needs either a dependency or
+// `unsafe`, and reading an entry is already far cheaper than parsing.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::parser::{Instruction, Line, Operand, Program, Span, Table};
+
+const MAGIC: &[u8; 4] = b"TISC";
+const FORMAT_VERSION: u32 = 4;
+
+// FNV-1a, chosen because its output is stable across builds and platforms
+pub(crate) fn hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in data {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("tis31337"))
+}
+
+// What an entry is built from: the hash of this tool's version and the
+// source text, and the length of that text
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Key {
+    hash: u64,
+    len: u64,
+}
+
+impl Key {
+    fn of<S: AsRef<str>>(lines: &[S]) -> Key {
+        let mut text = env!("CARGO_PKG_VERSION").as_bytes().to_vec();
+        for line in lines {
+            text.push(b'\n');
+            text.extend_from_slice(line.as_ref().as_bytes());
+        }
+        Key {
+            hash: hash(&text),
+            len: text.len() as u64,
+        }
+    }
+
+    fn path(self) -> Option<PathBuf> {
+        Some(cache_dir()?.join(format!("{:016x}.tisc", self.hash)))
+    }
+}
+
+// The cached parse of `lines`, if there is a valid entry for it
+pub fn load<S: AsRef<str>>(lines: &[S]) -> Option<Program> {
+    let key = Key::of(lines);
+    decode(&fs::read(key.path()?).ok()?, key)
+}
+
+// Store the parse of `lines`. Failures are ignored: the cache is only an
+// optimization.
+pub fn store<S: AsRef<str>>(lines: &[S], program: &Program) {
+    let key = Key::of(lines);
+    let Some(path) = key.path() else {
+        return;
+    };
+    let data = encode(program, key);
+    if let Some(dir) = path.parent()
+        && fs::create_dir_all(dir).is_ok()
+    {
+        let tmp = path.with_extension("tmp");
+        if fs::write(&tmp, &data).is_ok() {
+            let _ = fs::rename(&tmp, &path);
+        }
+    }
+}
+
+fn encode(program: &Program, key: Key) -> Vec<u8> {
+    let mut out = Writer(Vec::new());
+    out.0.extend_from_slice(MAGIC);
+    out.u32(FORMAT_VERSION);
+    out.0.extend_from_slice(&key.hash.to_le_bytes());
+    out.0.extend_from_slice(&key.len.to_le_bytes());
+    out.program(program);
+    out.0
+}
+
+// The program in `data`, if it is a complete entry built from `key`
+fn decode(data: &[u8], key: Key) -> Option<Program> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.bytes(4)? != MAGIC
+        || reader.u32()? != FORMAT_VERSION
+        || reader.u64()? != key.hash
+        || reader.u64()? != key.len
+    {
+        return None;
+    }
+    let program = reader.program()?;
+    (reader.pos == data.len()).then_some(program)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.u32(u32::try_from(value).unwrap_or(u32::MAX));
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn span(&mut self, span: Span) {
+        self.usize(span.line);
+        self.usize(span.col);
+        self.usize(span.len);
+    }
+
+    fn operand(&mut self, operand: Operand) {
+        match operand {
+            Operand::Acc => self.0.push(0),
+            Operand::Bak => self.0.push(1),
+            Operand::Clk => self.0.push(3),
+            Operand::Imm(value) => {
+                self.0.push(2);
+                self.0.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Mov(src, dst) => {
+                self.0.push(0);
+                self.operand(*src);
+                self.operand(*dst);
+            }
+            Instruction::Swp => self.0.push(1),
+            Instruction::Save => self.0.push(2),
+            Instruction::Add(src) => {
+                self.0.push(3);
+                self.operand(*src);
+            }
+            Instruction::Jmp(label) => {
+                self.0.push(4);
+                self.str(label);
+            }
+            Instruction::Jez(label) => {
+                self.0.push(5);
+                self.str(label);
+            }
+            Instruction::Jnz(label) => {
+                self.0.push(6);
+                self.str(label);
+            }
+            Instruction::Jgz(label) => {
+                self.0.push(7);
+                self.str(label);
+            }
+            Instruction::Jlz(label) => {
+                self.0.push(8);
+                self.str(label);
+            }
+            Instruction::Ret => self.0.push(9),
+            Instruction::Reti => self.0.push(10),
+            Instruction::Dbg => self.0.push(11),
+            Instruction::Out(src) => {
+                self.0.push(12);
+                self.operand(*src);
+            }
+            Instruction::In(dst) => {
+                self.0.push(13);
+                self.operand(*dst);
+            }
+            Instruction::Outc(src) => {
+                self.0.push(14);
+                self.operand(*src);
+            }
+            Instruction::Slp(src) => {
+                self.0.push(15);
+                self.operand(*src);
+            }
+            Instruction::Lookup(table) => {
+                self.0.push(16);
+                self.str(table);
+            }
+        }
+    }
+
+    fn program(&mut self, program: &Program) {
+        self.usize(program.lines.len());
+        for line in &program.lines {
+            let flags = u8::from(line.label.is_some())
+                | u8::from(line.instruction.is_some()) << 1
+                | u8::from(line.comment.is_some()) << 2
+                | u8::from(line.table.is_some()) << 3
+                | u8::from(line.entry.is_some()) << 4;
+            self.0.push(flags);
+            if let Some((name, span)) = &line.label {
+                self.str(name);
+                self.span(*span);
+            }
+            if let (Some(instruction), Some(span)) = (&line.instruction, line.span) {
+                self.instruction(instruction);
+                self.span(span);
+                self.usize(line.operands.len());
+                for &operand in &line.operands {
+                    self.span(operand);
+                }
+            }
+            if let Some(comment) = &line.comment {
+                self.str(comment);
+            }
+            if let Some(table) = &line.table {
+                self.str(&table.name);
+                self.span(table.span);
+                self.usize(table.values.len());
+                for &value in &table.values {
+                    self.0.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            if let Some((label, span)) = &line.entry {
+                self.str(label);
+                self.span(*span);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        usize::try_from(self.u32()?).ok()
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+
+    fn span(&mut self) -> Option<Span> {
+        Some(Span {
+            line: self.usize()?,
+            col: self.usize()?,
+            len: self.usize()?,
+        })
+    }
+
+    fn operand(&mut self) -> Option<Operand> {
+        match self.u8()? {
+            0 => Some(Operand::Acc),
+            1 => Some(Operand::Bak),
+            3 => Some(Operand::Clk),
+            2 => Some(Operand::Imm(i32::from_le_bytes(
+                self.bytes(4)?.try_into().ok()?,
+            ))),
+            _ => None,
+        }
+    }
+
+    fn instruction(&mut self) -> Option<Instruction> {
+        Some(match self.u8()? {
+            0 => Instruction::Mov(self.operand()?, self.operand()?),
+            1 => Instruction::Swp,
+            2 => Instruction::Save,
+            3 => Instruction::Add(self.operand()?),
+            4 => Instruction::Jmp(self.str()?),
+            5 => Instruction::Jez(self.str()?),
+            6 => Instruction::Jnz(self.str()?),
+            7 => Instruction::Jgz(self.str()?),
+            8 => Instruction::Jlz(self.str()?),
+            9 => Instruction::Ret,
+            10 => Instruction::Reti,
+            11 => Instruction::Dbg,
+            12 => Instruction::Out(self.operand()?),
+            13 => Instruction::In(self.operand()?),
+            14 => Instruction::Outc(self.operand()?),
+            15 => Instruction::Slp(self.operand()?),
+            16 => Instruction::Lookup(self.str()?),
+            _ => return None,
+        })
+    }
+
+    fn program(&mut self) -> Option<Program> {
+        let count = self.usize()?;
+        let mut program = Program::default();
+        for idx in 0..count {
+            let flags = self.u8()?;
+            let mut line = Line::default();
+            if flags & 1 != 0 {
+                let name = self.str()?;
+                let span = self.span()?;
+                program.labels.insert(name.clone(), idx);
+                line.label = Some((name, span));
+            }
+            if flags & 2 != 0 {
+                line.instruction = Some(self.instruction()?);
+                line.span = Some(self.span()?);
+                let operands = self.usize()?;
+                for _ in 0..operands {
+                    line.operands.push(self.span()?);
+                }
+            }
+            if flags & 4 != 0 {
+                line.comment = Some(self.str()?);
+            }
+            if flags & 8 != 0 {
+                let name = self.str()?;
+                let span = self.span()?;
+                let count = self.usize()?;
+                let mut values = Vec::new();
+                for _ in 0..count {
+                    values.push(i32::from_le_bytes(self.bytes(4)?.try_into().ok()?));
+                }
+                program.tables.insert(name.clone(), idx);
+                line.table = Some(Table { name, span, values });
+            }
+            if flags & 16 != 0 {
+                program.entry = Some(idx);
+                line.entry = Some((self.str()?, self.span()?));
+            }
+            program.lines.push(line);
+        }
+        Some(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    // Every instruction, every operand kind, and each of the line parts
+    const EVERY_VARIANT: &[&str] = &[
+        "# comment",
+        ".entry start",
+        ".table squares: 1, -4, 9",
+        "start: mov 5, acc",
+        "mov acc, acc",
+        "mov bak, acc",
+        "mov clk, acc",
+        "swp",
+        "sav",
+        "add -999",
+        "jmp start",
+        "jez start",
+        "jnz start",
+        "jgz start",
+        "jlz start",
+        "in acc",
+        "out bak",
+        "outc 65",
+        "slp 1",
+        "lookup squares",
+        "dbg",
+        "reti",
+        "end:",
+        "ret",
+    ];
+
+    #[test]
+    fn round_trips_every_variant() {
+        let program = parser::parse(EVERY_VARIANT).unwrap();
+        let key = Key::of(EVERY_VARIANT);
+        let decoded = decode(&encode(&program, key), key).unwrap();
+        assert_eq!(decoded, program);
+        let mut kinds: Vec<_> = decoded
+            .lines
+            .iter()
+            .filter_map(|l| l.instruction.as_ref().map(std::mem::discriminant))
+            .collect();
+        kinds.dedup();
+        assert_eq!(kinds.len(), parser::MNEMONICS.len());
+    }
+
+    #[test]
+    fn rejects_entries_for_other_sources() {
+        let program = parser::parse(EVERY_VARIANT).unwrap();
+        let key = Key::of(EVERY_VARIANT);
+        let data = encode(&program, key);
+        let other = Key::of(&["ret"]);
+        assert_eq!(decode(&data, other), None);
+        let longer = Key {
+            len: key.len + 1,
+            ..key
+        };
+        assert_eq!(decode(&data, longer), None);
+        assert_eq!(decode(&data[..data.len() - 1], key), None);
+    }
+}
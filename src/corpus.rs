@@ -0,0 +1,668 @@
+// Golden-output regression runner. Every `.tis` program in a directory is
+// run and its outcome compared with the `.expected` file next to it, which
+// `--bless` (re)writes from the current behavior.
+//
+// An outcome is plain text: the load diagnostics, or else an optional trace
+// of one `line <n>: <instruction> -> acc=<a> bak=<b> cycles=<c>` line per
+// executed instruction, then the runtime error if there was one and the
+// final `acc`, `bak`, `cycles` and `instructions`.
+//
+// A failing case earns partial credit on each output stream its `.expected`
+// file has: how many values it got right before the first mismatch, so a
+// program that is nearly working shows how far it gets.
+
+use std::fmt::{self, Write};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::emulator::{Emulator, RunOutcome};
+
+// Programs still running after this many cycles are stopped, so a looping
+// program fails its case instead of hanging the run
+pub const CYCLE_BUDGET: u64 = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Fail { expected: String, actual: String },
+    // There is no `.expected` file yet
+    Missing,
+    // The `.expected` file was written by `--bless`
+    Blessed,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub path: PathBuf,
+    pub status: Status,
+}
+
+impl CaseResult {
+    #[must_use]
+    pub fn failed(&self) -> bool {
+        matches!(self.status, Status::Fail { .. } | Status::Missing)
+    }
+}
+
+// The outcome of loading and running `lines`, as stored in `.expected` files
+pub fn outcome<S: AsRef<str>>(lines: &[S], trace: bool) -> String {
+    let mut emu = Emulator::new();
+    match emu.load_program(lines) {
+        Ok(_) => {}
+        Err(diagnostics) => {
+            return diagnostics.iter().fold(String::new(), |mut out, d| {
+                let _ = writeln!(out, "{}[{}]: {}", d.severity.name(), d.code, d.message);
+                out
+            });
+        }
+    }
+    let mut out = String::new();
+    let result = if trace {
+        let mut result = Ok(RunOutcome::Halted);
+        for state in emu.iter_states() {
+            let state = match state {
+                Ok(state) => state,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let _ = writeln!(
+                out,
+                "line {}: {} -> acc={} bak={} cycles={}",
+                state.pc + 1,
+                state.instruction,
+                state.acc,
+                state.bak,
+                state.cycle
+            );
+            if state.cycle >= CYCLE_BUDGET {
+                result = Ok(RunOutcome::BudgetExhausted);
+                break;
+            }
+        }
+        result
+    } else {
+        emu.run_for(CYCLE_BUDGET)
+    };
+    match result {
+        Ok(RunOutcome::BudgetExhausted) => {
+            let _ = writeln!(out, "still running after {CYCLE_BUDGET} cycles");
+        }
+        Ok(_) => {}
+        Err(e) => {
+            let _ = writeln!(out, "{e}");
+        }
+    }
+    if !emu.output().is_empty() {
+        let values: Vec<String> = emu.output().iter().map(i32::to_string).collect();
+        let _ = writeln!(out, "output: {}", values.join(", "));
+    }
+    if !emu.text_output().is_empty() {
+        let text = String::from_utf8_lossy(emu.text_output());
+        let _ = writeln!(out, "text: {text:?}");
+    }
+    let _ = writeln!(
+        out,
+        "acc: {}\nbak: {}\ncycles: {}\ninstructions: {}",
+        emu.acc, emu.bak, emu.cycles, emu.instructions
+    );
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+// Line diff turning `expected` into `actual`, from their longest common
+// subsequence. Outcomes are short, so the quadratic table is fine.
+#[must_use]
+pub fn diff<'a>(expected: &'a str, actual: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+    // common[i][j]: length of the LCS of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+// The `<field>: <value>` lines of an outcome that failure reports line up
+// side by side, in the order they are shown
+const FIELDS: [&str; 6] = ["output", "text", "acc", "bak", "cycles", "instructions"];
+
+// One field of an expected and an actual outcome, with its value in each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Row<'a> {
+    pub field: &'static str,
+    pub expected: Option<&'a str>,
+    pub actual: Option<&'a str>,
+}
+
+impl Row<'_> {
+    #[must_use]
+    pub fn differs(&self) -> bool {
+        self.expected != self.actual
+    }
+}
+
+fn field<'a>(outcome: &'a str, name: &str) -> Option<&'a str> {
+    outcome
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+}
+
+// The fields either outcome has, for showing expected against actual
+#[must_use]
+pub fn compare<'a>(expected: &'a str, actual: &'a str) -> Vec<Row<'a>> {
+    FIELDS
+        .iter()
+        .map(|&name| Row {
+            field: name,
+            expected: field(expected, name),
+            actual: field(actual, name),
+        })
+        .filter(|row| row.expected.is_some() || row.actual.is_some())
+        .collect()
+}
+
+// The values of one output stream of an outcome: the numbers written by
+// `out` for `output`, the characters written by `outc` for `text`
+fn stream(outcome: &str, name: &str) -> Vec<String> {
+    let Some(value) = field(outcome, name) else {
+        return Vec::new();
+    };
+    if name == "text" {
+        return unquote(value).chars().map(String::from).collect();
+    }
+    value.split(", ").map(String::from).collect()
+}
+
+// The text of a `{:?}`-quoted string, for the escapes it can contain
+fn unquote(quoted: &str) -> String {
+    let inner = quoted
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(quoted);
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('0') => out.push('\0'),
+            Some('u') => {
+                let code: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                out.extend(u32::from_str_radix(&code, 16).ok().and_then(char::from_u32));
+            }
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+// The index of the first value of `stream` that differs between the
+// outcomes, counting a value only one of them wrote
+fn first_mismatch(expected: &str, actual: &str, name: &str) -> Option<usize> {
+    let (expected, actual) = (stream(expected, name), stream(actual, name));
+    expected
+        .iter()
+        .zip(&actual)
+        .position(|(e, a)| e != a)
+        .or_else(|| (expected.len() != actual.len()).then(|| expected.len().min(actual.len())))
+}
+
+// The index of the first value written by `out` that differs between the
+// outcomes, counting a value only one of them wrote
+#[must_use]
+pub fn first_output_mismatch(expected: &str, actual: &str) -> Option<usize> {
+    first_mismatch(expected, actual, "output")
+}
+
+// Partial credit for one output stream: how many of the values expected on
+// it were written before the first mismatch, and how many were written
+// after all of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credit {
+    pub stream: &'static str,
+    pub matched: usize,
+    pub expected: usize,
+    pub extra: usize,
+}
+
+impl Credit {
+    #[must_use]
+    pub fn full(&self) -> bool {
+        self.matched == self.expected && self.extra == 0
+    }
+}
+
+impl fmt::Display for Credit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = if self.stream == "text" {
+            "characters"
+        } else {
+            "values"
+        };
+        write!(
+            f,
+            "{}: {} of {} {} match",
+            self.stream, self.matched, self.expected, unit
+        )?;
+        if self.extra > 0 {
+            write!(f, ", then {} extra", self.extra)?;
+        }
+        Ok(())
+    }
+}
+
+// The credit `actual` earns on each output stream `expected` has
+#[must_use]
+pub fn credit(expected: &str, actual: &str) -> Vec<Credit> {
+    ["output", "text"]
+        .into_iter()
+        .filter(|name| field(expected, name).is_some())
+        .map(|name| {
+            let values = stream(expected, name).len();
+            let written = stream(actual, name).len();
+            let matched =
+                first_mismatch(expected, actual, name).map_or(values, |idx| idx.min(values));
+            Credit {
+                stream: name,
+                matched,
+                expected: values,
+                extra: if matched == values {
+                    written - values
+                } else {
+                    0
+                },
+            }
+        })
+        .collect()
+}
+
+// `outcome` without the lines `compare` covers: the diagnostics, trace and
+// runtime error
+#[must_use]
+pub fn without_fields(outcome: &str) -> String {
+    outcome
+        .lines()
+        .filter(|line| {
+            !FIELDS.iter().any(|name| {
+                line.strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with(": "))
+            })
+        })
+        .flat_map(|line| [line, "\n"])
+        .collect()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// `text` as an indented YAML block scalar
+fn yaml_block(text: &str) -> String {
+    let mut out = String::from("|\n");
+    for line in text.lines() {
+        out.push_str("    ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+// Partial credit as a YAML `credit` mapping from each stream to its counts
+fn yaml_credit(credit: &[Credit]) -> String {
+    if credit.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("  credit:\n");
+    for c in credit {
+        let _ = writeln!(
+            out,
+            "    {}: {{matched: {}, expected: {}, extra: {}}}",
+            c.stream, c.matched, c.expected, c.extra
+        );
+    }
+    out
+}
+
+// `results` as Test Anything Protocol output, with the partial credit and
+// the expected and actual outcomes of each failure in a YAML diagnostic block
+#[must_use]
+pub fn tap(results: &[CaseResult]) -> String {
+    let mut out = format!("TAP version 13\n1..{}\n", results.len());
+    for (idx, result) in results.iter().enumerate() {
+        let path = result.path.display();
+        let _ = match &result.status {
+            Status::Pass => writeln!(out, "ok {} - {}", idx + 1, path),
+            Status::Blessed => writeln!(out, "ok {} - {} # blessed", idx + 1, path),
+            Status::Missing => writeln!(
+                out,
+                "not ok {} - {}\n  ---\n  message: no .expected file\n  ...",
+                idx + 1,
+                path
+            ),
+            Status::Fail { expected, actual } => writeln!(
+                out,
+                "not ok {} - {}\n  ---\n  message: outcome differs from .expected\n{}  expected: {}  actual: {}  ...",
+                idx + 1,
+                path,
+                yaml_credit(&credit(expected, actual)),
+                yaml_block(expected),
+                yaml_block(actual)
+            ),
+        };
+    }
+    out
+}
+
+// `results` as a JUnit XML report, for CI systems that display test results
+#[must_use]
+pub fn junit(suite: &str, results: &[CaseResult]) -> String {
+    let failures = results.iter().filter(|r| r.failed()).count();
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n",
+        xml_escape(suite),
+        results.len(),
+        failures
+    );
+    for result in results {
+        let name = xml_escape(&result.path.display().to_string());
+        let _ = match &result.status {
+            Status::Pass | Status::Blessed => writeln!(out, "    <testcase name=\"{name}\"/>"),
+            Status::Missing => writeln!(
+                out,
+                "    <testcase name=\"{name}\">\n      <failure message=\"no .expected file\"/>\n    </testcase>"
+            ),
+            Status::Fail { expected, actual } => writeln!(
+                out,
+                "    <testcase name=\"{}\">\n      <failure message=\"outcome differs from .expected\">{}expected:\n{}\nactual:\n{}</failure>\n    </testcase>",
+                name,
+                credit(expected, actual)
+                    .iter()
+                    .fold(String::new(), |mut lines, c| {
+                        let _ = writeln!(lines, "{c}");
+                        lines
+                    }),
+                xml_escape(expected),
+                xml_escape(actual)
+            ),
+        };
+    }
+    out.push_str("  </testsuite>\n</testsuites>\n");
+    out
+}
+
+// Run every `.tis` program in `dir`, in file name order, with execution
+// traces in the outcomes if `trace` is set
+pub fn run(dir: &Path, bless: bool, trace: bool) -> io::Result<Vec<CaseResult>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    paths.retain(|p| p.extension().is_some_and(|ext| ext == "tis"));
+    paths.sort();
+    let mut results = Vec::new();
+    for path in paths {
+        let source = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = source.lines().collect();
+        let actual = outcome(&lines, trace);
+        let expected_path = path.with_extension("expected");
+        let status = if bless {
+            fs::write(&expected_path, &actual)?;
+            Status::Blessed
+        } else {
+            match fs::read_to_string(&expected_path) {
+                Ok(expected) if expected == actual => Status::Pass,
+                Ok(expected) => Status::Fail { expected, actual },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Status::Missing,
+                Err(e) => return Err(e),
+            }
+        };
+        results.push(CaseResult { path, status });
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credits_each_stream_up_to_its_first_mismatch() {
+        let expected = "output: 1, 2, 3, 4\ntext: \"hi\\n\"\nacc: 0\n";
+        let actual = "output: 1, 2, 9\ntext: \"hi\\n!\"\nacc: 0\n";
+        let credits = credit(expected, actual);
+        assert_eq!(
+            credits,
+            [
+                Credit {
+                    stream: "output",
+                    matched: 2,
+                    expected: 4,
+                    extra: 0
+                },
+                Credit {
+                    stream: "text",
+                    matched: 3,
+                    expected: 3,
+                    extra: 1
+                },
+            ]
+        );
+        assert_eq!(credits[0].to_string(), "output: 2 of 4 values match");
+        assert_eq!(
+            credits[1].to_string(),
+            "text: 3 of 3 characters match, then 1 extra"
+        );
+        assert!(!credits[1].full());
+    }
+
+    #[test]
+    fn credits_nothing_for_a_missing_stream() {
+        let missing = credit("output: 5\nacc: 0\n", "acc: 0\n");
+        assert_eq!((missing[0].matched, missing[0].expected), (0, 1));
+        assert!(credit("acc: 0\n", "output: 5\nacc: 0\n").is_empty());
+        let same = "output: -1, 0\ntext: \"\\u{7f}\"\n";
+        assert!(credit(same, same).iter().all(Credit::full));
+    }
+
+    const EXPECTED: &str = "line 1: out 1 -> acc=0 bak=0 cycles=1\noutput: 1, 2\nacc: 0\nbak: 0\ncycles: 2\ninstructions: 2\n";
+    const ACTUAL: &str = "line 1: out 1 -> acc=0 bak=0 cycles=1\nNo more input\noutput: 1, 3, 4\nacc: 0\nbak: 0\ncycles: 3\ninstructions: 3\n";
+
+    #[test]
+    fn compares_fields_side_by_side() {
+        let rows = compare(EXPECTED, ACTUAL);
+        let shown: Vec<(&str, Option<&str>, Option<&str>, bool)> = rows
+            .iter()
+            .map(|r| (r.field, r.expected, r.actual, r.differs()))
+            .collect();
+        assert_eq!(
+            shown,
+            [
+                ("output", Some("1, 2"), Some("1, 3, 4"), true),
+                ("acc", Some("0"), Some("0"), false),
+                ("bak", Some("0"), Some("0"), false),
+                ("cycles", Some("2"), Some("3"), true),
+                ("instructions", Some("2"), Some("3"), true),
+            ]
+        );
+        assert!(compare(EXPECTED, EXPECTED).iter().all(|r| !r.differs()));
+        assert_eq!(compare("", ""), []);
+    }
+
+    #[test]
+    fn finds_the_first_output_mismatch() {
+        assert_eq!(first_output_mismatch(EXPECTED, ACTUAL), Some(1));
+        assert_eq!(first_output_mismatch(EXPECTED, EXPECTED), None);
+        assert_eq!(
+            first_output_mismatch("output: 1\n", "output: 1, 2\n"),
+            Some(1)
+        );
+        assert_eq!(first_output_mismatch("output: 1\n", "acc: 0\n"), Some(0));
+        assert_eq!(first_output_mismatch("acc: 0\n", "acc: 1\n"), None);
+    }
+
+    #[test]
+    fn diffs_what_the_fields_leave_out() {
+        assert_eq!(
+            without_fields(ACTUAL),
+            "line 1: out 1 -> acc=0 bak=0 cycles=1\nNo more input\n"
+        );
+        let (expected, actual) = (without_fields(EXPECTED), without_fields(ACTUAL));
+        assert_eq!(
+            diff(&expected, &actual),
+            [
+                DiffLine::Same("line 1: out 1 -> acc=0 bak=0 cycles=1"),
+                DiffLine::Added("No more input"),
+            ]
+        );
+        assert_eq!(
+            diff("a\nb\nc\n", "a\nc\nd\n"),
+            [
+                DiffLine::Same("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Same("c"),
+                DiffLine::Added("d"),
+            ]
+        );
+        assert!(
+            diff(EXPECTED, EXPECTED)
+                .iter()
+                .all(|l| matches!(l, DiffLine::Same(_)))
+        );
+    }
+
+    fn results(statuses: Vec<Status>) -> Vec<CaseResult> {
+        statuses
+            .into_iter()
+            .enumerate()
+            .map(|(idx, status)| CaseResult {
+                path: PathBuf::from(format!("cases/{idx}.tis")),
+                status,
+            })
+            .collect()
+    }
+
+    fn failing() -> Vec<CaseResult> {
+        results(vec![
+            Status::Pass,
+            Status::Missing,
+            Status::Fail {
+                expected: "output: 1, 2\n".to_string(),
+                actual: "output: 1, <3> & 4\n".to_string(),
+            },
+        ])
+    }
+
+    #[test]
+    fn writes_junit_for_passing_cases() {
+        assert_eq!(
+            junit("suite", &results(vec![Status::Pass, Status::Blessed])),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<testsuites>
+  <testsuite name=\"suite\" tests=\"2\" failures=\"0\">
+    <testcase name=\"cases/0.tis\"/>
+    <testcase name=\"cases/1.tis\"/>
+  </testsuite>
+</testsuites>
+"
+        );
+    }
+
+    #[test]
+    fn writes_junit_for_failing_cases() {
+        assert_eq!(
+            junit("a \"suite\"", &failing()),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<testsuites>
+  <testsuite name=\"a &quot;suite&quot;\" tests=\"3\" failures=\"2\">
+    <testcase name=\"cases/0.tis\"/>
+    <testcase name=\"cases/1.tis\">
+      <failure message=\"no .expected file\"/>
+    </testcase>
+    <testcase name=\"cases/2.tis\">
+      <failure message=\"outcome differs from .expected\">output: 1 of 2 values match
+expected:
+output: 1, 2
+
+actual:
+output: 1, &lt;3&gt; &amp; 4
+</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"
+        );
+    }
+
+    #[test]
+    fn writes_tap_for_passing_cases() {
+        assert_eq!(
+            tap(&results(vec![Status::Pass, Status::Blessed])),
+            "TAP version 13\n1..2\nok 1 - cases/0.tis\nok 2 - cases/1.tis # blessed\n"
+        );
+        assert_eq!(tap(&[]), "TAP version 13\n1..0\n");
+    }
+
+    #[test]
+    fn writes_tap_for_failing_cases() {
+        assert_eq!(
+            tap(&failing()),
+            "TAP version 13
+1..3
+ok 1 - cases/0.tis
+not ok 2 - cases/1.tis
+  ---
+  message: no .expected file
+  ...
+not ok 3 - cases/2.tis
+  ---
+  message: outcome differs from .expected
+  credit:
+    output: {matched: 1, expected: 2, extra: 0}
+  expected: |
+    output: 1, 2
+  actual: |
+    output: 1, <3> & 4
+  ...
+"
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// Lockstep co-simulation, for hosts such as game engines that embed the
+// emulator as an in-world computer and advance it alongside their own logic.
+// `CoSim` moves the machine forward one cycle per `tick`, so an instruction
+// costing several cycles spans several ticks. Input and output go through
+// the host: an `in` with no value queued becomes a pending read, which the
+// host resolves with `provide`, and values written by `out` wait until the
+// host collects them with `take_output`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::emulator::{Emulator, InputEnd, RuntimeError};
+use crate::stream::{FnSink, FnSource};
+
+// What one tick did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tick {
+    // The instruction at line index `pc` started this cycle
+    Started(usize),
+    // An instruction that started on an earlier tick is still running
+    Busy,
+    // The `in` at line index `pc` is waiting for a value from the host
+    Waiting(usize),
+    // The program has halted; ticking further does nothing
+    Halted,
+}
+
+pub struct CoSim {
+    emu: Emulator,
+    // Host cycles ticked so far, including those spent waiting for input
+    clock: u64,
+    // The clock at which the instruction running finishes
+    busy_until: u64,
+    input: Arc<Mutex<VecDeque<i32>>>,
+    output: Arc<Mutex<Vec<i32>>>,
+}
+
+impl CoSim {
+    // Take over `emu`, which should have its program loaded. Its input and
+    // output are replaced by the host's, and running out of input waits.
+    #[must_use]
+    pub fn new(mut emu: Emulator) -> Self {
+        let input = Arc::new(Mutex::new(VecDeque::new()));
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let source = Arc::clone(&input);
+        emu.set_input(FnSource(move || {
+            source
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop_front()
+        }));
+        let sink = Arc::clone(&output);
+        emu.set_output(FnSink(move |value| {
+            sink.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(value);
+        }));
+        emu.on_input_end = InputEnd::Block;
+        CoSim {
+            emu,
+            clock: 0,
+            busy_until: 0,
+            input,
+            output,
+        }
+    }
+
+    // Advance one cycle. A runtime error halts the program and is returned
+    // from the tick that raised it.
+    pub fn tick(&mut self) -> Result<Tick, RuntimeError> {
+        if self.emu.is_halted() {
+            return Ok(Tick::Halted);
+        }
+        if self.clock < self.busy_until {
+            self.clock += 1;
+            return Ok(Tick::Busy);
+        }
+        let start = self.emu.cycles;
+        let executed = self.emu.step()?;
+        let tick = match executed {
+            Some(pc) => Tick::Started(pc),
+            None if self.emu.is_blocked() => Tick::Waiting(self.emu.pc),
+            None => return Ok(Tick::Halted),
+        };
+        self.busy_until = self.clock + (self.emu.cycles - start);
+        self.clock += 1;
+        Ok(tick)
+    }
+
+    // The line index of the `in` waiting for a value, if one is
+    #[must_use]
+    pub fn pending_read(&self) -> Option<usize> {
+        self.emu.is_blocked().then_some(self.emu.pc)
+    }
+
+    // Queue `value` for the next `in`, resolving a pending read
+    pub fn provide(&mut self, value: i32) {
+        self.input
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(value);
+    }
+
+    // The values written by `out` since the last call
+    pub fn take_output(&mut self) -> Vec<i32> {
+        std::mem::take(&mut *self.output.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    // Host cycles ticked so far
+    #[must_use]
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    // The machine, for inspecting its registers and counters
+    #[must_use]
+    pub fn emulator(&self) -> &Emulator {
+        &self.emu
+    }
+
+    #[must_use]
+    pub fn into_emulator(self) -> Emulator {
+        self.emu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cosim(source: &[&str], mut emu: Emulator) -> CoSim {
+        emu.load_program(source).unwrap();
+        CoSim::new(emu)
+    }
+
+    fn ticks(sim: &mut CoSim, n: usize) -> Vec<Tick> {
+        (0..n).map(|_| sim.tick().unwrap()).collect()
+    }
+
+    #[test]
+    fn slow_instructions_span_several_ticks() {
+        let mut emu = Emulator::new();
+        emu.cycle_costs.set("swp", 3).unwrap();
+        let mut sim = cosim(&["swp", "out 7"], emu);
+        assert_eq!(
+            ticks(&mut sim, 6),
+            [
+                Tick::Started(0),
+                Tick::Busy,
+                Tick::Busy,
+                Tick::Started(1),
+                Tick::Halted,
+                Tick::Halted,
+            ]
+        );
+        assert_eq!(sim.clock(), 4);
+        assert_eq!(sim.emulator().cycles, 4);
+        assert_eq!(sim.take_output(), [7]);
+        assert!(sim.take_output().is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_host_to_provide_input() {
+        let mut sim = cosim(&["in acc", "add 1", "out acc"], Emulator::new());
+        assert_eq!(ticks(&mut sim, 2), [Tick::Waiting(0), Tick::Waiting(0)]);
+        assert_eq!(sim.pending_read(), Some(0));
+        sim.provide(41);
+        assert_eq!(
+            ticks(&mut sim, 4),
+            [
+                Tick::Started(0),
+                Tick::Started(1),
+                Tick::Started(2),
+                Tick::Halted
+            ]
+        );
+        assert_eq!(sim.pending_read(), None);
+        assert_eq!(sim.take_output(), [42]);
+        // Waiting costs the host cycles but not the machine
+        assert_eq!((sim.clock(), sim.emulator().cycles), (5, 3));
+    }
+
+    #[test]
+    fn returns_the_error_that_halts_the_program() {
+        let mut sim = cosim(&["swp", "lookup t"], Emulator::new());
+        assert_eq!(sim.tick().unwrap(), Tick::Started(0));
+        assert_eq!(sim.tick().unwrap_err().message, "Unknown table: t");
+        assert_eq!(sim.tick().unwrap(), Tick::Halted);
+        assert_eq!(sim.into_emulator().pc, 1);
+    }
+}
@@ -0,0 +1,641 @@
+// A daemon holding named emulator sessions, driven over a Unix socket so
+// editors and other tools can attach, detach and come back without losing
+// state.
+//
+// The protocol is line based: each request is one line of space-separated
+// words and gets one response line, `ok ...` or `error <message>`.
+//
+//   create <session>                 start an empty session
+//   load <session> <path>            load a program file into the session
+//   set <session> <reg>=<value>      set acc or bak
+//   step <session> [<n>]             execute up to n instructions (default 1)
+//   run <session> <cycles>           run for at most <cycles> cycles
+//   inspect <session>                the session's state as a JSON object
+//   destroy <session>                drop the session
+//   list                             the names of all sessions
+//
+// Given an audit log, the daemon appends a record of every `step` and `run`
+// to it; see `audit`. Given a span log, it writes a trace of every `load`,
+// `resume`, `step` and `run`, with spans for parsing, validating and running
+// the program; see `telemetry`.
+//
+// With a store directory, sessions can also be written to disk and brought
+// back after the daemon restarts:
+//
+//   save <session>                   write the session to the store
+//   resume <session>                 load a saved session, replacing any
+//                                    session of the same name
+//   stored                           the names of all saved sessions
+//
+// A saved session is a text file: `<field> <value>` lines for the registers
+// and counters, then for how far the run has got (why it halted, the reason
+// pending after a `ret`, the timer and the recent jumps), a `source` line,
+// then the program source. Sessions always run with the default limits,
+// lints, cycle costs and input handling, with no input and their output kept
+// in memory, so none of those are saved; neither is that output, which the
+// protocol has no way to read. The store is a plain directory
+// rather than a database such as sled or SQLite, as the crate has no
+// dependencies; one small file per session needs nothing more.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use crate::audit::{AuditLog, Record};
+use crate::emulator::{Emulator, HaltReason, RunOutcome, RunState, RuntimeError};
+use crate::observer::Register;
+use crate::parser::{Diagnostic, Severity};
+use crate::telemetry::{Span, SpanLog, Trace};
+
+// The requests that are traced, as the ones that parse or run a program
+const TRACED: [&str; 4] = ["load", "resume", "step", "run"];
+
+#[derive(Default)]
+struct Session {
+    emu: Emulator,
+    // Source of the loaded program, kept so the session can be saved
+    source: Option<String>,
+}
+
+// Each session has its own lock, so a long `run` only holds up requests for
+// the session it is running; the map's lock is held just long enough to find
+// or change an entry.
+#[derive(Default)]
+struct State {
+    sessions: BTreeMap<String, Arc<Mutex<Session>>>,
+    store: Option<PathBuf>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    spans: Option<Arc<Mutex<SpanLog>>>,
+}
+
+type Shared = Arc<Mutex<State>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Listen on `path` until the process exits, serving each connection on its
+// own thread. A stale socket left by an earlier daemon is replaced. Sessions
+// are saved under `store`, if given, every `step` and `run` is recorded in
+// `audit` and the traced requests are written to `spans`.
+pub fn serve(
+    path: &Path,
+    store: Option<&Path>,
+    audit: Option<AuditLog>,
+    spans: Option<SpanLog>,
+) -> io::Result<()> {
+    if let Some(store) = store {
+        fs::create_dir_all(store)?;
+    }
+    if path.exists() && UnixStream::connect(path).is_err() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let sessions = Shared::new(Mutex::new(State {
+        sessions: BTreeMap::new(),
+        store: store.map(Path::to_path_buf),
+        audit: audit.map(|audit| Arc::new(Mutex::new(audit))),
+        spans: spans.map(|spans| Arc::new(Mutex::new(spans))),
+    }));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let sessions = Arc::clone(&sessions);
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &sessions) {
+                eprintln!("Connection closed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(stream: UnixStream, sessions: &Shared) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let response = match request(&line?, sessions) {
+            Ok(response) => format!(
+                "ok{}{}",
+                if response.is_empty() { "" } else { " " },
+                response
+            ),
+            Err(message) => format!("error {message}"),
+        };
+        writeln!(out, "{response}")?;
+    }
+    Ok(())
+}
+
+fn request(line: &str, state: &Shared) -> Result<String, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = words.first().copied().unwrap_or_default();
+    let mut trace = Trace::new(command);
+    let response = respond(line, &words, state, &mut trace);
+    let spans = lock(state).spans.clone();
+    if let Some(spans) = spans.filter(|_| TRACED.contains(&command)) {
+        if let Some(name) = words.get(1) {
+            trace.root.attribute("tis.session", *name);
+        }
+        trace.root.end(&response);
+        if let Err(e) = lock(&spans).export(&trace) {
+            eprintln!("Could not write the span log: {e}");
+        }
+    }
+    response
+}
+
+fn respond(
+    line: &str,
+    words: &[&str],
+    state: &Shared,
+    trace: &mut Trace,
+) -> Result<String, String> {
+    let session = |name: &str| {
+        lock(state)
+            .sessions
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("no session {name}"))
+    };
+    let store = || lock(state).store.clone().ok_or("no store directory");
+    match words {
+        ["list"] => Ok(lock(state)
+            .sessions
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")),
+        ["create", name] => {
+            let mut state = lock(state);
+            if state.sessions.contains_key(*name) {
+                return Err(format!("session {name} already exists"));
+            }
+            state.sessions.insert(name.to_string(), Arc::default());
+            Ok(String::new())
+        }
+        ["destroy", name] => match lock(state).sessions.remove(*name) {
+            Some(_) => Ok(String::new()),
+            None => Err(format!("no session {name}")),
+        },
+        ["stored"] => {
+            let mut names: Vec<String> = fs::read_dir(store()?)
+                .map_err(|e| e.to_string())?
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    (path.extension()? == "session")
+                        .then(|| path.file_stem()?.to_str().map(String::from))?
+                })
+                .collect();
+            names.sort();
+            Ok(names.join(" "))
+        }
+        ["save", name] => {
+            let path = session_path(&store()?, name)?;
+            let session = session(name)?;
+            let text = save(&lock(&session));
+            fs::write(path, text).map_err(|e| e.to_string())?;
+            Ok(String::new())
+        }
+        ["resume", name] => {
+            let text = fs::read_to_string(session_path(&store()?, name)?)
+                .map_err(|e| format!("no saved session {name}: {e}"))?;
+            let restored = Arc::new(Mutex::new(restore(&text, trace)?));
+            lock(state).sessions.insert(name.to_string(), restored);
+            Ok(String::new())
+        }
+        [command, name, rest @ ..] => {
+            // The map is unlocked by now, so other sessions keep going
+            let session = session(name)?;
+            let mut session = lock(&session);
+            let (acc, bak, cycles) = (session.emu.acc, session.emu.bak, session.emu.cycles);
+            let response = session_request(&mut session, command, rest, trace);
+            let audit = lock(state).audit.clone();
+            if let Some(audit) = audit.filter(|_| matches!(*command, "step" | "run")) {
+                let record = Record {
+                    session: name,
+                    source: session.source.as_deref(),
+                    acc,
+                    bak,
+                    cycles: session.emu.cycles - cycles,
+                    result: match &response {
+                        Ok(result) | Err(result) => result,
+                    },
+                };
+                if let Err(e) = lock(&audit).record(&record) {
+                    eprintln!("Could not write the audit log: {e}");
+                }
+            }
+            response
+        }
+        _ => Err(format!("invalid request: {line}")),
+    }
+}
+
+// Session names become file names, so they are limited to a safe alphabet
+fn session_path(store: &Path, name: &str) -> Result<PathBuf, String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "session names for the store may only use letters, digits, - and _: {name}"
+        ));
+    }
+    Ok(store.join(format!("{name}.session")))
+}
+
+fn save(session: &Session) -> String {
+    let emu = &session.emu;
+    let mut out = format!(
+        "acc {}\nbak {}\npc {}\ncycles {}\ninstructions {}\n",
+        emu.acc, emu.bak, emu.pc, emu.cycles, emu.instructions
+    );
+    let state = emu.run_state();
+    match &state.halt_reason {
+        Some(HaltReason::Error(error)) => {
+            let message = error.message.replace('\n', " ");
+            let _ = writeln!(out, "halt error {} {}", error.line, message);
+        }
+        Some(reason) => {
+            let _ = writeln!(out, "halt {}", reason.name());
+        }
+        None => {}
+    }
+    if let Some(reason) = &state.stopping {
+        let _ = writeln!(out, "stopping {}", reason.name());
+    }
+    if let Some((next_fire, return_pc)) = state.timer {
+        let return_pc = return_pc.map_or("-".to_string(), |pc| pc.to_string());
+        let _ = writeln!(out, "timer {next_fire} {return_pc}");
+    }
+    for (from, to) in &state.jumps {
+        let _ = writeln!(out, "jump {from} {to}");
+    }
+    if let Some(source) = &session.source {
+        out.push_str("source\n");
+        out.push_str(source);
+    }
+    out
+}
+
+// The session saved as `text`, with the phases of loading its program added
+// to `trace`
+fn restore(text: &str, trace: &mut Trace) -> Result<Session, String> {
+    let mut session = Session::default();
+    let (header, source) = match text.split_once("source\n") {
+        Some((header, source)) => (header, Some(source)),
+        None => (text, None),
+    };
+    if let Some(source) = source {
+        load(&mut session, source.to_string(), trace)?;
+    }
+    let emu = &mut session.emu;
+    let mut state = RunState::default();
+    for line in header.lines() {
+        let invalid = || format!("invalid saved session line: {line}");
+        let (field, value) = line.split_once(' ').ok_or_else(invalid)?;
+        let reason = || HaltReason::from_name(value).ok_or_else(invalid);
+        match field {
+            "acc" => emu.acc = value.parse().map_err(|_| invalid())?,
+            "bak" => emu.bak = value.parse().map_err(|_| invalid())?,
+            "pc" => emu.pc = value.parse().map_err(|_| invalid())?,
+            "cycles" => emu.cycles = value.parse().map_err(|_| invalid())?,
+            "instructions" => emu.instructions = value.parse().map_err(|_| invalid())?,
+            "halt" => {
+                state.halt_reason = Some(match value.strip_prefix("error ") {
+                    Some(error) => {
+                        let (line, message) = error.split_once(' ').ok_or_else(invalid)?;
+                        HaltReason::Error(RuntimeError {
+                            line: line.parse().map_err(|_| invalid())?,
+                            message: message.to_string(),
+                            jumps: Vec::new(),
+                        })
+                    }
+                    None => reason()?,
+                });
+            }
+            "stopping" => state.stopping = Some(reason()?),
+            "timer" => {
+                let (next_fire, return_pc) = value.split_once(' ').ok_or_else(invalid)?;
+                let return_pc = match return_pc {
+                    "-" => None,
+                    pc => Some(pc.parse().map_err(|_| invalid())?),
+                };
+                state.timer = Some((next_fire.parse().map_err(|_| invalid())?, return_pc));
+            }
+            "jump" => {
+                let (from, to) = value.split_once(' ').ok_or_else(invalid)?;
+                let jump = (
+                    from.parse().map_err(|_| invalid())?,
+                    to.parse().map_err(|_| invalid())?,
+                );
+                state.jumps.push(jump);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    // The error's jump history is the one saved alongside it
+    if let Some(HaltReason::Error(error)) = &mut state.halt_reason {
+        error.jumps = state.jumps.iter().map(|(f, t)| (f + 1, t + 1)).collect();
+    }
+    emu.set_run_state(state);
+    Ok(session)
+}
+
+// Load `source` into the session, keeping it for `save`, with a span in
+// `trace` for parsing it and one for validating it
+fn load(session: &mut Session, source: String, trace: &mut Trace) -> Result<String, String> {
+    let lines: Vec<&str> = source.lines().collect();
+    let span = trace.child("parse");
+    span.attribute("tis.lines", lines.len());
+    let parsed = session
+        .emu
+        .parse_source(&lines)
+        .map_err(|diagnostics| rejected(span, &diagnostics));
+    span.end(&parsed);
+    let parsed = parsed?;
+    let span = trace.child("validate");
+    let result = match session.emu.load_parsed(&lines, parsed) {
+        Ok(warnings) => {
+            span.attribute("tis.warnings", warnings.len());
+            Ok(format!("{} warning(s)", warnings.len()))
+        }
+        Err(diagnostics) => Err(rejected(span, &diagnostics)),
+    };
+    span.end(&result);
+    if result.is_ok() {
+        session.source = Some(source);
+    }
+    result
+}
+
+// Record the codes of the errors in `diagnostics` on `span`, returning
+// their messages
+fn rejected(span: &mut Span, diagnostics: &[Diagnostic]) -> String {
+    let errors: Vec<&Diagnostic> = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .collect();
+    let codes: Vec<&str> = errors.iter().map(|d| d.code).collect();
+    span.attribute("tis.error.codes", codes.join(",").as_str());
+    errors
+        .iter()
+        .map(|d| d.message.as_str())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn session_request(
+    session: &mut Session,
+    command: &str,
+    args: &[&str],
+    trace: &mut Trace,
+) -> Result<String, String> {
+    if let ("load", [path]) = (command, args) {
+        let source = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        return load(session, source, trace);
+    }
+    let emu = &mut session.emu;
+    if matches!(command, "step" | "run") {
+        let (cycles, instructions) = (emu.cycles, emu.instructions);
+        let span = trace.child("run");
+        let result = run(emu, command, args);
+        span.attribute("tis.cycles", emu.cycles - cycles);
+        span.attribute("tis.instructions", emu.instructions - instructions);
+        match (&result, emu.halt_reason()) {
+            (Ok(outcome), _) => span.attribute("tis.outcome", outcome.as_str()),
+            (Err(_), Some(HaltReason::Error(error))) => {
+                span.attribute("tis.error.line", error.line);
+            }
+            (Err(_), _) => {}
+        }
+        span.end(&result);
+        return result;
+    }
+    match (command, args) {
+        ("set", [assignment]) => {
+            let (name, value) = assignment
+                .split_once('=')
+                .ok_or_else(|| format!("expected <reg>=<value>, found {assignment}"))?;
+            let register =
+                Register::from_name(name).ok_or_else(|| format!("unknown register {name}"))?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid value {value}"))?;
+            emu.set_register(register, value);
+            Ok(String::new())
+        }
+        ("inspect", []) => Ok(format!(
+            "{{\"acc\":{},\"bak\":{},\"line\":{},\"cycles\":{},\"instructions\":{},\"halted\":{}}}",
+            emu.acc,
+            emu.bak,
+            emu.pc + 1,
+            emu.cycles,
+            emu.instructions,
+            emu.is_halted()
+        )),
+        _ => Err(format!("invalid request: {} {}", command, args.join(" "))),
+    }
+}
+
+// Carry out a `step` or `run` request, returning how the program was left
+fn run(emu: &mut Emulator, command: &str, args: &[&str]) -> Result<String, String> {
+    match (command, args) {
+        ("step", [] | [_]) => {
+            let count: u64 = match args.first() {
+                Some(n) => n.parse().map_err(|_| format!("invalid count {n}"))?,
+                None => 1,
+            };
+            for _ in 0..count {
+                if emu
+                    .step()
+                    .map_err(|e| e.to_string().replace('\n', " "))?
+                    .is_none()
+                {
+                    let stop = if emu.is_blocked() {
+                        "blocked"
+                    } else {
+                        "halted"
+                    };
+                    return Ok(stop.to_string());
+                }
+            }
+            Ok("running".to_string())
+        }
+        ("run", [cycles]) => {
+            let cycles = cycles
+                .parse()
+                .map_err(|_| format!("invalid cycle count {cycles}"))?;
+            match emu.run_for(cycles) {
+                Ok(RunOutcome::Halted) => Ok("halted".to_string()),
+                Ok(RunOutcome::Blocked) => Ok("blocked".to_string()),
+                Ok(_) => Ok("running".to_string()),
+                Err(e) => Err(e.to_string().replace('\n', " ")),
+            }
+        }
+        _ => Err(format!("invalid request: {} {}", command, args.join(" "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    // A fresh directory for one test's files
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tis31337-daemon-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn handles_a_session_from_create_to_destroy() {
+        let dir = scratch("requests");
+        let program = dir.join("count.tis");
+        fs::write(&program, "mov 3, acc\nl: add -1\njnz l\n").unwrap();
+        let state = Shared::default();
+        let ask = |line: &str| request(line, &state);
+        assert_eq!(ask("create a"), Ok(String::new()));
+        assert_eq!(ask("create a"), Err("session a already exists".to_string()));
+        assert_eq!(
+            ask(&format!("load a {}", program.display())),
+            Ok("0 warning(s)".to_string())
+        );
+        assert_eq!(ask("set a bak=4"), Ok(String::new()));
+        assert_eq!(ask("step a 2"), Ok("running".to_string()));
+        assert_eq!(
+            ask("inspect a"),
+            Ok(
+                "{\"acc\":2,\"bak\":4,\"line\":3,\"cycles\":2,\"instructions\":2,\"halted\":false}"
+                    .to_string()
+            )
+        );
+        assert_eq!(ask("run a 100"), Ok("halted".to_string()));
+        assert_eq!(ask("step a"), Ok("halted".to_string()));
+        assert_eq!(ask("create b"), Ok(String::new()));
+        assert_eq!(ask("list"), Ok("a b".to_string()));
+        assert_eq!(ask("destroy a"), Ok(String::new()));
+        assert_eq!(ask("inspect a"), Err("no session a".to_string()));
+        assert_eq!(
+            ask("set b acc"),
+            Err("expected <reg>=<value>, found acc".to_string())
+        );
+        assert_eq!(ask("frob"), Err("invalid request: frob".to_string()));
+        assert_eq!(ask("save b"), Err("no store directory".to_string()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_load_and_runtime_errors() {
+        let dir = scratch("errors");
+        let bad = dir.join("bad.tis");
+        fs::write(&bad, "frob acc\n").unwrap();
+        let crash = dir.join("crash.tis");
+        fs::write(&crash, "mov 1, acc\njmp nowhere\n").unwrap();
+        let state = Shared::default();
+        let ask = |line: &str| request(line, &state);
+        ask("create a").unwrap();
+        assert_eq!(
+            ask(&format!("load a {}", bad.display())),
+            Err("Unknown instruction: frob".to_string())
+        );
+        ask(&format!("load a {}", crash.display())).unwrap();
+        let error = ask("run a 10").unwrap_err();
+        assert!(error.contains("nowhere"), "{}", error);
+        assert!(!error.contains('\n'));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A session with `source` loaded, stepped `steps` times
+    fn stepped(source: &str, steps: usize) -> Session {
+        let mut session = Session::default();
+        load(&mut session, source.to_string(), &mut Trace::new("load")).unwrap();
+        for _ in 0..steps {
+            let _ = session.emu.step();
+        }
+        session
+    }
+
+    // Saving, restoring and saving again changes nothing, and the restored
+    // session carries on exactly like the original
+    fn round_trip(mut session: Session) -> String {
+        let text = save(&session);
+        let mut restored = restore(&text, &mut Trace::new("resume")).unwrap();
+        assert_eq!(save(&restored), text);
+        assert_eq!(restored.emu.run_state(), session.emu.run_state());
+        let original = session.emu.run_for(100).map_err(|e| e.to_string());
+        let resumed = restored.emu.run_for(100).map_err(|e| e.to_string());
+        assert_eq!(original, resumed);
+        assert_eq!(
+            (restored.emu.acc, restored.emu.bak, restored.emu.cycles),
+            (session.emu.acc, session.emu.bak, session.emu.cycles)
+        );
+        text
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let countdown = "mov 3, acc\nsave\nl: add -1\njnz l\n";
+        assert_eq!(
+            round_trip(stepped(countdown, 4)),
+            format!("acc 2\nbak 3\npc 2\ncycles 4\ninstructions 4\njump 3 2\nsource\n{countdown}")
+        );
+        // Pending halt after a `ret`, and a halt by a runtime error
+        let text = round_trip(stepped("mov 1, acc\nret\nadd 1\n", 2));
+        assert!(text.contains("stopping ret\n"), "{}", text);
+        let text = round_trip(stepped("mov 1, acc\njmp nowhere\n", 3));
+        assert!(text.contains("halt error 2 "), "{}", text);
+        // A session with nothing loaded has no source
+        assert_eq!(
+            round_trip(Session::default()),
+            "acc 0\nbak 0\npc 0\ncycles 0\ninstructions 0\n"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_snapshots() {
+        let mut trace = Trace::new("resume");
+        assert!(restore("acc x\n", &mut trace).is_err());
+        assert!(restore("colour blue\n", &mut trace).is_err());
+        assert!(restore("halt sideways\n", &mut trace).is_err());
+        assert!(restore("acc 1\nsource\nfrob\n", &mut trace).is_err());
+    }
+
+    #[test]
+    fn saves_and_resumes_through_the_store() {
+        let dir = scratch("store");
+        let program = dir.join("count.tis");
+        fs::write(&program, "mov 5, acc\nl: add -1\njnz l\n").unwrap();
+        let state = Shared::default();
+        lock(&state).store = Some(dir.join("store"));
+        fs::create_dir_all(dir.join("store")).unwrap();
+        let ask = |line: &str| request(line, &state);
+        ask("create a").unwrap();
+        ask(&format!("load a {}", program.display())).unwrap();
+        ask("step a 3").unwrap();
+        assert_eq!(ask("save a"), Ok(String::new()));
+        assert_eq!(ask("stored"), Ok("a".to_string()));
+        ask("run a 100").unwrap();
+        // Resuming replaces the session with the saved one
+        assert_eq!(ask("resume a"), Ok(String::new()));
+        assert!(ask("inspect a").unwrap().contains("\"cycles\":3,"));
+        ask("destroy a").unwrap();
+        assert_eq!(ask("resume a"), Ok(String::new()));
+        assert_eq!(ask("run a 100"), Ok("halted".to_string()));
+        assert!(ask("save ../a").unwrap_err().starts_with("session names"));
+        assert!(
+            ask("resume b")
+                .unwrap_err()
+                .starts_with("no saved session b")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
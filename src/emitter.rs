@@ -0,0 +1,188 @@
+// Emits a parsed program as normalized assembly text. Each line of the
+// program becomes one line of output, so line numbers are preserved, and
+// parsing the output yields the same program again.
+
+use std::fmt;
+
+use crate::parser::{Instruction, Line, Operand, Program, Table};
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Acc => write!(f, "acc"),
+            Operand::Bak => write!(f, "bak"),
+            Operand::Imm(value) => write!(f, "{value}"),
+            Operand::Clk => write!(f, "clk"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mnemonic = self.mnemonic();
+        match self {
+            Instruction::Mov(src, dst) => write!(f, "{mnemonic} {src}, {dst}"),
+            Instruction::Add(src)
+            | Instruction::Out(src)
+            | Instruction::Outc(src)
+            | Instruction::Slp(src)
+            | Instruction::In(src) => {
+                write!(f, "{mnemonic} {src}")
+            }
+            Instruction::Jmp(label)
+            | Instruction::Jez(label)
+            | Instruction::Jnz(label)
+            | Instruction::Jgz(label)
+            | Instruction::Jlz(label)
+            | Instruction::Lookup(label) => write!(f, "{mnemonic} {label}"),
+            Instruction::Swp
+            | Instruction::Save
+            | Instruction::Ret
+            | Instruction::Reti
+            | Instruction::Dbg => write!(f, "{mnemonic}"),
+        }
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values: Vec<String> = self.values.iter().map(i32::to_string).collect();
+        write!(f, ".table {}: {}", self.name, values.join(", "))
+    }
+}
+
+// A single line in canonical form, without a trailing newline
+#[must_use]
+pub fn emit_line(line: &Line) -> String {
+    if let Some(comment) = &line.comment {
+        return format!("#{comment}");
+    }
+    if let Some(table) = &line.table {
+        return table.to_string();
+    }
+    if let Some((label, _)) = &line.entry {
+        return format!(".entry {label}");
+    }
+    match (&line.label, &line.instruction) {
+        (Some((label, _)), Some(instruction)) => format!("{label}: {instruction}"),
+        (Some((label, _)), None) => format!("{label}:"),
+        (None, Some(instruction)) => instruction.to_string(),
+        (None, None) => String::new(),
+    }
+}
+
+// The whole program in canonical form: one line each, ending in a newline,
+// with trailing blank lines dropped
+#[must_use]
+pub fn emit(program: &Program) -> String {
+    let mut out = String::new();
+    for line in &program.lines {
+        out.push_str(&emit_line(line));
+        out.push('\n');
+    }
+    let kept = out.trim_end_matches('\n').len();
+    out.truncate(kept);
+    if kept > 0 {
+        out.push('\n');
+    }
+    out
+}
+
+// The first line where `source` differs from its canonical form
+// `formatted`, with what was expected there, or None if it is formatted
+#[must_use]
+pub fn first_unformatted(source: &str, formatted: &str) -> Option<(usize, String)> {
+    let mut old = source.split_inclusive('\n');
+    let mut new = formatted.split_inclusive('\n');
+    let mut line = 1;
+    loop {
+        let expected = match (old.next(), new.next()) {
+            (None, None) => return None,
+            (Some(a), Some(b)) if a == b => {
+                line += 1;
+                continue;
+            }
+            (Some(a), Some(b)) if !a.ends_with('\n') && a == b.trim_end_matches('\n') => {
+                "a newline at the end of the file".to_string()
+            }
+            (_, Some(b)) => format!("`{}`", b.trim_end_matches('\n')),
+            (Some(_), None) => "the end of the file".to_string(),
+        };
+        return Some((line, expected));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    const MESSY: &[&str] = &[
+        "# header  ",
+        ".ENTRY start",
+        "  .table   squares:  1,4 ,  9",
+        "start:  MOV 5 ,ACC",
+        "",
+        "Loop:   add   -1",
+        "   JNZ Loop",
+        "  swp",
+        "  SAV",
+        "lookup squares",
+        "in acc",
+        "out   bak",
+        "outc 65",
+        "mov clk, acc",
+        "dbg",
+        "end:",
+        "   ret",
+    ];
+
+    #[test]
+    fn emits_canonical_text() {
+        let program = parser::parse(MESSY).unwrap();
+        let lines: Vec<String> = program.lines.iter().map(emit_line).collect();
+        assert_eq!(lines[0], "# header");
+        assert_eq!(lines[1], ".entry start");
+        assert_eq!(lines[2], ".table squares: 1, 4, 9");
+        assert_eq!(lines[3], "start: mov 5, acc");
+        assert_eq!(lines[4], "");
+        assert_eq!(lines[5], "Loop: add -1");
+        assert_eq!(lines[15], "end:");
+    }
+
+    #[test]
+    fn parse_emit_parse_is_a_fixed_point() {
+        let first = parser::parse(MESSY).unwrap();
+        let emitted = emit(&first);
+        let lines: Vec<&str> = emitted.lines().collect();
+        assert_eq!(lines.len(), MESSY.len());
+        let second = parser::parse(&lines).unwrap();
+        assert_eq!(emit(&second), emitted);
+        let instructions = |p: &Program| -> Vec<Option<Instruction>> {
+            p.lines.iter().map(|l| l.instruction.clone()).collect()
+        };
+        assert_eq!(instructions(&first), instructions(&second));
+        assert_eq!(first.labels, second.labels);
+        assert_eq!(first.tables, second.tables);
+        assert_eq!(first.entry, second.entry);
+    }
+
+    #[test]
+    fn finds_the_first_unformatted_line() {
+        let check = |source: &str| {
+            let lines: Vec<&str> = source.lines().collect();
+            first_unformatted(source, &emit(&parser::parse(&lines).unwrap()))
+        };
+        assert_eq!(check("mov 1, acc\nret\n"), None);
+        assert_eq!(check("mov 1, acc\nRET\n"), Some((2, "`ret`".to_string())));
+        assert_eq!(
+            check("mov 1, acc\nret\n\n\n"),
+            Some((3, "the end of the file".to_string()))
+        );
+        assert_eq!(
+            check("mov 1, acc\nret"),
+            Some((2, "a newline at the end of the file".to_string()))
+        );
+        assert_eq!(check(""), None);
+    }
+}
@@ -0,0 +1,1870 @@
+// The single-node machine: registers, the loaded program and the executor
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cache;
+use crate::json;
+use crate::lint::{self, LintConfig};
+use crate::observer::{Observer, Observers, Register};
+use crate::parser::{self, Diagnostic, Instruction, Line, Operand, Program, Severity};
+use crate::profile::{self, Profile};
+use crate::stream::{Encoding, Input, InputSource, Output, OutputSink};
+
+// The bools are independent settings, each with its own CLI flag
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default)]
+pub struct Emulator {
+    pub acc: i32,
+    pub bak: i32,
+    pub pc: usize,
+    // The line index the loaded program starts at
+    start: usize,
+    labels: HashMap<String, usize>,
+    // The values of each `.table`, by name
+    tables: HashMap<String, Vec<i32>>,
+    program: Arc<Vec<Option<Instruction>>>,
+    // Modeled cycles, weighted by `cycle_costs`
+    pub cycles: u64,
+    // Instructions executed, regardless of their cost
+    pub instructions: u64,
+    pub cycle_costs: CycleCosts,
+    // Scores each executed instruction into `energy`, when set
+    pub cost_model: Option<CostModel>,
+    pub energy: f64,
+    timer: Option<Timer>,
+    pub limits: Limits,
+    pub lints: LintConfig,
+    // Reuse parsed programs from the on-disk cache
+    pub cache: bool,
+    observers: Observers,
+    // When the run started, for `Limits::timeout`
+    started: Option<Instant>,
+    // Why the program stopped, once observers have been told it did
+    halt_reason: Option<HaltReason>,
+    // The label to start at instead of the program's `.entry` or first line
+    pub entry: Option<String>,
+    // The reason to give when the program next halts, for the ways of
+    // stopping that work by moving past the last line
+    stopping: Option<HaltReason>,
+    pub jump_history: JumpHistory,
+    pub profile: Profile,
+    // Reject programs that read `bak` as a source
+    pub strict_bak: bool,
+    // Drop `dbg` instructions when loading, for scored or strict runs
+    pub strip_dbg: bool,
+    // Replace `${NAME}` with environment variables when loading
+    pub substitute_env: bool,
+    // Let `slp` pause for wall-clock time; without it `slp` is an error
+    pub realtime: bool,
+    // Values read by `in`, and what happens when they run out
+    input: Input,
+    pub on_input_end: InputEnd,
+    // The last step stopped at an `in` with no input, under `InputEnd::Block`
+    blocked: bool,
+    // Values written by `out`, and text written by `outc`
+    output: Output,
+    pub encoding: Encoding,
+    // Lines still being parsed, after `load_streaming`
+    incoming: Option<Receiver<(Line, Vec<Diagnostic>)>>,
+}
+
+// What `in` does when the input is exhausted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputEnd {
+    // Fail with a runtime error
+    #[default]
+    Error,
+    // Stop the program as if it had run off the end
+    Halt,
+    // Wait at the `in`, so more input can be attached and the run resumed
+    Block,
+}
+
+impl InputEnd {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            InputEnd::Error => "error",
+            InputEnd::Halt => "halt",
+            InputEnd::Block => "block",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<InputEnd> {
+        [InputEnd::Error, InputEnd::Halt, InputEnd::Block]
+            .into_iter()
+            .find(|end| end.name() == name)
+    }
+}
+
+// Size limits enforced when a program is loaded, and cycle, time and output
+// limits enforced while it runs; `None` means unlimited
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    pub max_lines: Option<usize>,
+    pub max_instructions: Option<usize>,
+    pub max_label_len: Option<usize>,
+    // Values across all `.table`s
+    pub max_table_values: Option<usize>,
+    pub max_cycles: Option<u64>,
+    // Wall-clock time from the first step after loading
+    pub timeout: Option<Duration>,
+    // Values written by `out` plus characters written by `outc`
+    pub max_output: Option<usize>,
+}
+
+impl Limits {
+    // Each limit of `self`, or of `fallback` where `self` has none
+    #[must_use]
+    pub fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            max_lines: self.max_lines.or(fallback.max_lines),
+            max_instructions: self.max_instructions.or(fallback.max_instructions),
+            max_label_len: self.max_label_len.or(fallback.max_label_len),
+            max_table_values: self.max_table_values.or(fallback.max_table_values),
+            max_cycles: self.max_cycles.or(fallback.max_cycles),
+            timeout: self.timeout.or(fallback.timeout),
+            max_output: self.max_output.or(fallback.max_output),
+        }
+    }
+}
+
+// Everything needed to run an untrusted program, applied with
+// `Emulator::sandbox`. The default is sized for judging submissions: far
+// beyond what puzzle solutions need, but small enough that a server can run
+// many at once.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    pub max_lines: usize,
+    pub max_instructions: usize,
+    pub max_label_len: usize,
+    pub max_table_values: usize,
+    pub max_cycles: u64,
+    pub timeout: Duration,
+    pub max_output: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            max_lines: 1000,
+            max_instructions: 1000,
+            max_label_len: 64,
+            max_table_values: 10_000,
+            max_cycles: 10_000_000,
+            timeout: Duration::from_secs(5),
+            max_output: 100_000,
+        }
+    }
+}
+
+impl Sandbox {
+    #[must_use]
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_lines: Some(self.max_lines),
+            max_instructions: Some(self.max_instructions),
+            max_label_len: Some(self.max_label_len),
+            max_table_values: Some(self.max_table_values),
+            max_cycles: Some(self.max_cycles),
+            timeout: Some(self.timeout),
+            max_output: Some(self.max_output),
+        }
+    }
+}
+
+// Cycles each instruction takes, by mnemonic. Instructions without an
+// entry take one cycle.
+#[derive(Debug, Default, Clone)]
+pub struct CycleCosts {
+    costs: HashMap<&'static str, u64>,
+}
+
+impl CycleCosts {
+    // Set the cost of `mnemonic`, failing if it is not an instruction or
+    // the cost is zero: every instruction must take time, or a loop would
+    // never reach the cycle limits that stop runs
+    pub fn set(&mut self, mnemonic: &str, cycles: u64) -> Result<(), String> {
+        let lower = mnemonic.to_lowercase();
+        let Some(&known) = parser::MNEMONICS.iter().find(|&&m| m == lower) else {
+            return Err(format!("Unknown instruction: {mnemonic}"));
+        };
+        if cycles == 0 {
+            return Err(format!("{known} must cost at least one cycle"));
+        }
+        self.costs.insert(known, cycles);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn cost(&self, instruction: &Instruction) -> u64 {
+        self.costs.get(instruction.mnemonic()).copied().unwrap_or(1)
+    }
+}
+
+// Weights for the energy score, by mnemonic, so runs can be ranked by a
+// custom metric. Unlike cycle costs they do not change how long anything
+// takes. Instructions without an entry weigh `default`.
+#[derive(Debug, Clone)]
+pub struct CostModel {
+    weights: HashMap<&'static str, f64>,
+    pub default: f64,
+}
+
+impl CostModel {
+    // Parse a JSON object of weights such as `{"mov": 1, "add": 2.5}`. The
+    // key `default` sets the weight of every other instruction, 1 if absent.
+    pub fn from_json(text: &str) -> Result<CostModel, String> {
+        let mut model = CostModel {
+            weights: HashMap::new(),
+            default: 1.0,
+        };
+        for (key, weight) in json::number_object(text)? {
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(format!("The weight of {key} must not be negative"));
+            }
+            if key == "default" {
+                model.default = weight;
+                continue;
+            }
+            let lower = key.to_lowercase();
+            let Some(&known) = parser::MNEMONICS.iter().find(|&&m| m == lower) else {
+                return Err(format!("Unknown instruction: {key}"));
+            };
+            model.weights.insert(known, weight);
+        }
+        Ok(model)
+    }
+
+    #[must_use]
+    pub fn weight(&self, instruction: &Instruction) -> f64 {
+        self.weights
+            .get(instruction.mnemonic())
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+// The most recent control transfers (taken jumps, interrupts and `reti`) as
+// (from, to) line indexes, oldest first, kept for error reports
+#[derive(Debug, Clone)]
+pub struct JumpHistory {
+    capacity: usize,
+    jumps: VecDeque<(usize, usize)>,
+}
+
+impl Default for JumpHistory {
+    fn default() -> Self {
+        JumpHistory::with_capacity(16)
+    }
+}
+
+impl JumpHistory {
+    // Keep the last `capacity` transfers; zero disables the history
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        JumpHistory {
+            capacity,
+            jumps: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn record(&mut self, from: usize, to: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.jumps.len() == self.capacity {
+            self.jumps.pop_front();
+        }
+        self.jumps.push_back((from, to));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.jumps.iter().copied()
+    }
+}
+
+// Periodic timer interrupt: every `period` cycles execution is forced to
+// `handler`, and `reti` resumes at the saved pc
+#[derive(Debug)]
+struct Timer {
+    period: u64,
+    handler: String,
+    next_fire: u64,
+    return_pc: Option<usize>,
+}
+
+fn strip_dbg(line: &mut Line) {
+    if let Some(Instruction::Dbg) = line.instruction {
+        line.instruction = None;
+        line.span = None;
+        line.operands.clear();
+    }
+}
+
+impl Emulator {
+    #[must_use]
+    pub fn new() -> Self {
+        Emulator::default()
+    }
+
+    pub fn set_timer(&mut self, period: u64, handler: &str) {
+        self.timer = Some(Timer {
+            period,
+            handler: handler.to_string(),
+            next_fire: period,
+            return_pc: None,
+        });
+    }
+
+    // Apply `sandbox` for running an untrusted program: its limits, and no
+    // wall-clock `slp`, on-disk cache or `dbg` output
+    pub fn sandbox(&mut self, sandbox: &Sandbox) {
+        self.limits = sandbox.limits();
+        self.realtime = false;
+        self.cache = false;
+        self.strip_dbg = true;
+    }
+
+    // Put the machine back as it was when the program was loaded, so one load
+    // serves many runs: registers, counters, the timer and buffered output
+    // are cleared. Settings, observers and the input source are kept.
+    pub fn reset(&mut self) {
+        self.acc = 0;
+        self.bak = 0;
+        self.pc = self.start;
+        self.cycles = 0;
+        self.instructions = 0;
+        self.energy = 0.0;
+        if let Some(timer) = self.timer.as_mut() {
+            timer.next_fire = timer.period;
+            timer.return_pc = None;
+        }
+        self.halt_reason = None;
+        self.stopping = None;
+        self.started = None;
+        self.blocked = false;
+        self.jump_history.jumps.clear();
+        self.output.buffer.clear();
+        self.output.text.clear();
+        self.output.written = 0;
+    }
+
+    // What the public fields do not show of where a run has got to, for
+    // saving it and carrying on later
+    #[must_use]
+    pub fn run_state(&self) -> RunState {
+        RunState {
+            halt_reason: self.halt_reason(),
+            stopping: self.stopping.clone(),
+            timer: self.timer.as_ref().map(|t| (t.next_fire, t.return_pc)),
+            jumps: self.jump_history.iter().collect(),
+        }
+    }
+
+    // Carry on from `state`, after loading the program it was taken from.
+    // Observers are not told again about a halt they have already seen.
+    pub fn set_run_state(&mut self, state: RunState) {
+        self.blocked = state.halt_reason == Some(HaltReason::Blocked);
+        self.halt_reason = state.halt_reason.filter(|r| *r != HaltReason::Blocked);
+        self.stopping = state.stopping;
+        if let (Some(timer), Some((next_fire, return_pc))) = (self.timer.as_mut(), state.timer) {
+            timer.next_fire = next_fire;
+            timer.return_pc = return_pc;
+        }
+        self.jump_history.jumps.clear();
+        for (from, to) in state.jumps {
+            self.jump_history.record(from, to);
+        }
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.0.push(observer);
+    }
+
+    // Read the values for `in` from `source` from now on
+    pub fn set_input(&mut self, source: impl InputSource + Send + 'static) {
+        self.input.source = Some(Box::new(source));
+    }
+
+    // Whether the program is waiting at an `in` for more input
+    #[must_use]
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    // Send the values written by `out` to `sink` from now on
+    pub fn set_output(&mut self, sink: impl OutputSink + Send + 'static) {
+        self.output.sink = Some(Box::new(sink));
+    }
+
+    // The values written by `out` while no sink was attached
+    #[must_use]
+    pub fn output(&self) -> &[i32] {
+        &self.output.buffer
+    }
+
+    // Send the text written by `outc` to `out` from now on
+    pub fn set_text_output(&mut self, out: impl Write + Send + 'static) {
+        self.output.text_sink = Some(Box::new(out));
+    }
+
+    // The text written by `outc` while no text output was attached
+    #[must_use]
+    pub fn text_output(&self) -> &[u8] {
+        &self.output.text
+    }
+
+    // Label names and the line index each one marks
+    #[must_use]
+    pub fn labels(&self) -> &HashMap<String, usize> {
+        &self.labels
+    }
+
+    // Set a register before running, e.g. to parameterize a program. Values
+    // are clamped to -999..999 like any other write.
+    pub fn set_register(&mut self, register: Register, value: i32) {
+        let value = value.clamp(-999, 999);
+        match register {
+            Register::Acc => self.acc = value,
+            Register::Bak => self.bak = value,
+        }
+    }
+
+    fn clamp_acc(&mut self) {
+        self.acc = self.acc.clamp(-999, 999);
+    }
+
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), String> {
+        // dbg only reports the machine, so it takes no cycles
+        if let Instruction::Dbg = instruction {
+            return self.execute_inner(instruction, None);
+        }
+        // Running out of input takes no cycle either
+        let input = match instruction {
+            Instruction::In(_) => match self.input.read() {
+                Ok(Some(value)) => Some(value),
+                Ok(None) => return self.input_exhausted(),
+                Err(e) => return Err(format!("Could not read input: {e}")),
+            },
+            _ => None,
+        };
+        for observer in &mut self.observers.0 {
+            observer.step(self.pc, self.cycles);
+        }
+        self.cycles += self.cycle_costs.cost(instruction);
+        if let Some(model) = &self.cost_model {
+            self.energy += model.weight(instruction);
+        }
+        self.instructions += 1;
+        let (pc, acc, bak) = (self.pc, self.acc, self.bak);
+        self.execute_inner(instruction, input)?;
+        if self.observers.0.is_empty() {
+            return Ok(());
+        }
+        let writes: &[Register] = match instruction {
+            Instruction::Mov(..)
+            | Instruction::Add(_)
+            | Instruction::In(_)
+            | Instruction::Lookup(_) => &[Register::Acc],
+            Instruction::Swp => &[Register::Acc, Register::Bak],
+            Instruction::Save => &[Register::Bak],
+            _ => &[],
+        };
+        for &register in writes {
+            let (old, new) = match register {
+                Register::Acc => (acc, self.acc),
+                Register::Bak => (bak, self.bak),
+            };
+            for observer in &mut self.observers.0 {
+                observer.register_write(pc, register, old, new);
+            }
+        }
+        Ok(())
+    }
+
+    fn input_exhausted(&mut self) -> Result<(), String> {
+        match self.on_input_end {
+            InputEnd::Error => Err("No more input".to_string()),
+            InputEnd::Halt => {
+                self.incoming = None;
+                self.stopping = Some(HaltReason::InputEnded);
+                self.pc = self.program.len();
+                Ok(())
+            }
+            InputEnd::Block => {
+                self.blocked = true;
+                Ok(())
+            }
+        }
+    }
+
+    // `input` is the value for an `in`, already read by `execute`
+    fn execute_inner(
+        &mut self,
+        instruction: &Instruction,
+        input: Option<i32>,
+    ) -> Result<(), String> {
+        match instruction {
+            Instruction::Mov(src, dst) => {
+                let value = self.read_value(*src);
+                self.write_value(*dst, value)?;
+            }
+            Instruction::Swp => std::mem::swap(&mut self.acc, &mut self.bak),
+            Instruction::Save => self.bak = self.acc,
+            Instruction::Add(src) => {
+                // Saturate first: a program built without the parser can
+                // hold any i32 immediate
+                self.acc = self.acc.saturating_add(self.read_value(*src));
+                self.clamp_acc();
+            }
+            Instruction::Jmp(label) => return self.jump(label),
+            Instruction::Jez(label) if self.acc == 0 => return self.jump(label),
+            Instruction::Jnz(label) if self.acc != 0 => return self.jump(label),
+            Instruction::Jgz(label) if self.acc > 0 => return self.jump(label),
+            Instruction::Jlz(label) if self.acc < 0 => return self.jump(label),
+            Instruction::Jez(_)
+            | Instruction::Jnz(_)
+            | Instruction::Jgz(_)
+            | Instruction::Jlz(_) => {}
+            Instruction::In(dst) => {
+                if let Some(value) = input {
+                    self.write_value(*dst, value)?;
+                }
+            }
+            Instruction::Out(src) => {
+                let value = self.read_value(*src).clamp(-999, 999);
+                self.check_output()?;
+                self.output
+                    .write(value)
+                    .map_err(|e| format!("Could not write output: {e}"))?;
+            }
+            Instruction::Outc(src) => {
+                let value = self.read_value(*src).clamp(-999, 999);
+                let Some(bytes) = self.encoding.encode(value) else {
+                    return Err(format!(
+                        "{} is not a character in {}",
+                        value,
+                        self.encoding.name()
+                    ));
+                };
+                self.check_output()?;
+                self.output
+                    .write_text(&bytes)
+                    .map_err(|e| format!("Could not write output: {e}"))?;
+            }
+            Instruction::Lookup(name) => {
+                let value = self.lookup(name)?;
+                self.write_value(Operand::Acc, value)?;
+            }
+            Instruction::Slp(src) => {
+                if !self.realtime {
+                    return Err("slp needs real-time mode".to_string());
+                }
+                let millis = self.read_value(*src).clamp(0, 999);
+                thread::sleep(Duration::from_millis(u64::from(millis.unsigned_abs())));
+            }
+            Instruction::Dbg => eprintln!(
+                "[dbg line {}] acc: {}, bak: {}, cycles: {}",
+                self.pc + 1,
+                self.acc,
+                self.bak,
+                self.cycles
+            ),
+            Instruction::Ret => {
+                // For now, ret just ends the program, so nothing more needs
+                // to be parsed
+                self.incoming = None;
+                self.stopping = Some(HaltReason::Ret);
+                self.pc = self.program.len();
+                return Ok(());
+            }
+            Instruction::Reti => match self.timer.as_mut().and_then(|t| t.return_pc.take()) {
+                Some(target) => {
+                    // Ticks due by the end of the `reti` are dropped too, so
+                    // the interrupted code runs before the next interrupt
+                    if let Some(timer) = self.timer.as_mut() {
+                        while timer.next_fire <= self.cycles {
+                            timer.next_fire += timer.period;
+                        }
+                    }
+                    for observer in &mut self.observers.0 {
+                        observer.interrupt_return(self.pc, target);
+                    }
+                    self.jump_history.record(self.pc, target);
+                    self.pc = target;
+                    return Ok(());
+                }
+                None => return Err("reti outside of interrupt handler".to_string()),
+            },
+        }
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn jump(&mut self, label: &str) -> Result<(), String> {
+        match self.resolve(label)? {
+            Some(target) => {
+                for observer in &mut self.observers.0 {
+                    observer.jump(self.pc, target);
+                }
+                self.jump_history.record(self.pc, target);
+                self.pc = target;
+                Ok(())
+            }
+            None => Err(
+                match parser::suggest(label, self.labels.keys().map(String::as_str)) {
+                    Some(l) => format!("Unknown label: {label} (did you mean {l}?)"),
+                    None => format!("Unknown label: {label}"),
+                },
+            ),
+        }
+    }
+
+    // Fail if one more write would go over `Limits::max_output`
+    fn check_output(&self) -> Result<(), String> {
+        match self.limits.max_output {
+            Some(max) if self.output.written >= max => {
+                Err(format!("Output exceeds the limit of {max} values"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The entry of table `name` at index acc, parsing further ahead while
+    // streaming
+    fn lookup(&mut self, name: &str) -> Result<i32, String> {
+        while !self.tables.contains_key(name) {
+            if !self.pull()? {
+                return Err(
+                    match parser::suggest(name, self.tables.keys().map(String::as_str)) {
+                        Some(t) => format!("Unknown table: {name} (did you mean {t}?)"),
+                        None => format!("Unknown table: {name}"),
+                    },
+                );
+            }
+        }
+        let table = &self.tables[name];
+        usize::try_from(self.acc)
+            .ok()
+            .and_then(|idx| table.get(idx))
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "Index {} is outside table {}, which has {} entries",
+                    self.acc,
+                    name,
+                    table.len()
+                )
+            })
+    }
+
+    fn read_value(&self, src: Operand) -> i32 {
+        match src {
+            Operand::Acc => self.acc,
+            Operand::Bak => self.bak,
+            Operand::Clk => (self.cycles % 1000) as i32,
+            Operand::Imm(value) => value,
+        }
+    }
+
+    fn write_value(&mut self, dst: Operand, value: i32) -> Result<(), String> {
+        match dst {
+            Operand::Acc => {
+                self.acc = value;
+                self.clamp_acc();
+                Ok(())
+            }
+            // bak cannot be written to directly
+            Operand::Bak => Err("Cannot write directly to bak".to_string()),
+            Operand::Imm(_) | Operand::Clk => Err(format!("Invalid destination: {dst:?}")),
+        }
+    }
+
+    pub fn print_state(&self) {
+        println!("acc: {}", self.acc);
+        println!("bak: {}", self.bak);
+        println!("cycles: {}", self.cycles);
+        println!("instructions: {}", self.instructions);
+        if self.cost_model.is_some() {
+            println!("energy: {}", self.energy);
+        }
+    }
+
+    // The machine state after `error` as a JSON `.tiscore` document, for
+    // post-mortem inspection. Line numbers are 1-based.
+    #[must_use]
+    pub fn core_dump(&self, error: &RuntimeError) -> String {
+        let timer = match &self.timer {
+            Some(t) => format!(
+                "{{\"period\":{},\"handler\":{},\"next_fire\":{},\"return_line\":{}}}",
+                t.period,
+                json::string(&t.handler),
+                t.next_fire,
+                t.return_pc
+                    .map_or("null".to_string(), |pc| (pc + 1).to_string())
+            ),
+            None => "null".to_string(),
+        };
+        let jumps: Vec<String> = error
+            .jumps
+            .iter()
+            .map(|(from, to)| format!("[{from},{to}]"))
+            .collect();
+        format!(
+            "{{\"version\":{},\"error\":{{\"line\":{},\"message\":{}}},\"acc\":{},\"bak\":{},\"line\":{},\"cycles\":{},\"instructions\":{},\"timer\":{},\"jumps\":[{}]}}",
+            json::string(env!("CARGO_PKG_VERSION")),
+            error.line,
+            json::string(&error.message),
+            self.acc,
+            self.bak,
+            self.pc + 1,
+            self.cycles,
+            self.instructions,
+            timer,
+            jumps.join(",")
+        )
+    }
+    // Parse the program and collect labels, rejecting programs over the
+    // limits. On success the warnings raised by the enabled lints are returned.
+    pub fn load_program<S: AsRef<str>>(
+        &mut self,
+        lines: &[S],
+    ) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+        if !self.substitute_env {
+            return self.load_source(lines);
+        }
+        let mut substituted = Vec::with_capacity(lines.len());
+        let mut diagnostics = Vec::new();
+        for (idx, text) in lines.iter().enumerate() {
+            match parser::substitute_env(idx, text.as_ref()) {
+                Ok(text) => substituted.push(text),
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        self.load_source(&substituted)
+    }
+
+    fn load_source<S: AsRef<str>>(
+        &mut self,
+        lines: &[S],
+    ) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+        let parsed = self.parse_source(lines)?;
+        self.load_parsed(lines, parsed)
+    }
+
+    // Parse `lines`, through the cache if it is enabled. Only the syntax and
+    // the line limit are checked; everything else is left to `load_parsed`.
+    pub fn parse_source<S: AsRef<str>>(&self, lines: &[S]) -> Result<Program, Vec<Diagnostic>> {
+        if let Some(max) = self.limits.max_lines
+            && lines.len() > max
+        {
+            return Err(vec![Diagnostic::error(
+                "limit",
+                format!("Program has {} lines, limit is {}", lines.len(), max),
+                None,
+            )]);
+        }
+        if !self.cache {
+            return parser::parse(lines);
+        }
+        if let Some(parsed) = cache::load(lines) {
+            return Ok(parsed);
+        }
+        let parsed = parser::parse(lines)?;
+        cache::store(lines, &parsed);
+        Ok(parsed)
+    }
+
+    // The limit errors for the size of `parsed`: its labels, instructions
+    // and table values
+    fn size_limits(&self, parsed: &Program) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        if let Some(max) = self.limits.max_label_len {
+            for (label, span) in parsed.lines.iter().filter_map(|l| l.label.as_ref()) {
+                if span.len > max {
+                    diagnostics.push(Diagnostic::error(
+                        "limit",
+                        format!(
+                            "Label {} is {} characters, limit is {}",
+                            label, span.len, max
+                        ),
+                        Some(*span),
+                    ));
+                }
+            }
+        }
+        let instructions = parsed
+            .lines
+            .iter()
+            .filter(|l| l.instruction.is_some())
+            .count();
+        if let Some(max) = self.limits.max_instructions
+            && instructions > max
+        {
+            diagnostics.push(Diagnostic::error(
+                "limit",
+                format!("Program has {instructions} instructions, limit is {max}"),
+                None,
+            ));
+        }
+        let table_values: usize = parsed
+            .lines
+            .iter()
+            .filter_map(|l| l.table.as_ref())
+            .map(|t| t.values.len())
+            .sum();
+        if let Some(max) = self.limits.max_table_values
+            && table_values > max
+        {
+            diagnostics.push(Diagnostic::error(
+                "limit",
+                format!("Program has {table_values} table values, limit is {max}"),
+                None,
+            ));
+        }
+        diagnostics
+    }
+
+    // The line index to start `parsed` at: the label given by `entry` or the
+    // program's `.entry`, else the first line
+    fn entry_point(&self, parsed: &Program) -> Result<usize, Diagnostic> {
+        let Some(label) = self.entry.as_deref().or(parsed.entry_label()) else {
+            return Ok(0);
+        };
+        if let Some(&idx) = parsed.labels.get(label) {
+            return Ok(idx);
+        }
+        // Only a `.entry` in the source has a span to point at
+        let span = match &self.entry {
+            Some(_) => None,
+            None => parsed
+                .entry
+                .and_then(|idx| parsed.lines[idx].entry.as_ref()),
+        };
+        let diagnostic = Diagnostic::error(
+            "unknown-entry",
+            format!("Entry label {label} is not defined"),
+            span.map(|&(_, span)| span),
+        );
+        Err(
+            match parser::suggest(label, parsed.labels.keys().map(String::as_str)) {
+                Some(l) => diagnostic.with_suggestion(&format!("did you mean `{l}`?")),
+                None => diagnostic,
+            },
+        )
+    }
+
+    // Check `parsed`, the parse of `lines`, against the profile, limits and
+    // lints, and load it if none of them reject it. Returns the warnings,
+    // like `load_program`, but makes no environment substitution.
+    pub fn load_parsed<S: AsRef<str>>(
+        &mut self,
+        lines: &[S],
+        mut parsed: Program,
+    ) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+        if self.strip_dbg {
+            for line in &mut parsed.lines {
+                strip_dbg(line);
+            }
+        }
+        let mut diagnostics = self.profile.check(lines, &parsed);
+        if self.strict_bak || self.profile == Profile::Tis100 {
+            diagnostics.extend(profile::bak_reads(&parsed));
+        }
+        diagnostics.extend(self.size_limits(&parsed));
+        let entry = self.entry_point(&parsed).unwrap_or_else(|diagnostic| {
+            diagnostics.push(diagnostic);
+            0
+        });
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        let handler = self.timer.as_ref().map(|t| t.handler.as_str());
+        let warnings = lint::check(&parsed, handler, self.entry.as_deref(), &self.lints);
+        if warnings.iter().any(|w| w.severity == Severity::Error) {
+            return Err(warnings);
+        }
+        self.tables = parsed
+            .lines
+            .iter_mut()
+            .filter_map(|l| l.table.take())
+            .map(|table| (table.name, table.values))
+            .collect();
+        self.program = Arc::new(parsed.lines.into_iter().map(|l| l.instruction).collect());
+        self.labels = parsed.labels;
+        self.incoming = None;
+        self.pc = entry;
+        self.start = entry;
+        self.halt_reason = None;
+        self.stopping = None;
+        self.started = None;
+        self.jump_history.jumps.clear();
+        Ok(warnings)
+    }
+    // Fire the timer interrupt if its deadline has passed. Ticks that expire
+    // while the handler is still running, up to and including its `reti`,
+    // are dropped rather than held, and so are ticks once the program is
+    // stopping: an interrupt never resumes a program that has ended.
+    fn check_timer(&mut self) -> Result<(), String> {
+        let ended = self.stopping.is_some()
+            || self.halt_reason.is_some()
+            || (self.incoming.is_none() && self.pc >= self.program.len());
+        let Some(timer) = self.timer.as_mut() else {
+            return Ok(());
+        };
+        if self.cycles < timer.next_fire {
+            return Ok(());
+        }
+        while timer.next_fire <= self.cycles {
+            timer.next_fire += timer.period;
+        }
+        if timer.return_pc.is_some() || ended {
+            return Ok(());
+        }
+        let handler = timer.handler.clone();
+        let Some(target) = self.resolve(&handler)? else {
+            return Err(format!("Unknown timer handler label: {handler}"));
+        };
+        if let Some(timer) = self.timer.as_mut() {
+            timer.return_pc = Some(self.pc);
+        }
+        for observer in &mut self.observers.0 {
+            observer.interrupt(self.pc, target);
+        }
+        self.jump_history.record(self.pc, target);
+        self.pc = target;
+        Ok(())
+    }
+    // Execute the next instruction, skipping blank, comment and label-only
+    // lines. Returns the line index that was executed, or `None` once the
+    // program has halted or is blocked waiting for input.
+    pub fn step(&mut self) -> Result<Option<usize>, RuntimeError> {
+        // Once halted, by an error or a limit as much as by finishing, the
+        // program stays halted until it is loaded or reset again
+        if self.halt_reason.is_some() {
+            return Ok(None);
+        }
+        self.blocked = false;
+        if let Some(max) = self.limits.max_cycles
+            && self.cycles >= max
+            && !self.at_end()
+        {
+            self.halt(HaltReason::CycleLimit);
+            return Ok(None);
+        }
+        if let Some(timeout) = self.limits.timeout
+            && !self.at_end()
+        {
+            let started = *self.started.get_or_insert_with(Instant::now);
+            if self.instructions.is_multiple_of(TIMEOUT_CHECK_INTERVAL)
+                && started.elapsed() >= timeout
+            {
+                self.halt(HaltReason::Timeout);
+                return Ok(None);
+            }
+        }
+        loop {
+            let program = Arc::clone(&self.program);
+            match program.get(self.pc) {
+                Some(None) => self.pc += 1,
+                Some(Some(instruction)) => {
+                    let pc = self.pc;
+                    let result = self.execute(instruction).and_then(|()| self.check_timer());
+                    return match result {
+                        Ok(()) if self.blocked => Ok(None),
+                        Ok(()) => Ok(Some(pc)),
+                        Err(message) => Err(self.fail(pc, message)),
+                    };
+                }
+                None => {
+                    drop(program);
+                    match self.pull() {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(message) => return Err(self.fail(self.pc, message)),
+                    }
+                }
+            }
+        }
+        let reason = self.stopping.take().unwrap_or(HaltReason::RanOffEnd);
+        self.halt(reason);
+        Ok(None)
+    }
+
+    // Stop with a runtime error raised at line index `pc`
+    fn fail(&mut self, pc: usize, message: String) -> RuntimeError {
+        let error = RuntimeError {
+            line: pc + 1,
+            message,
+            jumps: self
+                .jump_history
+                .iter()
+                .map(|(f, t)| (f + 1, t + 1))
+                .collect(),
+        };
+        self.halt(HaltReason::Error(error.clone()));
+        error
+    }
+
+    // Start running `reader` before it has been fully read: lines are parsed
+    // on a background thread and appended to the program as they arrive.
+    // Execution waits when it reaches the end of what has been parsed, or
+    // jumps to a label that has not been seen yet. Lints and limits need the
+    // whole program, so they are not applied; an invalid line stops execution
+    // as soon as the parser reaches it. Execution starts at the first line,
+    // so `.entry` is an error and `entry` is ignored.
+    pub fn load_streaming<R: BufRead + Send + 'static>(&mut self, reader: R) {
+        let (sender, receiver) = mpsc::channel();
+        let substitute = self.substitute_env;
+        thread::spawn(move || {
+            for (idx, text) in reader.lines().enumerate() {
+                let parsed = match text {
+                    Ok(text) if substitute => match parser::substitute_env(idx, &text) {
+                        Ok(text) => parser::parse_line(idx, &text),
+                        Err(d) => (Line::default(), vec![d]),
+                    },
+                    Ok(text) => parser::parse_line(idx, &text),
+                    Err(e) => (
+                        Line::default(),
+                        vec![Diagnostic::error("io", e.to_string(), None)],
+                    ),
+                };
+                if sender.send(parsed).is_err() {
+                    return;
+                }
+            }
+        });
+        self.program = Arc::new(Vec::new());
+        self.labels.clear();
+        self.tables.clear();
+        self.incoming = Some(receiver);
+        self.pc = 0;
+        self.start = 0;
+        self.halt_reason = None;
+        self.stopping = None;
+        self.jump_history.jumps.clear();
+    }
+
+    // Append the next streamed line to the program. Returns false once the
+    // whole input has been parsed.
+    fn pull(&mut self) -> Result<bool, String> {
+        let Some(incoming) = &self.incoming else {
+            return Ok(false);
+        };
+        let Ok((line, diagnostics)) = incoming.recv() else {
+            self.incoming = None;
+            return Ok(false);
+        };
+        let mut line = line;
+        if self.strip_dbg {
+            strip_dbg(&mut line);
+        }
+        let idx = self.program.len();
+        if let Some(diagnostic) = diagnostics.first() {
+            self.incoming = None;
+            return Err(format!("{} (line {})", diagnostic.message, idx + 1));
+        }
+        if let Some(max) = self.limits.max_lines
+            && idx >= max
+        {
+            self.incoming = None;
+            return Err(format!("Program is over the limit of {max} lines"));
+        }
+        if let Some((name, _)) = line.label {
+            if let Some(&prev) = self.labels.get(&name) {
+                self.incoming = None;
+                return Err(format!(
+                    "Duplicate label: {} (first defined on line {}, again on line {})",
+                    name,
+                    prev + 1,
+                    idx + 1
+                ));
+            }
+            self.labels.insert(name, idx);
+        }
+        if line.entry.is_some() {
+            self.incoming = None;
+            return Err(format!(
+                ".entry cannot be used while streaming (line {})",
+                idx + 1
+            ));
+        }
+        if let Some(table) = line.table {
+            if self.tables.contains_key(&table.name) {
+                self.incoming = None;
+                return Err(format!(
+                    "Duplicate table: {} (again on line {})",
+                    table.name,
+                    idx + 1
+                ));
+            }
+            let table_values: usize = self.tables.values().map(Vec::len).sum();
+            if let Some(max) = self.limits.max_table_values
+                && table_values + table.values.len() > max
+            {
+                self.incoming = None;
+                return Err(format!(
+                    "Program is over the limit of {} table values (line {})",
+                    max,
+                    idx + 1
+                ));
+            }
+            self.tables.insert(table.name, table.values);
+        }
+        Arc::make_mut(&mut self.program).push(line.instruction);
+        Ok(true)
+    }
+
+    // The line index of `label`, parsing further ahead while streaming
+    fn resolve(&mut self, label: &str) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(&target) = self.labels.get(label) {
+                return Ok(Some(target));
+            }
+            if !self.pull()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Record why execution stopped and notify observers, the first time it
+    // does
+    fn halt(&mut self, reason: HaltReason) {
+        if self.halt_reason.is_some() {
+            return;
+        }
+        let error = match &reason {
+            HaltReason::Error(error) => Some(error),
+            _ => None,
+        };
+        for observer in &mut self.observers.0 {
+            observer.halt(self.cycles, error);
+        }
+        self.halt_reason = Some(reason);
+    }
+
+    // Why the last run stopped: how the program halted, or that it is
+    // waiting for input. `None` while it can still run.
+    #[must_use]
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        match &self.halt_reason {
+            Some(reason) => Some(reason.clone()),
+            None => self.blocked.then_some(HaltReason::Blocked),
+        }
+    }
+
+    // End a run blocked waiting for input that will not come: it halts for
+    // good as `HaltReason::Blocked`, so observers are told and can finish
+    // what they write. Does nothing unless the last step blocked.
+    pub fn finish(&mut self) {
+        if self.blocked {
+            self.halt(HaltReason::Blocked);
+        }
+    }
+
+    // Run the loaded program until it halts, or blocks waiting for input
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        while self.step()?.is_some() {}
+        Ok(())
+    }
+
+    // Run at most `cycles` cycles, so callers can interleave emulation with
+    // other work and resume with another call
+    pub fn run_for(&mut self, cycles: u64) -> Result<RunOutcome, RuntimeError> {
+        let end = self.cycles.saturating_add(cycles);
+        while self.cycles < end {
+            if self.step()?.is_none() {
+                return Ok(if self.blocked {
+                    RunOutcome::Blocked
+                } else {
+                    RunOutcome::Halted
+                });
+            }
+        }
+        // A program that has just executed its last instruction halts without
+        // taking another cycle
+        if self.at_end() {
+            self.step()?;
+        }
+        if self.is_halted() {
+            Ok(RunOutcome::Halted)
+        } else {
+            Ok(RunOutcome::BudgetExhausted)
+        }
+    }
+
+    // Run until the program halts or `cancel` is set, e.g. from another
+    // thread. The flag is checked every `CANCEL_CHECK_INTERVAL` cycles.
+    pub fn run_with_cancel(&mut self, cancel: &AtomicBool) -> Result<RunOutcome, RuntimeError> {
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(RunOutcome::Cancelled);
+            }
+            match self.run_for(CANCEL_CHECK_INTERVAL)? {
+                RunOutcome::BudgetExhausted => {}
+                outcome => return Ok(outcome),
+            }
+        }
+    }
+
+    // Whether the program has halted, so stepping does nothing more
+    #[must_use]
+    pub fn is_halted(&self) -> bool {
+        self.halt_reason.is_some()
+    }
+
+    // Whether the next step would find no instruction left to execute, with
+    // the whole program loaded
+    fn at_end(&self) -> bool {
+        self.incoming.is_none()
+            && self.program[self.pc.min(self.program.len())..]
+                .iter()
+                .all(Option::is_none)
+    }
+
+    // Step through the program, yielding the state after each instruction
+    pub fn iter_states(&mut self) -> States<'_> {
+        States {
+            emu: self,
+            done: false,
+        }
+    }
+}
+
+// Why `Emulator::run_for` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    // The program ran to completion
+    Halted,
+    // The cycle budget ran out with the program still running
+    BudgetExhausted,
+    // The cancellation flag was set with the program still running
+    Cancelled,
+    // The program is waiting at an `in` for more input
+    Blocked,
+}
+
+// The part of a run's progress kept outside the public fields, from
+// `Emulator::run_state`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunState {
+    pub halt_reason: Option<HaltReason>,
+    // The reason the program gives when it next halts, after a `ret` or an
+    // `in` past the end of the input has moved it off the last line
+    pub stopping: Option<HaltReason>,
+    // The timer's next deadline and the line index its handler returns to
+    pub timer: Option<(u64, Option<usize>)>,
+    // Recent jumps as line indices, oldest first
+    pub jumps: Vec<(usize, usize)>,
+}
+
+// Why a program stopped running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HaltReason {
+    // Execution moved past the last instruction
+    RanOffEnd,
+    // A `ret` ended the program
+    Ret,
+    // An `in` found no more input, under `InputEnd::Halt`
+    InputEnded,
+    // The run reached `Limits::max_cycles`
+    CycleLimit,
+    // The run went on past `Limits::timeout`
+    Timeout,
+    // An `in` is waiting for more input, under `InputEnd::Block`
+    Blocked,
+    Error(RuntimeError),
+}
+
+impl HaltReason {
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            HaltReason::RanOffEnd => "ran-off-end",
+            HaltReason::Ret => "ret",
+            HaltReason::InputEnded => "input-ended",
+            HaltReason::CycleLimit => "cycle-limit",
+            HaltReason::Timeout => "timeout",
+            HaltReason::Blocked => "blocked",
+            HaltReason::Error(_) => "error",
+        }
+    }
+
+    // The reason called `name`, for those that carry nothing beyond it
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<HaltReason> {
+        [
+            HaltReason::RanOffEnd,
+            HaltReason::Ret,
+            HaltReason::InputEnded,
+            HaltReason::CycleLimit,
+            HaltReason::Timeout,
+            HaltReason::Blocked,
+        ]
+        .into_iter()
+        .find(|reason| reason.name() == name)
+    }
+
+    // Whether the program finished, rather than being cut short
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        matches!(
+            self,
+            HaltReason::RanOffEnd | HaltReason::Ret | HaltReason::InputEnded
+        )
+    }
+
+    // The process exit status for a run that stopped this way
+    #[must_use]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            HaltReason::RanOffEnd | HaltReason::Ret | HaltReason::InputEnded => 0,
+            HaltReason::Error(_) => 1,
+            HaltReason::CycleLimit | HaltReason::Timeout => 2,
+            HaltReason::Blocked => 3,
+        }
+    }
+}
+
+const CANCEL_CHECK_INTERVAL: u64 = 1024;
+// Instructions between looks at the clock, for `Limits::timeout`
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+// A failure while executing, reported against a 1-based source line along
+// with the recent jump history as (from, to) 1-based lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub line: usize,
+    pub message: String,
+    pub jumps: Vec<(usize, usize)>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Error on line {}: {}", self.line, self.message)?;
+        if !self.jumps.is_empty() {
+            write!(f, "\nRecent jumps (oldest first):")?;
+            for (from, to) in &self.jumps {
+                write!(f, "\n  line {from} -> line {to}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The machine right after one instruction executed
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub cycle: u64,
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub acc: i32,
+    pub bak: i32,
+}
+
+// Iterator returned by `Emulator::iter_states`. It ends when the program
+// halts; a runtime error is yielded once and then the iterator ends.
+pub struct States<'a> {
+    emu: &'a mut Emulator,
+    done: bool,
+}
+
+impl Iterator for States<'_> {
+    type Item = Result<State, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.emu.step() {
+            Ok(Some(pc)) => {
+                let instruction = self.emu.program[pc].clone()?;
+                Some(Ok(State {
+                    cycle: self.emu.cycles,
+                    pc,
+                    instruction,
+                    acc: self.emu.acc,
+                    bak: self.emu.bak,
+                }))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loaded(source: &[&str]) -> Emulator {
+        let mut emu = Emulator::new();
+        emu.load_program(source).unwrap();
+        emu
+    }
+
+    #[test]
+    fn rejects_free_instructions() {
+        let mut costs = CycleCosts::default();
+        assert_eq!(
+            costs.set("jmp", 0).unwrap_err(),
+            "jmp must cost at least one cycle"
+        );
+        assert!(costs.set("nope", 2).is_err());
+        costs.set("JMP", 3).unwrap();
+        assert_eq!(costs.cost(&Instruction::Jmp("l".to_string())), 3);
+        assert_eq!(costs.cost(&Instruction::Swp), 1);
+    }
+
+    #[test]
+    fn cycle_costs_weight_the_cycle_limit() {
+        let mut emu = loaded(&["l: jmp l"]);
+        emu.cycle_costs.set("jmp", 5).unwrap();
+        emu.limits.max_cycles = Some(100);
+        emu.run().unwrap();
+        assert_eq!(emu.halt_reason(), Some(HaltReason::CycleLimit));
+        assert_eq!((emu.cycles, emu.instructions), (100, 20));
+    }
+
+    #[test]
+    fn add_clamps_without_overflowing() {
+        let mut emu = loaded(&["mov 999, acc", "add 2147483647"]);
+        emu.run().unwrap();
+        assert_eq!(emu.acc, 999);
+        emu.acc = -999;
+        emu.execute(&Instruction::Add(Operand::Imm(i32::MIN)))
+            .unwrap();
+        assert_eq!(emu.acc, -999);
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Event {
+        Step(usize, u64),
+        Interrupt(usize, usize),
+        Return(usize, usize),
+    }
+
+    // Records steps and timer events, shared with the test
+    struct Events(Arc<std::sync::Mutex<Vec<Event>>>);
+
+    impl Observer for Events {
+        fn step(&mut self, pc: usize, cycle: u64) {
+            self.0.lock().unwrap().push(Event::Step(pc, cycle));
+        }
+
+        fn interrupt(&mut self, from: usize, to: usize) {
+            self.0.lock().unwrap().push(Event::Interrupt(from, to));
+        }
+
+        fn interrupt_return(&mut self, from: usize, to: usize) {
+            self.0.lock().unwrap().push(Event::Return(from, to));
+        }
+    }
+
+    // Run `source` with a timer every `period` cycles into `timer`, for
+    // `cycles` cycles, returning the events
+    fn timed(source: &[&str], period: u64, cycles: u64) -> (Emulator, Vec<Event>) {
+        let mut emu = loaded(source);
+        emu.set_timer(period, "timer");
+        let events = Arc::default();
+        emu.add_observer(Box::new(Events(Arc::clone(&events))));
+        emu.limits.max_cycles = Some(cycles);
+        emu.run().unwrap();
+        let events = events.lock().unwrap().clone();
+        (emu, events)
+    }
+
+    // The cycle at which the handler started after each interrupt
+    fn handler_starts(events: &[Event]) -> Vec<u64> {
+        events
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [Event::Interrupt(..), Event::Step(_, cycle)] => Some(*cycle),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn timer_fires_every_period() {
+        let (_, events) = timed(&["main: jmp main", "timer: reti"], 5, 30);
+        assert_eq!(handler_starts(&events), [5, 10, 15, 20, 25]);
+        assert!(events.contains(&Event::Interrupt(0, 1)));
+    }
+
+    #[test]
+    fn reti_returns_to_the_interrupted_line() {
+        let source = ["main: add 1", "jmp main", "timer: reti"];
+        let (emu, events) = timed(&source, 4, 40);
+        let mut interrupted = None;
+        let mut returns = 0;
+        for event in &events {
+            match *event {
+                Event::Interrupt(from, to) => {
+                    assert_eq!(to, 2);
+                    interrupted = Some(from);
+                }
+                Event::Return(from, to) => {
+                    assert_eq!(from, 2);
+                    assert_eq!(Some(to), interrupted.take());
+                    returns += 1;
+                }
+                Event::Step(..) => {}
+            }
+        }
+        assert!(returns > 5);
+        // Both main lines are interrupted at some point, and every `add` ran
+        let froms: Vec<usize> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Interrupt(from, _) => Some(*from),
+                _ => None,
+            })
+            .collect();
+        assert!(froms.contains(&0) && froms.contains(&1));
+        let adds = events
+            .iter()
+            .filter(|e| matches!(e, Event::Step(0, _)))
+            .count();
+        assert_eq!(emu.acc, i32::try_from(adds).unwrap());
+    }
+
+    #[test]
+    fn ticks_during_the_handler_are_dropped() {
+        // The handler takes 5 cycles, longer than the period of 3
+        let source = [
+            "main: jmp main",
+            "timer: swp",
+            "add 1",
+            "swp",
+            "jmp done",
+            "done: reti",
+        ];
+        let (emu, events) = timed(&source, 3, 38);
+        assert_eq!(handler_starts(&events), [3, 9, 15, 21, 27, 33]);
+        // Never re-entered: each interrupt is followed by its return
+        let timer_events: Vec<&Event> = events
+            .iter()
+            .filter(|e| !matches!(e, Event::Step(..)))
+            .collect();
+        for pair in timer_events.chunks(2) {
+            assert!(matches!(pair[0], Event::Interrupt(0, 1)));
+            if let Some(ret) = pair.get(1) {
+                assert_eq!(**ret, Event::Return(5, 0));
+            }
+        }
+        assert_eq!(timer_events.len(), 12);
+        assert_eq!(emu.bak, 6);
+    }
+
+    #[test]
+    fn run_for_stops_when_the_budget_runs_out() {
+        let mut emu = loaded(&["l: jmp l"]);
+        assert_eq!(emu.run_for(10).unwrap(), RunOutcome::BudgetExhausted);
+        assert_eq!(emu.cycles, 10);
+        // Another call carries on where the last one stopped
+        assert_eq!(emu.run_for(5).unwrap(), RunOutcome::BudgetExhausted);
+        assert_eq!(emu.cycles, 15);
+        assert!(!emu.is_halted());
+    }
+
+    #[test]
+    fn run_for_reports_a_halt_within_the_budget() {
+        let mut emu = loaded(&["add 1", "add 1"]);
+        assert_eq!(emu.run_for(100).unwrap(), RunOutcome::Halted);
+        assert_eq!((emu.acc, emu.cycles), (2, 2));
+        // Spending exactly the budget on the last instruction still halts
+        emu.reset();
+        assert_eq!(emu.run_for(2).unwrap(), RunOutcome::Halted);
+        assert_eq!(emu.halt_reason(), Some(HaltReason::RanOffEnd));
+        assert_eq!(emu.run_for(10).unwrap(), RunOutcome::Halted);
+        assert_eq!(emu.cycles, 2);
+    }
+
+    #[test]
+    fn run_for_reports_blocking_and_errors() {
+        let mut emu = loaded(&["in acc"]);
+        emu.on_input_end = InputEnd::Block;
+        assert_eq!(emu.run_for(10).unwrap(), RunOutcome::Blocked);
+        assert_eq!(emu.cycles, 0);
+        let mut emu = loaded(&["mov 1, acc", "jmp nowhere"]);
+        let error = emu.run_for(10).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn run_with_cancel_stops_when_cancelled() {
+        let mut emu = loaded(&["l: jmp l"]);
+        let cancel = AtomicBool::new(true);
+        assert_eq!(emu.run_with_cancel(&cancel).unwrap(), RunOutcome::Cancelled);
+        assert_eq!(emu.cycles, 0);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let setter = Arc::clone(&cancel);
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            setter.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(emu.run_with_cancel(&cancel).unwrap(), RunOutcome::Cancelled);
+        canceller.join().unwrap();
+        // The flag is only checked between intervals
+        assert!(emu.cycles > 0);
+        assert_eq!(emu.cycles % CANCEL_CHECK_INTERVAL, 0);
+        assert!(!emu.is_halted());
+    }
+
+    #[test]
+    fn run_with_cancel_runs_to_the_end_otherwise() {
+        let mut emu = loaded(&["mov 3, acc", "l: add -1", "jnz l"]);
+        let cancel = AtomicBool::new(false);
+        assert_eq!(emu.run_with_cancel(&cancel).unwrap(), RunOutcome::Halted);
+        assert_eq!((emu.acc, emu.cycles), (0, 7));
+    }
+
+    #[test]
+    fn lookup_replaces_acc_with_the_entry() {
+        let mut emu = loaded(&[
+            "loop: swp",
+            "save",
+            "lookup squares",
+            "out acc",
+            "swp",
+            "add 1",
+            "save",
+            "add -4",
+            "jnz loop",
+            ".table squares: 0, 1, 4, 9",
+        ]);
+        emu.run().unwrap();
+        assert_eq!(emu.output(), [0, 1, 4, 9]);
+    }
+
+    #[test]
+    fn lookup_reports_bad_indices_and_tables() {
+        let message = |source: &[&str]| loaded(source).run().unwrap_err().message;
+        let table = ".table squares: 0, 1, 4";
+        assert_eq!(
+            message(&["mov 3, acc", "lookup squares", table]),
+            "Index 3 is outside table squares, which has 3 entries"
+        );
+        assert_eq!(
+            message(&["mov -1, acc", "lookup squares", table]),
+            "Index -1 is outside table squares, which has 3 entries"
+        );
+        assert_eq!(
+            message(&["lookup squres", table]),
+            "Unknown table: squres (did you mean squares?)"
+        );
+        assert_eq!(message(&["lookup cubes"]), "Unknown table: cubes");
+    }
+
+    #[test]
+    fn starts_at_the_entry() {
+        let source = [".entry main", "out 1", "main: out 2", "other: out 3"];
+        let mut emu = loaded(&source);
+        emu.run().unwrap();
+        assert_eq!(emu.output(), [2, 3]);
+        emu.reset();
+        assert_eq!(emu.pc, 2);
+
+        // `entry` overrides the program's own
+        let mut emu = Emulator::new();
+        emu.entry = Some("other".to_string());
+        emu.load_program(&source).unwrap();
+        emu.run().unwrap();
+        assert_eq!(emu.output(), [3]);
+    }
+
+    #[test]
+    fn rejects_unknown_entries() {
+        let diagnostics = Emulator::new()
+            .load_program(&[".entry mainn", "main: swp"])
+            .unwrap_err();
+        assert_eq!(diagnostics[0].code, "unknown-entry");
+        assert_eq!(diagnostics[0].message, "Entry label mainn is not defined");
+        assert_eq!(diagnostics[0].span.map(|s| s.line), Some(1));
+        assert_eq!(
+            diagnostics[0].suggestion.as_deref(),
+            Some("did you mean `main`?")
+        );
+
+        // A label given by the caller is not anywhere in the source
+        let mut emu = Emulator::new();
+        emu.entry = Some("nowhere".to_string());
+        let diagnostics = emu.load_program(&["main: swp"]).unwrap_err();
+        assert_eq!(diagnostics[0].code, "unknown-entry");
+        assert_eq!(diagnostics[0].span, None);
+    }
+
+    #[test]
+    fn streaming_rejects_the_entry() {
+        let mut emu = Emulator::new();
+        emu.load_streaming(std::io::Cursor::new("swp\n.entry main\nmain: swp\n"));
+        assert_eq!(
+            emu.run().unwrap_err().message,
+            ".entry cannot be used while streaming (line 2)"
+        );
+    }
+
+    #[test]
+    fn substitutes_the_environment_when_asked() {
+        let source = [
+            "mov ${CARGO_PKG_VERSION_MINOR}, acc",
+            "out acc",
+            "mov 0, acc",
+            "lookup t",
+            "out acc",
+            ".table t: ${CARGO_PKG_VERSION_MINOR}",
+        ];
+        let minor: i32 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap();
+        let mut emu = Emulator::new();
+        emu.substitute_env = true;
+        emu.load_program(&source).unwrap();
+        emu.run().unwrap();
+        assert_eq!(emu.output(), [minor, minor]);
+
+        // Left alone, `${..}` is not an operand
+        let diagnostics = Emulator::new().load_program(&source).unwrap_err();
+        assert_eq!(
+            diagnostics[0].suggestion.as_deref(),
+            Some("load with --env-subst to substitute it")
+        );
+    }
+
+    #[test]
+    fn reports_every_bad_substitution() {
+        let mut emu = Emulator::new();
+        emu.substitute_env = true;
+        let diagnostics = emu
+            .load_program(&[
+                "mov ${TIS31337_TEST_UNSET}, acc",
+                "swp",
+                "add ${CARGO_PKG_NAME}",
+            ])
+            .unwrap_err();
+        let found: Vec<(&str, Option<usize>)> = diagnostics
+            .iter()
+            .map(|d| (d.code, d.span.map(|s| s.line)))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("undefined-variable", Some(1)),
+                ("invalid-variable", Some(3))
+            ]
+        );
+    }
+
+    fn sandboxed(sandbox: &Sandbox) -> Emulator {
+        let mut emu = Emulator::new();
+        emu.sandbox(sandbox);
+        emu
+    }
+
+    // The messages of the limits `source` breaks under `sandbox`
+    fn broken_limits(sandbox: &Sandbox, source: &[&str]) -> Vec<String> {
+        let diagnostics = sandboxed(sandbox).load_program(source).unwrap_err();
+        assert!(diagnostics.iter().all(|d| d.code == "limit"));
+        diagnostics.into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn sandbox_limits_the_program_size() {
+        let sandbox = Sandbox {
+            max_lines: 4,
+            max_instructions: 2,
+            max_label_len: 3,
+            max_table_values: 3,
+            ..Sandbox::default()
+        };
+        assert_eq!(
+            broken_limits(&sandbox, &["swp"; 5]),
+            ["Program has 5 lines, limit is 4"]
+        );
+        assert_eq!(
+            broken_limits(
+                &sandbox,
+                &["long: swp", "swp", "swp", ".table t: 1, 2, 3, 4"]
+            ),
+            [
+                "Label long is 4 characters, limit is 3",
+                "Program has 3 instructions, limit is 2",
+                "Program has 4 table values, limit is 3",
+            ]
+        );
+        sandboxed(&sandbox)
+            .load_program(&["abc: swp", "swp", ".table t: 1, 2, 3"])
+            .unwrap();
+    }
+
+    #[test]
+    fn sandbox_limits_the_run() {
+        let looping = ["l: out 1", "jmp l"];
+        let mut emu = sandboxed(&Sandbox {
+            max_cycles: 10,
+            ..Sandbox::default()
+        });
+        emu.load_program(&looping).unwrap();
+        emu.run().unwrap();
+        assert_eq!(emu.halt_reason(), Some(HaltReason::CycleLimit));
+        assert_eq!(emu.cycles, 10);
+
+        let mut emu = sandboxed(&Sandbox {
+            max_output: 3,
+            ..Sandbox::default()
+        });
+        emu.load_program(&looping).unwrap();
+        let error = emu.run().unwrap_err();
+        assert_eq!(error.message, "Output exceeds the limit of 3 values");
+        assert_eq!(emu.output(), [1, 1, 1]);
+
+        let mut emu = sandboxed(&Sandbox {
+            timeout: Duration::ZERO,
+            ..Sandbox::default()
+        });
+        emu.load_program(&looping).unwrap();
+        emu.run().unwrap();
+        assert_eq!(emu.halt_reason(), Some(HaltReason::Timeout));
+    }
+
+    #[test]
+    fn sandbox_turns_off_sleeping_and_debug_output() {
+        let mut emu = Emulator::new();
+        emu.realtime = true;
+        emu.sandbox(&Sandbox::default());
+        assert!(!emu.realtime && !emu.cache && emu.strip_dbg);
+        emu.load_program(&["dbg", "slp 1"]).unwrap();
+        assert_eq!(emu.run().unwrap_err().message, "slp needs real-time mode");
+        assert_eq!(emu.instructions, 1);
+    }
+
+    #[test]
+    fn limits_fall_back_one_by_one() {
+        let own = Limits {
+            max_cycles: Some(5),
+            ..Limits::default()
+        };
+        let limits = own.or(Sandbox::default().limits());
+        assert_eq!(limits.max_cycles, Some(5));
+        assert_eq!(limits.max_lines, Some(1000));
+        assert_eq!(Limits::default().or(Limits::default()).max_lines, None);
+    }
+}
@@ -0,0 +1,103 @@
+// Minimal helpers for writing JSON output, and for reading the flat objects
+// of numbers used as configuration
+
+use std::fmt::Write;
+
+// Quote and escape a string as a JSON string literal
+pub fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err("expected a string".to_string());
+    }
+    let mut out = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(out),
+            Some('\\') => match chars.next() {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('/') => out.push('/'),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('r') => out.push('\r'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid escape \\u{hex}"))?;
+                    out.push(c);
+                }
+                _ => return Err("invalid escape in string".to_string()),
+            },
+            Some(c) => out.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+// The members of a JSON object whose values are all numbers, in order
+pub fn number_object(text: &str) -> Result<Vec<(String, f64)>, String> {
+    let mut chars = text.chars().peekable();
+    skip_whitespace(&mut chars);
+    if chars.next() != Some('{') {
+        return Err("expected an object".to_string());
+    }
+    let mut members = Vec::new();
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_none() {
+        loop {
+            skip_whitespace(&mut chars);
+            let key = parse_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return Err(format!("expected `:` after {}", string(&key)));
+            }
+            skip_whitespace(&mut chars);
+            let mut number = String::new();
+            while let Some(c) =
+                chars.next_if(|c| matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+            {
+                number.push(c);
+            }
+            let value = number
+                .parse()
+                .map_err(|_| format!("the value of {} must be a number", string(&key)))?;
+            members.push((key, value));
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => {}
+                Some('}') => break,
+                _ => return Err("expected `,` or `}`".to_string()),
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None => Ok(members),
+        Some(c) => Err(format!("unexpected `{c}` after the object")),
+    }
+}
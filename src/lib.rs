@@ -0,0 +1,33 @@
+// TIS-100 style assembly emulator: parser, lints and the executor
+
+pub mod analysis;
+pub mod annotate;
+pub mod audit;
+pub mod cache;
+pub mod corpus;
+pub mod cosim;
+#[cfg(unix)]
+pub mod daemon;
+pub mod emitter;
+mod emulator;
+mod json;
+pub mod lint;
+pub mod manifest;
+pub mod minify;
+pub mod mutate;
+pub mod observer;
+pub mod parser;
+pub mod plot;
+pub mod profile;
+pub mod report;
+pub mod shared;
+pub mod stream;
+pub mod taint;
+pub mod telemetry;
+pub mod trace;
+pub mod verify;
+
+pub use emulator::{
+    CostModel, CycleCosts, Emulator, HaltReason, InputEnd, JumpHistory, Limits, RunOutcome,
+    RunState, RuntimeError, Sandbox, State, States,
+};
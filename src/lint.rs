@@ -0,0 +1,301 @@
+// Lints over a parsed program. They report likely mistakes as warnings,
+// which can be allowed individually or turned into errors.
+
+use std::collections::HashSet;
+
+use crate::analysis::{self, Certainty};
+use crate::parser::{self, Diagnostic, Instruction, Operand, Program, Severity, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnknownLabel,
+    UnknownTable,
+    UnusedLabel,
+    SelfMove,
+    ImmediateOutOfRange,
+    Complexity,
+    DeadStore,
+    RedundantSave,
+    AlwaysClamps,
+    ConstantBranch,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 10] = [
+        Lint::UnknownLabel,
+        Lint::UnknownTable,
+        Lint::UnusedLabel,
+        Lint::SelfMove,
+        Lint::ImmediateOutOfRange,
+        Lint::Complexity,
+        Lint::DeadStore,
+        Lint::RedundantSave,
+        Lint::AlwaysClamps,
+        Lint::ConstantBranch,
+    ];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnknownLabel => "unknown-label",
+            Lint::UnknownTable => "unknown-table",
+            Lint::UnusedLabel => "unused-label",
+            Lint::SelfMove => "self-move",
+            Lint::ImmediateOutOfRange => "immediate-out-of-range",
+            Lint::Complexity => "complexity",
+            Lint::DeadStore => "dead-store",
+            Lint::RedundantSave => "redundant-save",
+            Lint::AlwaysClamps => "always-clamps",
+            Lint::ConstantBranch => "constant-branch",
+        }
+    }
+
+    #[must_use]
+    pub fn description(self) -> &'static str {
+        match self {
+            Lint::UnknownLabel => "jump to a label that is not defined",
+            Lint::UnknownTable => "lookup in a table that is not defined",
+            Lint::UnusedLabel => "label that no jump refers to",
+            Lint::SelfMove => "mov with the same source and destination",
+            Lint::ImmediateOutOfRange => "immediate outside -999..999 that will be clamped",
+            Lint::Complexity => "labeled region with more branches than --max-complexity",
+            Lint::DeadStore => "write to acc that is overwritten before it is read",
+            Lint::RedundantSave => "save when bak already holds the value of acc",
+            Lint::AlwaysClamps => "add whose result is always clamped to -999..999",
+            Lint::ConstantBranch => "conditional jump that is always or never taken",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+// The cyclomatic complexity a labeled region may have before the
+// complexity lint warns
+pub const DEFAULT_MAX_COMPLEXITY: usize = 10;
+
+// Which lints run and whether their warnings fail the load
+#[derive(Debug, Default)]
+pub struct LintConfig {
+    pub allowed: HashSet<Lint>,
+    pub deny_warnings: bool,
+    // Overrides DEFAULT_MAX_COMPLEXITY
+    pub max_complexity: Option<usize>,
+}
+
+impl LintConfig {
+    pub fn set_allowed(&mut self, lint: Lint, allowed: bool) {
+        if allowed {
+            self.allowed.insert(lint);
+        } else {
+            self.allowed.remove(&lint);
+        }
+    }
+}
+
+// Run every enabled lint. `handler` is the timer interrupt label and `entry`
+// a label execution starts at in place of the program's own `.entry`; both
+// count as used even if nothing jumps to them.
+#[must_use]
+pub fn check(
+    program: &Program,
+    handler: Option<&str>,
+    entry: Option<&str>,
+    config: &LintConfig,
+) -> Vec<Diagnostic> {
+    let mut warnings = Warnings {
+        config,
+        list: Vec::new(),
+    };
+    let mut targets: HashSet<&str> = handler
+        .into_iter()
+        .chain(entry)
+        .chain(program.entry_label())
+        .collect();
+    check_lines(program, &mut targets, &mut warnings);
+    for line in &program.lines {
+        if let Some((label, span)) = &line.label
+            && !targets.contains(label.as_str())
+        {
+            warnings.warn(
+                Lint::UnusedLabel,
+                format!("Label {label} is never jumped to"),
+                Some(*span),
+                None,
+            );
+        }
+    }
+    let max_complexity = config.max_complexity.unwrap_or(DEFAULT_MAX_COMPLEXITY);
+    for region in analysis::regions(program) {
+        if region.complexity > max_complexity {
+            let line = &program.lines[region.lines.start];
+            let span = match &line.label {
+                Some((_, span)) => Some(*span),
+                None => program.lines[region.lines.clone()]
+                    .iter()
+                    .find_map(|line| line.span),
+            };
+            warnings.warn(
+                Lint::Complexity,
+                format!(
+                    "Region {} has cyclomatic complexity {}, over the limit of {}",
+                    region.name(),
+                    region.complexity,
+                    max_complexity
+                ),
+                span,
+                Some("split it into smaller labeled regions".to_string()),
+            );
+        }
+    }
+    // An interrupt handler could observe the registers between any two
+    // instructions, so the dataflow lints only run without a timer. They
+    // also start where the program itself does, so not with another entry.
+    if handler.is_none() && entry.is_none_or(|entry| program.entry_label() == Some(entry)) {
+        check_dataflow(program, &mut warnings);
+    }
+    let mut warnings = warnings.list;
+    warnings.sort_by_key(|w| w.span.map(|s| (s.line, s.col)));
+    warnings
+}
+
+// The warnings found so far, leaving out the lints `config` allows
+struct Warnings<'a> {
+    config: &'a LintConfig,
+    list: Vec<Diagnostic>,
+}
+
+impl Warnings<'_> {
+    fn warn(
+        &mut self,
+        lint: Lint,
+        message: String,
+        span: Option<Span>,
+        suggestion: Option<String>,
+    ) {
+        if !self.config.allowed.contains(&lint) {
+            self.list.push(Diagnostic {
+                severity: if self.config.deny_warnings {
+                    Severity::Error
+                } else {
+                    Severity::Warning
+                },
+                code: lint.name(),
+                message,
+                span,
+                suggestion,
+            });
+        }
+    }
+}
+
+// The lints of single lines. Every label a jump refers to is added to
+// `targets`.
+fn check_lines<'a>(program: &'a Program, targets: &mut HashSet<&'a str>, warnings: &mut Warnings) {
+    for line in &program.lines {
+        match &line.instruction {
+            Some(
+                Instruction::Jmp(label)
+                | Instruction::Jez(label)
+                | Instruction::Jnz(label)
+                | Instruction::Jgz(label)
+                | Instruction::Jlz(label),
+            ) => {
+                targets.insert(label);
+                if !program.labels.contains_key(label) {
+                    let suggestion =
+                        parser::suggest(label, program.labels.keys().map(String::as_str))
+                            .map(|l| format!("did you mean `{l}`?"));
+                    warnings.warn(
+                        Lint::UnknownLabel,
+                        format!("Label {label} is not defined"),
+                        line.operands.first().copied(),
+                        suggestion,
+                    );
+                }
+            }
+            Some(Instruction::Lookup(name)) if !program.tables.contains_key(name) => {
+                let suggestion = parser::suggest(name, program.tables.keys().map(String::as_str))
+                    .map(|t| format!("did you mean `{t}`?"));
+                warnings.warn(
+                    Lint::UnknownTable,
+                    format!("Table {name} is not defined"),
+                    line.operands.first().copied(),
+                    suggestion,
+                );
+            }
+            Some(Instruction::Mov(src, dst)) if src == dst => warnings.warn(
+                Lint::SelfMove,
+                "mov has the same source and destination".to_string(),
+                line.span,
+                None,
+            ),
+            _ => {}
+        }
+        let immediates = match &line.instruction {
+            Some(
+                Instruction::Mov(src, _)
+                | Instruction::Add(src)
+                | Instruction::Out(src)
+                | Instruction::Outc(src)
+                | Instruction::Slp(src),
+            ) => Some(src),
+            _ => None,
+        };
+        if let Some(&Operand::Imm(value)) = immediates
+            && !(-999..=999).contains(&value)
+        {
+            warnings.warn(
+                Lint::ImmediateOutOfRange,
+                format!("Immediate {value} is outside -999..999 and will be clamped"),
+                line.operands.first().copied(),
+                None,
+            );
+        }
+    }
+}
+
+// The lints that follow values through the program from where it starts
+fn check_dataflow(program: &Program, warnings: &mut Warnings) {
+    for idx in analysis::dead_stores(program) {
+        warnings.warn(
+            Lint::DeadStore,
+            "Value written to acc is overwritten before it is read".to_string(),
+            program.lines[idx].span,
+            Some("remove this instruction".to_string()),
+        );
+    }
+    for idx in analysis::redundant_saves(program) {
+        warnings.warn(
+            Lint::RedundantSave,
+            "bak already holds the value of acc here".to_string(),
+            program.lines[idx].span,
+            Some("remove this save".to_string()),
+        );
+    }
+    for (idx, certainty) in analysis::certainties(program) {
+        let span = program.lines[idx].span;
+        match certainty {
+            Certainty::AlwaysClamps => warnings.warn(
+                Lint::AlwaysClamps,
+                "Result of add is always clamped to -999..999".to_string(),
+                span,
+                None,
+            ),
+            Certainty::AlwaysTaken => warnings.warn(
+                Lint::ConstantBranch,
+                "Conditional jump is always taken".to_string(),
+                span,
+                Some("use `jmp`".to_string()),
+            ),
+            Certainty::NeverTaken => warnings.warn(
+                Lint::ConstantBranch,
+                "Conditional jump is never taken".to_string(),
+                span,
+                Some("remove this jump".to_string()),
+            ),
+        }
+    }
+}
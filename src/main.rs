@@ -1,230 +1,1112 @@
-
 use std::env;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal};
+use std::path::Path;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use tis31337::annotate::Annotate;
+use tis31337::lint::{Lint, LintConfig};
+use tis31337::manifest::{self, Manifest};
+use tis31337::observer::Register;
+use tis31337::parser::{self, Diagnostic, Severity};
+use tis31337::plot::Plot;
+use tis31337::profile::Profile;
+use tis31337::report::{Format, Report};
+use tis31337::stream::{Encoding, InputSource, PromptSource, ReaderSource, WriterSink};
+use tis31337::trace::{BinaryTrace, ChromeTrace, FoldedStacks, TraceReader};
+use tis31337::{
+    CostModel, CycleCosts, Emulator, HaltReason, InputEnd, JumpHistory, Limits, RunOutcome,
+    Sandbox, emitter,
+};
+use tis31337::{analysis, corpus};
+use tis31337::{minify, mutate, taint, verify};
+
+// Parse the value following a command line flag
+fn flag_value<'a, T: FromStr>(iter: &mut impl Iterator<Item = &'a String>) -> Option<T> {
+    iter.next().and_then(|v| v.parse().ok())
+}
+
+// Parse a `--set` value of the form `<register>=<value>`
+fn register_value(arg: &str) -> Option<(Register, i32)> {
+    let (name, value) = arg.split_once('=')?;
+    Some((Register::from_name(name)?, value.parse().ok()?))
+}
+
+// Parse a `--plot` value of the form `<register>:<path>`
+fn plot_target(arg: &str) -> Option<(Register, &str)> {
+    let (name, path) = arg.split_once(':').filter(|(_, path)| !path.is_empty())?;
+    Some((Register::from_name(name)?, path))
+}
 
-use std::collections::HashMap;
+// Parse a `sweep --set` value: `<register>=<value>`, `<register>=<a>..<b>`
+// or `<register>=<a>..=<b>`, with ranges as in Rust
+fn register_range(arg: &str) -> Option<(Register, Vec<i32>)> {
+    let (name, value) = arg.split_once('=')?;
+    Some((Register::from_name(name)?, value_range(value)?))
+}
+
+// Parse `<value>`, `<a>..<b>` or `<a>..=<b>` into the values it covers
+fn value_range<T: FromStr>(arg: &str) -> Option<Vec<T>>
+where
+    std::ops::Range<T>: Iterator<Item = T>,
+    std::ops::RangeInclusive<T>: Iterator<Item = T>,
+{
+    Some(if let Some((a, b)) = arg.split_once("..=") {
+        (a.parse().ok()?..=b.parse().ok()?).collect()
+    } else if let Some((a, b)) = arg.split_once("..") {
+        (a.parse().ok()?..b.parse().ok()?).collect()
+    } else {
+        vec![arg.parse().ok()?]
+    })
+}
 
-#[derive(Debug)]
-struct Emulator {
-    acc: i32,
-    bak: i32,
-    pc: usize,
-    labels: HashMap<String, usize>,
-    program: Vec<String>,
+// Read a program file, exiting if it cannot be opened. Lines are parsed as
+// slices of the returned buffer; invalid UTF-8 is replaced rather than
+// rejected.
+fn read_source(filename: &str) -> String {
+    let bytes = fs::read(filename).unwrap_or_else(|_| {
+        eprintln!("Could not open file: {filename}");
+        std::process::exit(1);
+    });
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
 }
 
-impl Emulator {
-    fn new() -> Self {
-        Emulator { acc: 0, bak: 0, pc: 0, labels: HashMap::new(), program: Vec::new() }
+// `dump-ast [--format json] <file>`: print the parsed program
+fn dump_ast(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: {} dump-ast [--format json] <input_file>", args[0]);
+        std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--format" => match iter.next().map(String::as_str) {
+                Some("json") => {}
+                _ => usage(),
+            },
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(filename) = filename else { usage() };
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    match parser::parse(&lines) {
+        Ok(program) => println!("{}", program.to_json(filename)),
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic.render(filename, &lines));
+            }
+            std::process::exit(1);
+        }
     }
+}
 
-    fn clamp_acc(&mut self) {
-        if self.acc > 999 {
-            self.acc = 999;
-        } else if self.acc < -999 {
-            self.acc = -999;
+// `minify [--keep <label>]... <file>`: print the smallest equivalent program.
+// The default timer handler label is always kept.
+fn minify_program(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: {} minify [--keep <label>]... <input_file>", args[0]);
+        std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut keep = vec!["timer"];
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--keep" => match iter.next() {
+                Some(label) => keep.push(label),
+                None => usage(),
+            },
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
         }
     }
+    let Some(filename) = filename else { usage() };
+    // A project's own timer handler is kept like `timer`
+    let manifest = env::current_dir()
+        .ok()
+        .and_then(|cwd| Manifest::find(&cwd))
+        .and_then(|path| Manifest::load(&path).ok());
+    if let Some(handler) = manifest.as_ref().and_then(|m| m.timer_handler.as_deref()) {
+        keep.push(handler);
+    }
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    match parser::parse(&lines) {
+        Ok(program) => print!("{}", minify::minify(&program, &keep)),
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic.render(filename, &lines));
+            }
+            std::process::exit(1);
+        }
+    }
+}
 
-    fn execute(&mut self, line: &str) -> Result<(), String> {
-        let trimmed = line.trim();
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            return Ok(());
+// `fmt [--check] <file>`: print the program in canonical form, or with
+// --check only report whether the file is already formatted
+fn format_program(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: {} fmt [--check] <input_file>", args[0]);
+        std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut check = false;
+    for arg in &args[2..] {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
         }
-        let tokens: Vec<&str> = trimmed.split_whitespace().collect();
-        if tokens.is_empty() {
-            return Ok(());
+    }
+    let Some(filename) = filename else { usage() };
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    let program = parser::parse(&lines).unwrap_or_else(|diagnostics| {
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render(filename, &lines));
         }
-        match tokens[0].to_lowercase().as_str() {
-            "mov" => {
-                // mov src, dst
-                if tokens.len() != 3 {
-                    return Err(format!("Invalid mov syntax: {}", line));
-                }
-                let src = tokens[1].trim_end_matches(',');
-                let dst = tokens[2];
-                let value = self.read_value(src)?;
-                self.write_value(dst, value)?;
-            }
-            "swp" => {
-                if tokens.len() != 1 {
-                    return Err(format!("Invalid swp syntax: {}", line));
-                }
-                std::mem::swap(&mut self.acc, &mut self.bak);
+        std::process::exit(1);
+    });
+    let formatted = emitter::emit(&program);
+    if !check {
+        print!("{formatted}");
+        return;
+    }
+    if let Some((line, expected)) = emitter::first_unformatted(&source, &formatted) {
+        eprintln!("{filename}:{line}: not formatted, expected {expected}");
+        std::process::exit(1);
+    }
+}
+
+// The manifest of the project containing the working directory
+fn project_manifest() -> Manifest {
+    let cwd = env::current_dir().unwrap_or_default();
+    let Some(path) = Manifest::find(&cwd) else {
+        eprintln!(
+            "No {} found in {} or its parents",
+            manifest::FILE_NAME,
+            cwd.display()
+        );
+        std::process::exit(1);
+    };
+    Manifest::load(&path).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    })
+}
+
+// Options shared by `corpus run` and `conformance`
+#[derive(Default)]
+struct CorpusOptions<'a> {
+    bless: bool,
+    // Where to write a JUnit XML report
+    report: Option<&'a str>,
+    // Print Test Anything Protocol output instead of the default listing
+    tap: bool,
+}
+
+// Parse corpus options and the optional directory from `args`, or `None` if
+// they are malformed
+fn corpus_args(args: &[String]) -> Option<(Option<&str>, CorpusOptions<'_>)> {
+    let mut dir = None;
+    let mut options = CorpusOptions::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--bless" => options.bless = true,
+            "--report" => match iter.next() {
+                Some(path) => options.report = Some(path),
+                None => return None,
+            },
+            "--format" => match iter.next().map(String::as_str) {
+                Some("text") => options.tap = false,
+                Some("tap") => options.tap = true,
+                _ => return None,
+            },
+            _ if dir.is_none() => dir = Some(arg.as_str()),
+            _ => return None,
+        }
+    }
+    Some((dir, options))
+}
+
+// `corpus run [--bless] [--report <file>] [--format text|tap] [dir]`: check
+// every program in [dir], or the corpus named by tis.toml, against its
+// `.expected` file, or with --bless rewrite those files
+fn run_corpus(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!(
+            "Usage: {} corpus run [--bless] [--report <file>] [--format text|tap] [dir]",
+            args[0]
+        );
+        std::process::exit(1);
+    };
+    if args.get(2).map(String::as_str) != Some("run") {
+        usage();
+    }
+    let Some((dir, options)) = corpus_args(&args[3..]) else {
+        usage()
+    };
+    // Without a directory, run the corpus named by the project manifest
+    let manifest_corpus;
+    let dir = if let Some(dir) = dir {
+        dir
+    } else {
+        let Some(corpus) = project_manifest().corpus else {
+            eprintln!("{} does not name a test corpus", manifest::FILE_NAME);
+            std::process::exit(1);
+        };
+        manifest_corpus = corpus.display().to_string();
+        &manifest_corpus
+    };
+    check_corpus(dir, &options, false);
+}
+
+// `conformance [--bless] [--report <file>] [--format text|tap] [dir]`: check
+// the interpreter against the semantics suite, whose cases record a full
+// execution trace
+fn run_conformance(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!(
+            "Usage: {} conformance [--bless] [--report <file>] [--format text|tap] [dir]",
+            args[0]
+        );
+        std::process::exit(1);
+    };
+    let Some((dir, options)) = corpus_args(&args[2..]) else {
+        usage()
+    };
+    let dir = dir.unwrap_or(concat!(env!("CARGO_MANIFEST_DIR"), "/conformance"));
+    check_corpus(dir, &options, true);
+}
+
+// Print how an actual outcome differs from the expected one, colored when
+// stdout is a terminal: the first output value that differs, the registers,
+// counters and output side by side, then a line diff of anything else, such
+// as diagnostics, the trace or a runtime error
+fn print_diff(expected: &str, actual: &str) {
+    let color = io::stdout().is_terminal();
+    let paint = |code: &str, text: String| {
+        if color {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text
+        }
+    };
+    for credit in corpus::credit(expected, actual) {
+        if !credit.full() {
+            println!("  {credit}");
+        }
+    }
+    let rows = corpus::compare(expected, actual);
+    let shown = |value: Option<&str>| value.unwrap_or("(none)").to_string();
+    let name_width = rows.iter().map(|r| r.field.len()).max().unwrap_or(0);
+    let width = rows
+        .iter()
+        .map(|r| shown(r.expected).len())
+        .chain(["expected".len()])
+        .max()
+        .unwrap_or(0);
+    if !rows.is_empty() {
+        println!("    {:name_width$}  {:width$}  actual", "", "expected");
+    }
+    for row in &rows {
+        let (expected, actual) = (shown(row.expected), shown(row.actual));
+        if row.differs() {
+            println!(
+                "  ! {:name_width$}  {}  {}",
+                row.field,
+                paint("31", format!("{expected:width$}")),
+                paint("32", actual)
+            );
+        } else {
+            println!(
+                "    {:name_width$}  {:width$}  {}",
+                row.field, expected, actual
+            );
+        }
+    }
+    let (expected, actual) = (
+        corpus::without_fields(expected),
+        corpus::without_fields(actual),
+    );
+    let lines = corpus::diff(&expected, &actual);
+    if lines.iter().all(|l| matches!(l, corpus::DiffLine::Same(_))) {
+        return;
+    }
+    for line in lines {
+        match line {
+            corpus::DiffLine::Same(text) => println!("    {text}"),
+            corpus::DiffLine::Removed(text) => println!("{}", paint("31", format!("  - {text}"))),
+            corpus::DiffLine::Added(text) => println!("{}", paint("32", format!("  + {text}"))),
+        }
+    }
+}
+
+// Run a corpus directory and print one line per case, exiting with failure
+// if any case failed
+fn check_corpus(dir: &str, options: &CorpusOptions, trace: bool) {
+    let results = corpus::run(Path::new(dir), options.bless, trace).unwrap_or_else(|e| {
+        eprintln!("Could not run corpus {dir}: {e}");
+        std::process::exit(1);
+    });
+    if let Some(path) = options.report
+        && let Err(e) = fs::write(path, corpus::junit(dir, &results))
+    {
+        eprintln!("Could not write {path}: {e}");
+        std::process::exit(1);
+    }
+    if options.tap {
+        print!("{}", corpus::tap(&results));
+        if results.iter().any(corpus::CaseResult::failed) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut failed = 0;
+    for result in &results {
+        let path = result.path.display();
+        match &result.status {
+            corpus::Status::Pass => println!("ok       {path}"),
+            corpus::Status::Blessed => println!("blessed  {path}"),
+            corpus::Status::Missing => {
+                failed += 1;
+                println!("missing  {path} (no .expected file, run with --bless)");
             }
-            "save" => {
-                if tokens.len() != 1 {
-                    return Err(format!("Invalid save syntax: {}", line));
-                }
-                self.bak = self.acc;
+            corpus::Status::Fail { expected, actual } => {
+                failed += 1;
+                println!("FAILED   {path}");
+                print_diff(expected, actual);
             }
-            "add" => {
-                if tokens.len() != 2 {
-                    return Err(format!("Invalid add syntax: {}", line));
-                }
-                let value = self.read_value(tokens[1])?;
-                self.acc += value;
-                self.clamp_acc();
-            }
-            // New opcodes for control flow
-            "jmp" => {
-                if tokens.len() != 2 {
-                    return Err(format!("Invalid jmp syntax: {}", line));
-                }
-                let label = tokens[1];
-                if let Some(&target) = self.labels.get(label) {
-                    self.pc = target;
-                    return Ok(()); // skip pc increment in run loop
-                } else {
-                    return Err(format!("Unknown label: {}", label));
-                }
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+// `stats <file>`: report instruction counts, basic blocks, jumps and loops
+fn program_stats(args: &[String]) {
+    let [_, _, filename] = args else {
+        eprintln!("Usage: {} stats <input_file>", args[0]);
+        std::process::exit(1);
+    };
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    match parser::parse(&lines) {
+        Ok(program) => print!(
+            "{}",
+            analysis::Stats::new(&program, &CycleCosts::default()).render()
+        ),
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic.render(filename, &lines));
             }
-            "jez" => { // jump if acc == 0
-                if tokens.len() != 2 {
-                    return Err(format!("Invalid jez syntax: {}", line));
-                }
-                let label = tokens[1];
-                if self.acc == 0 {
-                    if let Some(&target) = self.labels.get(label) {
-                        self.pc = target;
-                        return Ok(());
-                    } else {
-                        return Err(format!("Unknown label: {}", label));
-                    }
+            std::process::exit(1);
+        }
+    }
+}
+
+// `taint <file> [--input <path>]`: run the program on the values in <path>,
+// or stdin for -, and report which inputs each output and conditional jump
+// depends on
+fn taint_program(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: {} taint <input_file> [--input <path>]", args[0]);
+        std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut input = None;
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--input" => input = Some(iter.next().unwrap_or_else(|| usage())),
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(filename) = filename else { usage() };
+    let mut inputs = Vec::new();
+    if let Some(path) = input {
+        let mut source: Box<dyn InputSource> = match path.as_str() {
+            "-" => Box::new(ReaderSource::new(BufReader::new(io::stdin()))),
+            path => Box::new(ReaderSource::open(path).unwrap_or_else(|e| {
+                eprintln!("Could not open {path}: {e}");
+                std::process::exit(1);
+            })),
+        };
+        loop {
+            match source.read() {
+                Ok(Some(value)) => inputs.push(value),
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Could not read {path}: {e}");
+                    std::process::exit(1);
                 }
             }
-            "jnz" => { // jump if acc != 0
-                if tokens.len() != 2 {
-                    return Err(format!("Invalid jnz syntax: {}", line));
-                }
-                let label = tokens[1];
-                if self.acc != 0 {
-                    if let Some(&target) = self.labels.get(label) {
-                        self.pc = target;
-                        return Ok(());
-                    } else {
-                        return Err(format!("Unknown label: {}", label));
-                    }
-                }
+        }
+    }
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    let report =
+        taint::track(&lines, &inputs, corpus::CYCLE_BUDGET).unwrap_or_else(|diagnostics| {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic.render(filename, &lines));
             }
-            "jgz" => { // jump if acc > 0
-                if tokens.len() != 2 {
-                    return Err(format!("Invalid jgz syntax: {}", line));
-                }
-                let label = tokens[1];
-                if self.acc > 0 {
-                    if let Some(&target) = self.labels.get(label) {
-                        self.pc = target;
-                        return Ok(());
-                    } else {
-                        return Err(format!("Unknown label: {}", label));
-                    }
-                }
+            std::process::exit(1);
+        });
+    for (stream, writes) in [("output", &report.outputs), ("text", &report.text)] {
+        for (idx, write) in writes.iter().enumerate() {
+            println!(
+                "{} {} = {} (line {}) <- {}",
+                stream,
+                idx,
+                write.value,
+                write.line,
+                taint::names(&write.inputs)
+            );
+        }
+    }
+    for (line, branch) in &report.branches {
+        println!(
+            "branch line {}: taken {} of {} time(s) <- {}",
+            line,
+            branch.taken,
+            branch.evaluated,
+            taint::names(&branch.inputs)
+        );
+    }
+    if let Some(stopped) = &report.stopped {
+        println!("stopped: {}", stopped.replace('\n', " "));
+    }
+}
+
+// `annotate <file>`: run the program and print its source with how often
+// each line executed and the cycles it took, as a heat map
+fn annotate_program(args: &[String]) {
+    let [_, _, filename] = args else {
+        eprintln!("Usage: {} annotate <input_file>", args[0]);
+        std::process::exit(1);
+    };
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    let program = parser::parse(&lines).unwrap_or_else(|diagnostics| {
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render(filename, &lines));
+        }
+        std::process::exit(1);
+    });
+    let mut emu = Emulator::new();
+    emu.limits.max_cycles = Some(corpus::CYCLE_BUDGET);
+    load(&mut emu, filename, false);
+    let color = io::stdout().is_terminal();
+    emu.add_observer(Box::new(Annotate::new(
+        &program,
+        &lines,
+        color,
+        io::stdout(),
+    )));
+    if let Err(e) = emu.run() {
+        eprintln!("{e}");
+    }
+    emu.finish();
+    if let Some(reason) = emu.halt_reason() {
+        if reason == HaltReason::CycleLimit {
+            eprintln!("Stopped after {} cycles", corpus::CYCLE_BUDGET);
+        }
+        std::process::exit(reason.exit_code());
+    }
+}
+
+// `mutate <file>`: run every mutant of the program and report the ones whose
+// outcome still matches the expected one, from the `.expected` file next to
+// the program or else from the unmutated program
+fn mutate_program(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("Usage: {} mutate <input_file>", args[0]);
+        std::process::exit(1);
+    };
+    let [_, _, filename] = args else { usage() };
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    let program = parser::parse(&lines).unwrap_or_else(|diagnostics| {
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render(filename, &lines));
+        }
+        std::process::exit(1);
+    });
+    let expected = fs::read_to_string(Path::new(filename).with_extension("expected"))
+        .unwrap_or_else(|_| corpus::outcome(&lines, false));
+    let mutants = mutate::mutants(&program, &lines);
+    let mut survived = 0;
+    for mutant in &mutants {
+        if corpus::outcome(&mutant.lines, false) == expected {
+            survived += 1;
+            println!(
+                "survived {}:{}: {}",
+                filename, mutant.line, mutant.description
+            );
+        }
+    }
+    println!(
+        "{} mutants, {} killed, {} survived",
+        mutants.len(),
+        mutants.len() - survived,
+        survived
+    );
+    if survived > 0 {
+        std::process::exit(1);
+    }
+}
+
+// `sweep <file> --set <reg>=<range>... [--format table|csv]`: run the program
+// once for every combination of initial register values and print a row of
+// results for each
+fn sweep(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!(
+            "Usage: {} sweep <input_file> --set <reg>=<a>..<b>... [--format table|csv]",
+            args[0]
+        );
+        std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut ranges: Vec<(Register, Vec<i32>)> = Vec::new();
+    let mut csv = false;
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--set" => ranges.push(
+                iter.next()
+                    .and_then(|arg| register_range(arg))
+                    .filter(|(_, values)| !values.is_empty())
+                    .unwrap_or_else(|| usage()),
+            ),
+            "--format" => match iter.next().map(String::as_str) {
+                Some("table") => csv = false,
+                Some("csv") => csv = true,
+                _ => usage(),
+            },
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(filename) = filename else { usage() };
+    if ranges.is_empty() {
+        usage();
+    }
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+
+    let mut header: Vec<String> = ranges
+        .iter()
+        .map(|(r, _)| format!("start_{}", r.name()))
+        .collect();
+    header.extend(["acc", "bak", "cycles", "instructions", "termination"].map(String::from));
+    let mut rows = vec![header];
+    let mut emu = Emulator::new();
+    if let Err(diagnostics) = emu.load_program(&lines) {
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render(filename, &lines));
+        }
+        std::process::exit(1);
+    }
+    // Every combination of values, the last register varying fastest
+    let mut combination = vec![0; ranges.len()];
+    'runs: loop {
+        emu.reset();
+        let mut row = Vec::new();
+        for ((register, values), &idx) in ranges.iter().zip(&combination) {
+            emu.set_register(*register, values[idx]);
+            row.push(values[idx].to_string());
+        }
+        let termination = match emu.run_for(corpus::CYCLE_BUDGET) {
+            Ok(RunOutcome::Halted) => "halted".to_string(),
+            Ok(_) => "budget".to_string(),
+            Err(e) => format!("error on line {}", e.line),
+        };
+        row.extend([
+            emu.acc.to_string(),
+            emu.bak.to_string(),
+            emu.cycles.to_string(),
+            emu.instructions.to_string(),
+            termination,
+        ]);
+        rows.push(row);
+        for pos in (0..ranges.len()).rev() {
+            combination[pos] += 1;
+            if combination[pos] < ranges[pos].1.len() {
+                continue 'runs;
             }
-            "jlz" => { // jump if acc < 0
-                if tokens.len() != 2 {
-                    return Err(format!("Invalid jlz syntax: {}", line));
-                }
-                let label = tokens[1];
-                if self.acc < 0 {
-                    if let Some(&target) = self.labels.get(label) {
-                        self.pc = target;
-                        return Ok(());
-                    } else {
-                        return Err(format!("Unknown label: {}", label));
-                    }
-                }
+            combination[pos] = 0;
+        }
+        break;
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|col| rows.iter().map(|row| row[col].len()).max().unwrap_or(0))
+        .collect();
+    for row in &rows {
+        if csv {
+            println!("{}", row.join(","));
+        } else {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| format!("{cell:<width$}"))
+                .collect();
+            println!("{}", cells.join("  ").trim_end());
+        }
+    }
+}
+
+// `verify --exhaustive <file> --expect <outputs>`: run the program on every
+// input stream over a small domain and report each one whose output differs
+// from the reference
+fn verify_program(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!(
+            "Usage: {} verify --exhaustive <input_file> --expect <outputs> [--domain <a>..<b>] [--length <n>]",
+            args[0]
+        );
+        std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut exhaustive = false;
+    let mut reference = None;
+    let mut domain: Vec<i32> = (-99..=99).collect();
+    let mut lengths = vec![1];
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--exhaustive" => exhaustive = true,
+            "--expect" => {
+                let text = iter.next().unwrap_or_else(|| usage());
+                reference = Some(verify::Reference::parse(text).unwrap_or_else(|e| {
+                    eprintln!("Invalid reference: {e}");
+                    std::process::exit(1);
+                }));
             }
-            "ret" => {
-                // For now, ret just ends the program
-                self.pc = self.program.len();
-                return Ok(());
+            "--domain" => {
+                domain = iter
+                    .next()
+                    .and_then(|arg| value_range(arg))
+                    .unwrap_or_else(|| usage());
             }
-            _ => {
-                if tokens[0].ends_with(':') {
-                    // Label, ignore
-                    return Ok(());
-                }
-                return Err(format!("Unknown instruction: {}", tokens[0]));
+            "--length" => {
+                lengths = iter
+                    .next()
+                    .and_then(|arg| value_range(arg))
+                    .unwrap_or_else(|| usage());
             }
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
         }
-        self.pc += 1;
-        Ok(())
     }
+    let (Some(filename), Some(reference), true) = (filename, reference, exhaustive) else {
+        usage()
+    };
+    let shortest = lengths.iter().copied().min().unwrap_or(0);
+    if reference.inputs_needed() > shortest {
+        eprintln!(
+            "The reference reads x{} but the shortest input has {} value(s)",
+            reference.inputs_needed() - 1,
+            shortest
+        );
+        std::process::exit(1);
+    }
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    let verification =
+        verify::exhaustive(&lines, &domain, &lengths, &reference, corpus::CYCLE_BUDGET)
+            .unwrap_or_else(|diagnostics| {
+                for diagnostic in &diagnostics {
+                    eprintln!("{}", diagnostic.render(filename, &lines));
+                }
+                std::process::exit(1);
+            });
+    for failure in &verification.failures {
+        println!("failed {failure}");
+    }
+    println!(
+        "{} inputs, {} passed, {} failed",
+        verification.runs,
+        verification.runs - verification.failures.len() as u64,
+        verification.failures.len()
+    );
+    if !verification.failures.is_empty() {
+        std::process::exit(1);
+    }
+}
 
-    fn read_value(&self, src: &str) -> Result<i32, String> {
-        match src.to_lowercase().as_str() {
-            "acc" => Ok(self.acc),
-            "bak" => Ok(self.bak),
-            _ => {
-                // Try to parse as integer
-                src.parse::<i32>().map_err(|_| format!("Invalid source: {}", src))
-            }
+// `trace-seek <trace> <cycle>`: print the machine state at `cycle` from a
+// binary trace
+fn trace_seek(args: &[String]) {
+    let [_, _, path, cycle] = args else {
+        eprintln!("Usage: {} trace-seek <trace_file> <cycle>", args[0]);
+        std::process::exit(1);
+    };
+    let Ok(cycle) = cycle.replace(',', "").parse() else {
+        eprintln!("Invalid cycle: {cycle}");
+        std::process::exit(1);
+    };
+    let trace = TraceReader::open(path).unwrap_or_else(|e| {
+        eprintln!("Could not read {path}: {e}");
+        std::process::exit(1);
+    });
+    match trace.seek(cycle) {
+        Ok(Some(snapshot)) => {
+            println!("cycle: {}", snapshot.cycle);
+            println!("line: {}", snapshot.pc + 1);
+            println!("acc: {}", snapshot.acc);
+            println!("bak: {}", snapshot.bak);
+        }
+        Ok(None) => println!("The trace has no instructions"),
+        Err(e) => {
+            eprintln!("Could not seek {path}: {e}");
+            std::process::exit(1);
+        }
+    }
+    if cycle >= trace.cycles {
+        eprintln!("The run ended after {} cycles", trace.cycles);
+    }
+}
+
+// SplitMix64, a small seeded generator so Monte Carlo runs are repeatable
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        // Less than `n`, so it fits
+        usize::try_from(self.next() % n as u64).unwrap_or_default()
+    }
+}
+
+// `montecarlo <file> [--runs <n>] [--seed <n>] [--set <reg>=<range>]...`: run
+// the program with random initial register values (acc over -999..=999
+// unless ranges are given) and summarize the results
+fn monte_carlo(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!(
+            "Usage: {} montecarlo <input_file> [--runs <n>] [--seed <n>] [--set <reg>=<a>..<b>]...",
+            args[0]
+        );
+        std::process::exit(1);
+    };
+    let mut filename = None;
+    let mut ranges: Vec<(Register, Vec<i32>)> = Vec::new();
+    let mut runs: usize = 1000;
+    let mut seed: u64 = 0;
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--set" => ranges.push(
+                iter.next()
+                    .and_then(|arg| register_range(arg))
+                    .filter(|(_, values)| !values.is_empty())
+                    .unwrap_or_else(|| usage()),
+            ),
+            "--runs" => runs = flag_value(&mut iter).unwrap_or_else(|| usage()),
+            "--seed" => seed = flag_value(&mut iter).unwrap_or_else(|| usage()),
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
         }
     }
+    let Some(filename) = filename else { usage() };
+    if ranges.is_empty() {
+        ranges.push((Register::Acc, (-999..=999).collect()));
+    }
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
 
-    fn write_value(&mut self, dst: &str, value: i32) -> Result<(), String> {
-        match dst.to_lowercase().as_str() {
-            "acc" => {
-                self.acc = value;
-                self.clamp_acc();
-                Ok(())
+    let mut emu = Emulator::new();
+    if let Err(diagnostics) = emu.load_program(&lines) {
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render(filename, &lines));
+        }
+        std::process::exit(1);
+    }
+
+    let mut rng = Rng(seed);
+    let (mut acc, mut bak, mut cycles) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut errors, mut exhausted) = (0, 0);
+    for _ in 0..runs {
+        emu.reset();
+        for (register, values) in &ranges {
+            emu.set_register(*register, values[rng.below(values.len())]);
+        }
+        match emu.run_for(corpus::CYCLE_BUDGET) {
+            Ok(RunOutcome::Halted) => {
+                acc.push(i64::from(emu.acc));
+                bak.push(i64::from(emu.bak));
+                cycles.push(i64::try_from(emu.cycles).unwrap_or(i64::MAX));
             }
-            // bak cannot be written to directly
-            "bak" => Err("Cannot write directly to bak".to_string()),
-            _ => Err(format!("Invalid destination: {}", dst)),
+            Ok(_) => exhausted += 1,
+            Err(_) => errors += 1,
         }
     }
+    println!(
+        "{} runs, {} halted, {} errors, {} over the cycle budget",
+        runs,
+        cycles.len(),
+        errors,
+        exhausted
+    );
+    if cycles.is_empty() {
+        return;
+    }
+    println!(
+        "{:<8}{:>10}{:>10}{:>12}{:>10}{:>10}{:>10}",
+        "", "min", "max", "mean", "p50", "p90", "p99"
+    );
+    for (name, values) in [
+        ("acc", &mut acc),
+        ("bak", &mut bak),
+        ("cycles", &mut cycles),
+    ] {
+        values.sort_unstable();
+        let percentile = |p: usize| values[(values.len() * p).div_ceil(100).max(1) - 1];
+        // Sums of register values and cycle counts over the runs stay far
+        // below 2^52, where f64 starts rounding
+        #[allow(clippy::cast_precision_loss)]
+        let mean = values.iter().sum::<i64>() as f64 / values.len() as f64;
+        println!(
+            "{:<8}{:>10}{:>10}{:>12.2}{:>10}{:>10}{:>10}",
+            name,
+            values[0],
+            values[values.len() - 1],
+            mean,
+            percentile(50),
+            percentile(90),
+            percentile(99)
+        );
+    }
+}
 
-    fn print_state(&self) {
-        println!("acc: {}", self.acc);
-        println!("bak: {}", self.bak);
+const STARTER_PROGRAM: &str = "\
+# Count down from 5, adding each step into bak
+mov 5, acc
+loop:
+swp
+add 1
+swp
+add -1
+jnz loop
+ret
+";
+
+// `new <dir>`: create a project with a manifest, a starter program and a
+// test corpus holding one blessed case
+fn new_project(args: &[String]) {
+    let [_, _, dir] = args else {
+        eprintln!("Usage: {} new <dir>", args[0]);
+        std::process::exit(1);
+    };
+    let root = Path::new(dir);
+    if root.exists() {
+        eprintln!("{dir} already exists");
+        std::process::exit(1);
+    }
+    let manifest = format!(
+        "[program]\nsource = \"main.tis\"\nprofile = \"{}\"\n\n[test]\ncorpus = \"tests\"\n",
+        Profile::default().name()
+    );
+    let lines: Vec<&str> = STARTER_PROGRAM.lines().collect();
+    let files = [
+        (root.join(manifest::FILE_NAME), manifest),
+        (root.join("main.tis"), STARTER_PROGRAM.to_string()),
+        (
+            root.join("tests/countdown.tis"),
+            STARTER_PROGRAM.to_string(),
+        ),
+        (
+            root.join("tests/countdown.expected"),
+            corpus::outcome(&lines, false),
+        ),
+    ];
+    for (path, contents) in &files {
+        let written = fs::create_dir_all(path.parent().unwrap_or(root))
+            .and_then(|()| fs::write(path, contents));
+        if let Err(e) = written {
+            eprintln!("Could not write {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
     }
-    // Load program and collect labels
-    fn load_program(&mut self, lines: Vec<String>) {
-        self.program = lines;
-        self.labels.clear();
-        for (idx, line) in self.program.iter().enumerate() {
-            let trimmed = line.trim();
-            if trimmed.ends_with(':') {
-                let label = trimmed.trim_end_matches(':').to_string();
-                self.labels.insert(label, idx);
+    println!("Created {dir}");
+}
+
+// `daemon [--store <dir>] [--audit <file> [--audit-max-bytes <n>]]
+// [--spans <file>] <socket>`: serve named emulator sessions on a Unix
+// socket, saving them under <dir> on request, recording each run in the
+// audit <file>, which is rotated once it would pass <n> bytes, and writing
+// traces of the requests to the spans <file>
+#[cfg(unix)]
+fn run_daemon(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!(
+            "Usage: {} daemon [--store <dir>] [--audit <file> [--audit-max-bytes <n>]] [--spans <file>] <socket>",
+            args[0]
+        );
+        std::process::exit(1);
+    };
+    let mut socket = None;
+    let mut store = None;
+    let mut audit = None;
+    let mut audit_max_bytes = None;
+    let mut spans = None;
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--store" => match iter.next() {
+                Some(dir) => store = Some(Path::new(dir)),
+                None => usage(),
+            },
+            "--audit" => match iter.next() {
+                Some(file) => audit = Some(Path::new(file)),
+                None => usage(),
+            },
+            "--audit-max-bytes" => {
+                audit_max_bytes = Some(flag_value(&mut iter).unwrap_or_else(|| usage()));
             }
+            "--spans" => match iter.next() {
+                Some(file) => spans = Some(Path::new(file)),
+                None => usage(),
+            },
+            _ if socket.is_none() => socket = Some(arg),
+            _ => usage(),
         }
-        self.pc = 0;
     }
-    // Run the loaded program
-    fn run(&mut self) {
-        while self.pc < self.program.len() {
-            let line = &self.program[self.pc];
-            if let Err(e) = self.execute(line) {
-                eprintln!("Error on line {}: {}", self.pc + 1, e);
+    let Some(socket) = socket else { usage() };
+    if audit_max_bytes.is_some() && audit.is_none() {
+        usage();
+    }
+    let audit = audit.map(|path| {
+        tis31337::audit::AuditLog::open(path, audit_max_bytes).unwrap_or_else(|e| {
+            eprintln!("Could not open {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    });
+    let spans = spans.map(|path| {
+        tis31337::telemetry::SpanLog::open(path).unwrap_or_else(|e| {
+            eprintln!("Could not open {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    });
+    if let Err(e) = tis31337::daemon::serve(Path::new(socket), store, audit, spans) {
+        eprintln!("Could not serve on {socket}: {e}");
+        std::process::exit(1);
+    }
+}
+
+// Load `filename` into `emu`, printing any diagnostics and exiting if the
+// program is rejected
+fn load(emu: &mut Emulator, filename: &str, json_diagnostics: bool) {
+    let source = read_source(filename);
+    let lines: Vec<&str> = source.lines().collect();
+    let report = |diagnostics: &[Diagnostic]| {
+        for diagnostic in diagnostics {
+            if json_diagnostics {
+                eprintln!("{}", diagnostic.to_json(filename));
+            } else {
+                eprintln!("{}", diagnostic.render(filename, &lines));
+            }
+        }
+    };
+    match emu.load_program(&lines) {
+        Ok(warnings) => report(&warnings),
+        Err(diagnostics) => {
+            report(&diagnostics);
+            if json_diagnostics {
                 std::process::exit(1);
             }
+            let errors = diagnostics
+                .iter()
+                .filter(|d| d.severity == Severity::Error)
+                .count();
+            eprintln!("Could not load {filename}: {errors} error(s)");
+            std::process::exit(1);
         }
     }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <input_file>", args[0]);
-        std::process::exit(1);
+    match args.get(1).map(String::as_str) {
+        Some("dump-ast") => return dump_ast(&args),
+        Some("fmt") => return format_program(&args),
+        Some("minify") => return minify_program(&args),
+        Some("corpus") => return run_corpus(&args),
+        Some("mutate") => return mutate_program(&args),
+        Some("stats") => return program_stats(&args),
+        Some("annotate") => return annotate_program(&args),
+        Some("taint") => return taint_program(&args),
+        Some("conformance") => return run_conformance(&args),
+        Some("sweep") => return sweep(&args),
+        Some("verify") => return verify_program(&args),
+        Some("trace-seek") => return trace_seek(&args),
+        Some("montecarlo") => return monte_carlo(&args),
+        Some("new") => return new_project(&args),
+        #[cfg(unix)]
+        Some("daemon") => return run_daemon(&args),
+        _ => {}
+    }
+    let mut options = RunOptions::parse(&args);
+    let filename = options.source_file();
+    let mut emu = options.emulator();
+    options.load(&mut emu, &filename);
+    options.observe(&mut emu);
+    let result = emu.run();
+    // Nothing can attach more input once the run is over, so a blocked run
+    // ends here, letting traces and plots write everything out
+    emu.finish();
+    if let Err(e) = &result {
+        eprintln!("{e}");
+        if let Some(path) = options.core_dump
+            && let Err(err) = fs::write(path, emu.core_dump(e) + "\n")
+        {
+            eprintln!("Could not write core dump {path}: {err}");
+        }
+    }
+    if options.output.is_some() || options.output_file.is_some() {
+        let report = Report::new(&emu);
+        let rendered = report.render(options.output.unwrap_or(Format::Table));
+        match options.output_file {
+            Some(path) => {
+                if let Err(e) = fs::write(path, rendered) {
+                    eprintln!("Could not write {path}: {e}");
+                    std::process::exit(1);
+                }
+            }
+            None => print!("{rendered}"),
+        }
+        std::process::exit(report.halt_reason.map_or(0, |reason| reason.exit_code()));
     }
-    let filename = &args[1];
-    let file = File::open(filename).unwrap_or_else(|_| {
-        eprintln!("Could not open file: {}", filename);
+    if result.is_err() {
         std::process::exit(1);
-    });
-    let reader = BufReader::new(file);
-    let mut emu = Emulator::new();
-    let lines: Vec<String> = reader
-        .lines()
-        .map(|l| l.unwrap_or_else(|_| String::new()))
-        .collect();
-    emu.load_program(lines);
-    emu.run();
+    }
+    let halt_reason = emu.halt_reason();
+    match &halt_reason {
+        Some(HaltReason::Blocked) => eprintln!("Waiting for input on line {}", emu.pc + 1),
+        Some(HaltReason::CycleLimit) => {
+            eprintln!("Stopped at the cycle limit before line {}", emu.pc + 1);
+        }
+        Some(HaltReason::Timeout) => eprintln!("Timed out before line {}", emu.pc + 1),
+        _ => {}
+    }
     emu.print_state();
 
     // Examples of control flow usage:
@@ -235,4 +1117,506 @@ fn main() {
     println!("mov 0, acc\njez is_zero\nmov 1, acc\nret\nis_zero: mov 42, acc\nret\n");
     println!("\nExample 3: Jump forward and return\n");
     println!("mov 10, acc\njmp end\nmov 99, acc\nend: ret\n");
+    if let Some(reason) = halt_reason {
+        std::process::exit(reason.exit_code());
+    }
+}
+
+// Print how to use the command line and exit with status 1
+fn print_usage(program: &str) -> ! {
+    eprintln!("Usage: {program} [options] [input_file]");
+    eprintln!("       {program} dump-ast [--format json] <input_file>");
+    eprintln!("       {program} fmt [--check] <input_file>");
+    eprintln!("       {program} minify [--keep <label>]... <input_file>");
+    eprintln!("       {program} corpus run [--bless] [--report <file>] [--format text|tap] [dir]");
+    eprintln!("       {program} mutate <input_file>");
+    eprintln!("       {program} stats <input_file>");
+    eprintln!("       {program} annotate <input_file>");
+    eprintln!("       {program} taint <input_file> [--input <path>]");
+    eprintln!("       {program} conformance [--bless] [--report <file>] [--format text|tap] [dir]");
+    eprintln!("       {program} sweep <input_file> --set <reg>=<a>..<b>... [--format table|csv]");
+    eprintln!(
+        "       {program} montecarlo <input_file> [--runs <n>] [--seed <n>] [--set <reg>=<a>..<b>]..."
+    );
+    eprintln!(
+        "       {program} verify --exhaustive <input_file> --expect <outputs> [--domain <a>..<b>] [--length <n>]"
+    );
+    eprintln!("       {program} trace-seek <trace_file> <cycle>");
+    eprintln!(
+        "       {program} daemon [--store <dir>] [--audit <file> [--audit-max-bytes <n>]] [--spans <file>] <socket>"
+    );
+    eprintln!("       {program} new <dir>");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --set <reg>=<value>       Start with acc or bak set to <value>");
+    eprintln!("  --timer <cycles>          Fire a timer interrupt every <cycles> cycles");
+    eprintln!("  --timer-handler <label>   Label the timer jumps to (default: timer)");
+    eprintln!("  --entry <label>           Start at <label> instead of the program's .entry");
+    eprintln!("                            or first line");
+    eprintln!("  --max-lines <n>           Reject programs longer than <n> lines");
+    eprintln!("  --max-instructions <n>    Reject programs with more than <n> instructions");
+    eprintln!("  --max-label-len <n>       Reject labels longer than <n> characters");
+    eprintln!("  --max-table-values <n>    Reject programs with more than <n> table values");
+    eprintln!("  --max-cycles <n>          Stop the run after <n> cycles (exit status 2)");
+    eprintln!("  --timeout <ms>            Stop the run after <ms> milliseconds (exit status 2)");
+    eprintln!("  --max-output <n>          Fail once the program writes more than <n> values");
+    eprintln!("  --sandbox                 Run an untrusted program: default limits for all of");
+    eprintln!("                            the above that are not given, no cache and no dbg");
+    eprintln!("  -W <lint>                 Warn on <lint> (or \"all\")");
+    eprintln!("  -A <lint>                 Allow <lint> (or \"all\")");
+    eprintln!("  --deny-warnings           Treat warnings as errors");
+    eprintln!("  --max-complexity <n>      Warn on labeled regions more complex than <n>");
+    eprintln!("                            (default: 10)");
+    eprintln!("  --profile <profile>       extended (default) or tis100, which only accepts");
+    eprintln!("                            programs the original game does");
+    eprintln!("  --strict-bak              Only allow bak to be read through swp and save");
+    eprintln!("  --strip-dbg               Ignore dbg instructions (for scored runs)");
+    eprintln!("  --env-subst               Replace ${{NAME}} in the program with the integer in");
+    eprintln!("                            environment variable NAME");
+    eprintln!("  --realtime                Let slp pause for its operand in milliseconds");
+    eprintln!("  --input <path>            Read values for `in` from <path>, or stdin for -");
+    eprintln!("  --prompt                  Ask for values for `in` on the terminal");
+    eprintln!("  --out-path <path>         Write values from `out` to <path>, such as a FIFO,");
+    eprintln!("                            instead of stdout");
+    #[cfg(unix)]
+    eprintln!("  --connect <socket>        Exchange `in` and `out` values with the process");
+    #[cfg(unix)]
+    eprintln!("                            listening on a Unix socket, one per line");
+    eprintln!("  --on-input-end <action>   When input runs out: error (default), halt or block");
+    eprintln!("  --encoding <encoding>     How outc writes characters: utf8 (default), ascii");
+    eprintln!("                            or latin1");
+    eprintln!("  --diagnostics <format>    Diagnostic format: human (default) or json");
+    eprintln!("  --cache                   Cache parsed programs under $XDG_CACHE_HOME");
+    eprintln!("  --stream                  Start running while the file is still being parsed");
+    eprintln!("                            (lints and most size limits are not applied)");
+    eprintln!("  --cycle-cost <op>=<n>     Count <n> >= 1 cycles for each <op> (default: 1)");
+    eprintln!("  --cost-model <path>       Score an energy total from a JSON object of weights");
+    eprintln!("                            by instruction, e.g. {{\"mov\": 1, \"default\": 2}}");
+    eprintln!("  --jump-history <n>        Jumps shown in runtime errors (default: 16)");
+    eprintln!("  --core-dump <path>        Write machine state to <path> on a runtime error");
+    eprintln!("  --output <format>         Report the final state as table, json, csv or toml");
+    eprintln!("  --output-file <path>      Write the report to <path> instead of stdout");
+    eprintln!("  --trace-format <format>   Write an execution trace: chrome (for Perfetto),");
+    eprintln!("                            folded (stacks for flamegraph tools) or binary");
+    eprintln!("                            (compact and seekable with trace-seek)");
+    eprintln!("  --trace-file <path>       Where to write the trace (default: trace.json,");
+    eprintln!("                            trace.folded or trace.bin)");
+    eprintln!("  --plot <reg>:<path>       Write an SVG chart of acc or bak over the run");
+    eprintln!();
+    eprintln!("Without an input file, the program named by the tis.toml in the current");
+    eprintln!("directory or a parent is run, with the manifest's settings as defaults.");
+    eprintln!();
+    eprintln!("The exit status is 0 when the program finishes, 1 on an error, 2 at the");
+    eprintln!("cycle limit or timeout and 3 when it is left waiting for input.");
+    eprintln!();
+    eprintln!("Lints:");
+    for lint in Lint::ALL {
+        eprintln!("  {:<24}  {}", lint.name(), lint.description());
+    }
+    std::process::exit(1);
+}
+
+// The options of a run, from the command line. The bools are independent
+// switches, one per flag, not states that could be an enum.
+#[derive(Default)]
+#[allow(clippy::struct_excessive_bools)]
+struct RunOptions<'a> {
+    program: &'a str,
+    filename: Option<&'a String>,
+    timer_period: Option<u64>,
+    timer_handler: Option<String>,
+    entry: Option<String>,
+    limits: Limits,
+    lints: LintConfig,
+    json_diagnostics: bool,
+    use_cache: bool,
+    cycle_costs: CycleCosts,
+    cost_model: Option<CostModel>,
+    jump_history: JumpHistory,
+    core_dump: Option<&'a String>,
+    profile: Option<Profile>,
+    strict_bak: bool,
+    strip_dbg: bool,
+    substitute_env: bool,
+    realtime: bool,
+    sandbox: bool,
+    input: Option<&'a str>,
+    prompt: bool,
+    out_path: Option<&'a str>,
+    connect: Option<&'a str>,
+    on_input_end: InputEnd,
+    encoding: Encoding,
+    stream: bool,
+    output: Option<Format>,
+    output_file: Option<&'a String>,
+    registers: Vec<(Register, i32)>,
+    trace_format: Option<&'a str>,
+    trace_file: Option<&'a str>,
+    plots: Vec<(Register, &'a str)>,
+}
+
+impl<'a> RunOptions<'a> {
+    // The options in `args`, exiting with the usage if one is not valid
+    fn parse(args: &'a [String]) -> Self {
+        let mut options = RunOptions {
+            program: &args[0],
+            ..RunOptions::default()
+        };
+        let mut iter = args.iter().skip(1);
+        while let Some(arg) = iter.next() {
+            if options.load_flag(arg, &mut iter)
+                || options.limit_flag(arg, &mut iter)
+                || options.io_flag(arg, &mut iter)
+            {
+                continue;
+            }
+            if options.filename.is_some() {
+                print_usage(options.program);
+            }
+            options.filename = Some(arg);
+        }
+        options
+    }
+
+    // Take `arg` if it sets how the program is loaded and run, with its value
+    // from `iter`
+    fn load_flag(&mut self, arg: &str, iter: &mut impl Iterator<Item = &'a String>) -> bool {
+        let program = self.program;
+        let usage = || -> ! { print_usage(program) };
+        match arg {
+            "--timer" => match flag_value(iter) {
+                Some(p) if p > 0 => self.timer_period = Some(p),
+                _ => usage(),
+            },
+            "-W" | "-A" => {
+                let selected = match iter.next().map(String::as_str) {
+                    Some("all") => Lint::ALL.to_vec(),
+                    Some(name) => vec![Lint::from_name(name).unwrap_or_else(|| usage())],
+                    None => usage(),
+                };
+                for lint in selected {
+                    self.lints.set_allowed(lint, arg == "-A");
+                }
+            }
+            "--deny-warnings" => self.lints.deny_warnings = true,
+            "--max-complexity" => {
+                self.lints.max_complexity = Some(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--strict-bak" => self.strict_bak = true,
+            "--strip-dbg" => self.strip_dbg = true,
+            "--env-subst" => self.substitute_env = true,
+            "--realtime" => self.realtime = true,
+            "--profile" => {
+                self.profile = Some(
+                    iter.next()
+                        .and_then(|name| Profile::from_name(name))
+                        .unwrap_or_else(|| usage()),
+                );
+            }
+            "--cache" => self.use_cache = true,
+            "--stream" => self.stream = true,
+            "--set" => self.registers.push(
+                iter.next()
+                    .and_then(|arg| register_value(arg))
+                    .unwrap_or_else(|| usage()),
+            ),
+            "--jump-history" => {
+                self.jump_history =
+                    JumpHistory::with_capacity(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--cost-model" => {
+                let Some(path) = iter.next() else { usage() };
+                let text = fs::read_to_string(path).unwrap_or_else(|e| {
+                    eprintln!("Could not read {path}: {e}");
+                    std::process::exit(1);
+                });
+                self.cost_model = Some(CostModel::from_json(&text).unwrap_or_else(|e| {
+                    eprintln!("{path}: {e}");
+                    std::process::exit(1);
+                }));
+            }
+            "--cycle-cost" => {
+                let cost = iter.next().and_then(|v| v.split_once('='));
+                let Some((mnemonic, Ok(cycles))) = cost.map(|(m, n)| (m, n.parse())) else {
+                    usage()
+                };
+                if let Err(e) = self.cycle_costs.set(mnemonic, cycles) {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+            }
+            "--diagnostics" => match iter.next().map(String::as_str) {
+                Some("human") => self.json_diagnostics = false,
+                Some("json") => self.json_diagnostics = true,
+                _ => usage(),
+            },
+            "--timer-handler" => match iter.next() {
+                Some(label) => self.timer_handler = Some(label.clone()),
+                None => usage(),
+            },
+            "--entry" => match iter.next() {
+                Some(label) => self.entry = Some(label.clone()),
+                None => usage(),
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    // Take `arg` if it sets a limit on the program or its run, with its
+    // value from `iter`
+    fn limit_flag(&mut self, arg: &str, iter: &mut impl Iterator<Item = &'a String>) -> bool {
+        let program = self.program;
+        let usage = || -> ! { print_usage(program) };
+        match arg {
+            "--max-lines" => {
+                self.limits.max_lines = Some(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--max-cycles" => {
+                self.limits.max_cycles = Some(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--max-instructions" => {
+                self.limits.max_instructions = Some(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--max-label-len" => {
+                self.limits.max_label_len = Some(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--max-table-values" => {
+                self.limits.max_table_values = Some(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--max-output" => {
+                self.limits.max_output = Some(flag_value(iter).unwrap_or_else(|| usage()));
+            }
+            "--timeout" => {
+                let millis = flag_value(iter).unwrap_or_else(|| usage());
+                self.limits.timeout = Some(Duration::from_millis(millis));
+            }
+            "--sandbox" => self.sandbox = true,
+            _ => return false,
+        }
+        true
+    }
+
+    // Take `arg` if it sets where input, output, reports and traces go, with
+    // its value from `iter`
+    fn io_flag(&mut self, arg: &str, iter: &mut impl Iterator<Item = &'a String>) -> bool {
+        let program = self.program;
+        let usage = || -> ! { print_usage(program) };
+        match arg {
+            "--input" => match iter.next() {
+                Some(path) => self.input = Some(path.as_str()),
+                None => usage(),
+            },
+            "--prompt" => self.prompt = true,
+            "--out-path" => match iter.next() {
+                Some(path) => self.out_path = Some(path.as_str()),
+                None => usage(),
+            },
+            #[cfg(unix)]
+            "--connect" => match iter.next() {
+                Some(path) => self.connect = Some(path.as_str()),
+                None => usage(),
+            },
+            "--encoding" => {
+                self.encoding = iter
+                    .next()
+                    .and_then(|name| Encoding::from_name(name))
+                    .unwrap_or_else(|| usage());
+            }
+            "--on-input-end" => {
+                self.on_input_end = iter
+                    .next()
+                    .and_then(|name| InputEnd::from_name(name))
+                    .unwrap_or_else(|| usage());
+            }
+            "--plot" => self.plots.push(
+                iter.next()
+                    .and_then(|arg| plot_target(arg))
+                    .unwrap_or_else(|| usage()),
+            ),
+            "--output" => {
+                self.output = Some(
+                    iter.next()
+                        .and_then(|name| Format::from_name(name))
+                        .unwrap_or_else(|| usage()),
+                );
+            }
+            "--output-file" => match iter.next() {
+                Some(path) => self.output_file = Some(path),
+                None => usage(),
+            },
+            "--core-dump" => match iter.next() {
+                Some(path) => self.core_dump = Some(path),
+                None => usage(),
+            },
+            "--trace-format" => match iter.next().map(String::as_str) {
+                Some(format @ ("chrome" | "folded" | "binary")) => self.trace_format = Some(format),
+                _ => usage(),
+            },
+            "--trace-file" => match iter.next() {
+                Some(path) => self.trace_file = Some(path.as_str()),
+                None => usage(),
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    // The program to run: the file on the command line, or else the one
+    // named by the project manifest, whose settings become defaults for the
+    // flags
+    fn source_file(&mut self) -> String {
+        if let Some(filename) = self.filename {
+            return filename.clone();
+        }
+        let manifest = project_manifest();
+        let Some(source) = manifest.source else {
+            eprintln!("{} does not name a program source", manifest::FILE_NAME);
+            std::process::exit(1);
+        };
+        self.profile = self.profile.or(manifest.profile);
+        self.strict_bak |= manifest.strict_bak;
+        self.timer_period = self.timer_period.or(manifest.timer);
+        self.timer_handler = self.timer_handler.take().or(manifest.timer_handler);
+        source.display().to_string()
+    }
+
+    // An emulator with these settings and its input and output connected
+    fn emulator(&mut self) -> Emulator {
+        if self.sandbox && self.realtime {
+            eprintln!("--sandbox cannot be combined with --realtime");
+            std::process::exit(1);
+        }
+        let mut emu = Emulator::new();
+        emu.limits = self.limits;
+        emu.lints = std::mem::take(&mut self.lints);
+        emu.cache = self.use_cache;
+        emu.cycle_costs = std::mem::take(&mut self.cycle_costs);
+        emu.cost_model = self.cost_model.take();
+        emu.jump_history = std::mem::take(&mut self.jump_history);
+        emu.profile = self.profile.unwrap_or_default();
+        emu.strict_bak = self.strict_bak;
+        emu.strip_dbg = self.strip_dbg;
+        emu.substitute_env = self.substitute_env;
+        emu.realtime = self.realtime;
+        if self.sandbox {
+            let limits = std::mem::take(&mut emu.limits);
+            emu.sandbox(&Sandbox::default());
+            emu.limits = limits.or(emu.limits);
+        }
+        if self.connect.is_some() && (self.input.is_some() || self.out_path.is_some()) {
+            eprintln!("--connect cannot be combined with --input or --out-path");
+            std::process::exit(1);
+        }
+        if self.prompt && (self.input.is_some() || self.connect.is_some()) {
+            eprintln!("--prompt cannot be combined with --input or --connect");
+            std::process::exit(1);
+        }
+        match self.out_path {
+            // Unbuffered, so whatever reads the other end sees each value at once
+            Some(path) => match File::create(path) {
+                Ok(file) => emu.set_output(WriterSink(file)),
+                Err(e) => {
+                    eprintln!("Could not open {path}: {e}");
+                    std::process::exit(1);
+                }
+            },
+            None => emu.set_output(WriterSink(io::stdout())),
+        }
+        emu.set_text_output(io::stdout());
+        emu.encoding = self.encoding;
+        emu.on_input_end = self.on_input_end;
+        #[cfg(unix)]
+        if let Some(path) = self.connect {
+            match tis31337::stream::connect(path) {
+                Ok((source, sink)) => {
+                    emu.set_input(source);
+                    emu.set_output(sink);
+                }
+                Err(e) => {
+                    eprintln!("Could not connect to {path}: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        match self.input {
+            Some("-") => emu.set_input(ReaderSource::new(BufReader::new(io::stdin()))),
+            Some(path) => match ReaderSource::open(path) {
+                Ok(source) => emu.set_input(source),
+                Err(e) => {
+                    eprintln!("Could not open {path}: {e}");
+                    std::process::exit(1);
+                }
+            },
+            // Asked for on stderr, so the prompts stay out of the output
+            None if self.prompt => emu.set_input(PromptSource::new(
+                BufReader::new(io::stdin()),
+                io::stderr(),
+                "input> ",
+            )),
+            None => {}
+        }
+        if let Some(period) = self.timer_period {
+            emu.set_timer(period, self.timer_handler.as_deref().unwrap_or("timer"));
+        }
+        for &(register, value) in &self.registers {
+            emu.set_register(register, value);
+        }
+        emu
+    }
+
+    // Load `filename` into `emu`, or start streaming it
+    fn load(&mut self, emu: &mut Emulator, filename: &str) {
+        if self.stream {
+            if self.trace_format.is_some() {
+                eprintln!("--stream cannot be combined with --trace-format");
+                std::process::exit(1);
+            }
+            if self.entry.is_some() {
+                eprintln!("--stream cannot be combined with --entry");
+                std::process::exit(1);
+            }
+            let file = File::open(filename).unwrap_or_else(|_| {
+                eprintln!("Could not open file: {filename}");
+                std::process::exit(1);
+            });
+            emu.load_streaming(BufReader::new(file));
+        } else {
+            emu.entry = self.entry.take();
+            load(emu, filename, self.json_diagnostics);
+        }
+    }
+
+    // Attach the trace and plots asked for to `emu`
+    fn observe(&self, emu: &mut Emulator) {
+        if let Some(format) = self.trace_format {
+            let default = match format {
+                "chrome" => "trace.json",
+                "folded" => "trace.folded",
+                _ => "trace.bin",
+            };
+            let path = self.trace_file.unwrap_or(default);
+            let file = File::create(path).unwrap_or_else(|e| {
+                eprintln!("Could not create {path}: {e}");
+                std::process::exit(1);
+            });
+            let out = BufWriter::new(file);
+            match format {
+                "chrome" => emu.add_observer(Box::new(ChromeTrace::new(emu.labels(), out))),
+                "folded" => emu.add_observer(Box::new(FoldedStacks::new(emu.labels(), out))),
+                _ => emu.add_observer(Box::new(BinaryTrace::new(emu.acc, emu.bak, out))),
+            }
+        }
+        for &(register, path) in &self.plots {
+            let file = File::create(path).unwrap_or_else(|e| {
+                eprintln!("Could not create {path}: {e}");
+                std::process::exit(1);
+            });
+            let initial = match register {
+                Register::Acc => emu.acc,
+                Register::Bak => emu.bak,
+            };
+            emu.add_observer(Box::new(Plot::new(register, initial, BufWriter::new(file))));
+        }
+    }
 }
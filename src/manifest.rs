@@ -0,0 +1,168 @@
+// Project manifests. A `tis.toml` names the program to run, the profile it
+// is checked against and the corpus holding its tests, so commands given no
+// file or directory can work from the manifest, the way cargo does:
+//
+//     [program]
+//     source = "main.tis"
+//     profile = "tis100"
+//     strict-bak = true
+//     timer = 100
+//
+//     [test]
+//     corpus = "tests"
+//
+// Only the subset of TOML needed for this is understood: sections, and keys
+// with string, integer or boolean values.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::profile::Profile;
+
+pub const FILE_NAME: &str = "tis.toml";
+
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    // The directory holding the manifest; the paths below are relative to it
+    pub root: PathBuf,
+    pub source: Option<PathBuf>,
+    pub profile: Option<Profile>,
+    pub strict_bak: bool,
+    pub timer: Option<u64>,
+    pub timer_handler: Option<String>,
+    pub corpus: Option<PathBuf>,
+}
+
+enum Value {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Bool(_) => "a boolean",
+        }
+    }
+}
+
+// A value and anything after it on the line, or `None` if it is malformed
+fn parse_value(text: &str) -> Option<(Value, &str)> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::String(value), &rest[idx + 1..])),
+                '\\' => match chars.next()?.1 {
+                    '"' => value.push('"'),
+                    '\\' => value.push('\\'),
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    _ => return None,
+                },
+                _ => value.push(c),
+            }
+        }
+        return None;
+    }
+    let end = text.find(['#', ' ', '\t']).unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    let value = match token {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => Value::Integer(token.replace('_', "").parse().ok()?),
+    };
+    Some((value, rest))
+}
+
+impl Manifest {
+    // The nearest manifest in `dir` or one of its ancestors
+    #[must_use]
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    pub fn load(path: &Path) -> Result<Manifest, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let root = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Manifest::parse(&text, root).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str, root: PathBuf) -> Result<Manifest, String> {
+        let mut manifest = Manifest {
+            root,
+            ..Manifest::default()
+        };
+        let mut section = String::new();
+        for (idx, line) in text.lines().enumerate() {
+            let err = |message: String| format!("line {}: {}", idx + 1, message);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix('[') {
+                let Some((name, rest)) = rest.split_once(']') else {
+                    return Err(err("unterminated section header".to_string()));
+                };
+                let rest = rest.trim();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(err(format!("unexpected `{rest}` after section header")));
+                }
+                section = name.trim().to_string();
+                if section != "program" && section != "test" {
+                    return Err(err(format!("unknown section [{section}]")));
+                }
+                continue;
+            }
+            let Some((key, text)) = line.split_once('=') else {
+                return Err(err(format!("expected `key = value`, found `{line}`")));
+            };
+            let key = key.trim().trim_matches('"');
+            let Some((value, rest)) = parse_value(text.trim()) else {
+                return Err(err(format!("invalid value for `{key}`")));
+            };
+            let rest = rest.trim();
+            if !rest.is_empty() && !rest.starts_with('#') {
+                return Err(err(format!("unexpected `{rest}` after value")));
+            }
+            let kind = value.kind();
+            let expected = |wanted: &str| err(format!("`{key}` must be {wanted}, not {kind}"));
+            match (section.as_str(), key, value) {
+                ("program", "source", Value::String(path)) => {
+                    manifest.source = Some(manifest.root.join(path));
+                }
+                ("program", "profile", Value::String(name)) => {
+                    let profile = Profile::from_name(&name)
+                        .ok_or_else(|| err(format!("unknown profile `{name}`")))?;
+                    manifest.profile = Some(profile);
+                }
+                ("program", "strict-bak", Value::Bool(strict)) => manifest.strict_bak = strict,
+                ("program", "timer", Value::Integer(period)) => match u64::try_from(period) {
+                    Ok(period) if period > 0 => manifest.timer = Some(period),
+                    _ => return Err(err("`timer` must be positive".to_string())),
+                },
+                ("program", "timer-handler", Value::String(label)) => {
+                    manifest.timer_handler = Some(label);
+                }
+                ("test", "corpus", Value::String(path)) => {
+                    manifest.corpus = Some(manifest.root.join(path));
+                }
+                ("program", "source" | "profile" | "timer-handler", _) | ("test", "corpus", _) => {
+                    return Err(expected("a string"));
+                }
+                ("program", "strict-bak", _) => return Err(expected("a boolean")),
+                ("program", "timer", _) => return Err(expected("an integer")),
+                ("", _, _) => return Err(err(format!("`{key}` is outside any section"))),
+                _ => return Err(err(format!("unknown key `{key}` in [{section}]"))),
+            }
+        }
+        Ok(manifest)
+    }
+}
@@ -0,0 +1,317 @@
+// Smallest textual form of a program, for size-scored challenges. Comments,
+// blank lines and instructions with no effect (`mov acc, acc`, `add 0`) are
+// dropped, label-only lines are merged into the next instruction, unused
+// labels are removed and the rest renamed to the shortest free names. Tables
+// that are looked up are kept, after the code, and an `.entry` is kept
+// before it. The final state is unchanged, though dropped instructions no
+// longer take cycles; a program that could notice, because it reads `clk` or
+// may be interrupted by a timer, keeps them.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::parser::{Instruction, MNEMONICS, Operand, Program};
+
+fn is_nop(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Mov(Operand::Acc, Operand::Acc) | Instruction::Add(Operand::Imm(0))
+    )
+}
+
+fn reads_clk(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Mov(Operand::Clk, _)
+            | Instruction::Add(Operand::Clk)
+            | Instruction::Out(Operand::Clk)
+            | Instruction::Outc(Operand::Clk)
+            | Instruction::Slp(Operand::Clk)
+    )
+}
+
+fn target(instruction: &Instruction) -> Option<&String> {
+    match instruction {
+        Instruction::Jmp(l)
+        | Instruction::Jez(l)
+        | Instruction::Jnz(l)
+        | Instruction::Jgz(l)
+        | Instruction::Jlz(l) => Some(l),
+        _ => None,
+    }
+}
+
+// Short label names in order: a..z, aa..zz, ..., skipping names that could be
+// misread or that are reserved
+fn short_names(reserved: &HashSet<&str>) -> impl Iterator<Item = String> {
+    (1..)
+        .flat_map(|len| {
+            let count = 26usize.pow(len);
+            (0..count).map(move |mut n| {
+                let mut name = vec![b'a'; len as usize];
+                for byte in name.iter_mut().rev() {
+                    *byte = b"abcdefghijklmnopqrstuvwxyz"[n % 26];
+                    n /= 26;
+                }
+                String::from_utf8(name).unwrap_or_default()
+            })
+        })
+        .filter(|name| {
+            !reserved.contains(name.as_str())
+                && !MNEMONICS.contains(&name.as_str())
+                && name != "acc"
+                && name != "bak"
+        })
+}
+
+// Minify `program`. Labels in `keep`, such as a timer handler, are kept under
+// their own names even when no jump refers to them. Since any of them may be
+// a timer handler, a program with one keeps its cycle count.
+pub fn minify(program: &Program, keep: &[&str]) -> String {
+    let mut used: HashSet<&str> = program
+        .lines
+        .iter()
+        .filter_map(|line| line.instruction.as_ref().and_then(target))
+        .map(String::as_str)
+        .collect();
+    let keep: HashSet<&str> = keep
+        .iter()
+        .copied()
+        .filter(|name| program.labels.contains_key(*name))
+        .collect();
+    used.extend(&keep);
+    used.extend(program.entry_label());
+    let timed = !keep.is_empty()
+        || program
+            .lines
+            .iter()
+            .filter_map(|line| line.instruction.as_ref())
+            .any(reads_clk);
+    let looked_up: HashSet<&str> = program
+        .lines
+        .iter()
+        .filter_map(|line| match &line.instruction {
+            Some(Instruction::Lookup(name)) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    // Instructions to emit, each with the used labels that mark it. A final
+    // entry without an instruction holds labels at the end of the program.
+    let mut items: Vec<Item> = Vec::new();
+    let mut pending = Vec::new();
+    for line in &program.lines {
+        if let Some((name, _)) = &line.label
+            && used.contains(name.as_str())
+        {
+            pending.push(name.as_str());
+        }
+        if let Some(instruction) = &line.instruction
+            && (timed || !is_nop(instruction))
+        {
+            items.push((std::mem::take(&mut pending), Some(instruction)));
+        }
+    }
+    if !pending.is_empty() {
+        items.push((pending, None));
+    }
+
+    let (renamed, item_names) = rename(&items, &keep);
+
+    let mut out = String::new();
+    if let Some(label) = program.entry_label() {
+        let _ = writeln!(
+            out,
+            ".entry {}",
+            renamed.get(label).map_or(label, String::as_str)
+        );
+    }
+    for ((_, instruction), group) in items.iter().zip(&item_names) {
+        for (idx, name) in group.iter().enumerate() {
+            out.push_str(name);
+            out.push(':');
+            if idx + 1 < group.len() || instruction.is_none() {
+                out.push('\n');
+            } else {
+                out.push(' ');
+            }
+        }
+        if let Some(instruction) = instruction {
+            let instruction = match target(instruction) {
+                Some(label) => retarget(instruction, renamed.get(label.as_str()).unwrap_or(label)),
+                None => (*instruction).clone(),
+            };
+            out.push_str(&instruction.to_string().replace(", ", ","));
+            out.push('\n');
+        }
+    }
+    for line in &program.lines {
+        if let Some(table) = &line.table
+            && looked_up.contains(table.name.as_str())
+        {
+            out.push_str(&table.to_string().replace(", ", ","));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// An instruction with the labels that mark it, or `None` for labels at the
+// end of the program
+type Item<'a> = (Vec<&'a str>, Option<&'a Instruction>);
+
+// New names for the labels of `items`, and the names to write before each
+// item. Labels marking the same instruction share one name; kept labels also
+// keep theirs, on lines of their own before the instruction.
+fn rename<'a>(
+    items: &[Item<'a>],
+    keep: &HashSet<&str>,
+) -> (HashMap<&'a str, String>, Vec<Vec<String>>) {
+    let mut names = short_names(keep);
+    let mut renamed: HashMap<&str, String> = HashMap::new();
+    let mut item_names = Vec::new();
+    for (labels, _) in items {
+        let mut group: Vec<String> = labels
+            .iter()
+            .filter(|l| keep.contains(*l))
+            .map(ToString::to_string)
+            .collect();
+        if group.is_empty() && !labels.is_empty() {
+            group.push(names.next().unwrap_or_default());
+        }
+        for label in labels {
+            let name = if keep.contains(label) {
+                label.to_string()
+            } else {
+                group[0].clone()
+            };
+            renamed.insert(label, name);
+        }
+        item_names.push(group);
+    }
+    (renamed, item_names)
+}
+
+// `instruction`, a jump, aimed at `label` instead
+fn retarget(instruction: &Instruction, label: &str) -> Instruction {
+    let label = label.to_string();
+    match instruction {
+        Instruction::Jmp(_) => Instruction::Jmp(label),
+        Instruction::Jez(_) => Instruction::Jez(label),
+        Instruction::Jnz(_) => Instruction::Jnz(label),
+        Instruction::Jgz(_) => Instruction::Jgz(label),
+        Instruction::Jlz(_) => Instruction::Jlz(label),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::emulator::{Emulator, HaltReason, InputEnd};
+    use crate::parser;
+
+    // Everything a run leaves behind that the minified program must match
+    struct Outcome {
+        output: Vec<i32>,
+        text: Vec<u8>,
+        acc: i32,
+        bak: i32,
+        halt: Option<HaltReason>,
+        cycles: u64,
+    }
+
+    fn run(source: &str, inputs: &[i32]) -> Outcome {
+        let lines: Vec<&str> = source.lines().collect();
+        let mut emu = Emulator::new();
+        emu.load_program(&lines).unwrap();
+        emu.on_input_end = InputEnd::Halt;
+        emu.set_input(inputs.iter().copied().collect::<VecDeque<i32>>());
+        emu.limits.max_cycles = Some(10_000);
+        let _ = emu.run();
+        Outcome {
+            output: emu.output().to_vec(),
+            text: emu.text_output().to_vec(),
+            acc: emu.acc,
+            bak: emu.bak,
+            halt: emu.halt_reason(),
+            cycles: emu.cycles,
+        }
+    }
+
+    // Minify `source`, checking that both forms end the same way on `inputs`
+    fn check(source: &str, inputs: &[i32]) -> (String, Outcome, Outcome) {
+        let lines: Vec<&str> = source.lines().collect();
+        let minified = minify(&parser::parse(&lines).unwrap(), &[]);
+        let before = run(source, inputs);
+        let after = run(&minified, inputs);
+        assert_eq!(after.output, before.output, "{minified}");
+        assert_eq!(after.text, before.text, "{minified}");
+        assert_eq!(
+            (after.acc, after.bak),
+            (before.acc, before.bak),
+            "{minified}"
+        );
+        assert_eq!(after.halt, before.halt, "{minified}");
+        (minified, before, after)
+    }
+
+    #[test]
+    fn keeps_what_a_loop_computes() {
+        let source = "\
+# Sum the inputs until a zero
+start:
+    in acc
+    jez done
+    add bak
+    mov acc, acc
+    save
+    add 0
+    jmp start
+
+done:
+    mov bak, acc
+    out acc
+    outc 33
+";
+        let (minified, before, after) = check(source, &[3, 4, 5, 0]);
+        assert_eq!(before.output, [12]);
+        assert!(after.cycles < before.cycles);
+        assert!(!minified.contains('#'));
+        assert!(!minified.contains("add 0"));
+        assert!(minified.len() < source.len());
+    }
+
+    #[test]
+    fn keeps_tables_and_the_entry() {
+        let source = "\
+.table unused: 1, 2
+.entry main
+skip:
+    out 99
+main:
+    in acc
+    lookup squares
+    out acc
+    jmp main
+.table squares: 0, 1, 4, 9
+";
+        let (minified, ..) = check(source, &[2, 3, 1]);
+        assert!(minified.starts_with(".entry "));
+        assert!(minified.contains(".table squares: 0,1,4,9"));
+        assert!(!minified.contains("unused"));
+    }
+
+    #[test]
+    fn keeps_the_cycles_of_a_program_that_reads_clk() {
+        let source = "\
+    add 0
+    mov acc, acc
+    out clk
+";
+        let (_, before, after) = check(source, &[]);
+        assert_eq!(after.cycles, before.cycles);
+    }
+}
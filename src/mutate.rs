@@ -0,0 +1,111 @@
+// Mutation testing: small semantic changes to a program, used to check that
+// its expected outcome is specific enough to notice when the program breaks.
+
+use crate::emitter;
+use crate::parser::{Instruction, Operand, Program};
+
+pub struct Mutant {
+    // 1-based line that was changed
+    pub line: usize,
+    pub description: String,
+    pub lines: Vec<String>,
+}
+
+// The conditions a conditional jump is flipped to
+fn flipped(instruction: &Instruction) -> Vec<Instruction> {
+    match instruction {
+        Instruction::Jez(l) => vec![Instruction::Jnz(l.clone())],
+        Instruction::Jnz(l) => vec![Instruction::Jez(l.clone())],
+        Instruction::Jgz(l) => vec![Instruction::Jlz(l.clone()), Instruction::Jez(l.clone())],
+        Instruction::Jlz(l) => vec![Instruction::Jgz(l.clone()), Instruction::Jez(l.clone())],
+        _ => Vec::new(),
+    }
+}
+
+// The instruction with its immediate operands moved by one in each
+// direction, skipping a direction that would overflow
+fn perturbed(instruction: &Instruction) -> Vec<Instruction> {
+    let nudge = |op: Operand| match op {
+        Operand::Imm(v) => [v.checked_sub(1), v.checked_add(1)]
+            .into_iter()
+            .flatten()
+            .map(Operand::Imm)
+            .collect(),
+        _ => Vec::new(),
+    };
+    match instruction {
+        Instruction::Mov(src, dst) => nudge(*src)
+            .into_iter()
+            .map(|src| Instruction::Mov(src, *dst))
+            .collect(),
+        Instruction::Add(src) => nudge(*src).into_iter().map(Instruction::Add).collect(),
+        Instruction::Out(src) => nudge(*src).into_iter().map(Instruction::Out).collect(),
+        Instruction::Outc(src) => nudge(*src).into_iter().map(Instruction::Outc).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Every mutant of `program`, whose source is `lines`: each conditional jump
+// flipped, each immediate perturbed and each instruction deleted
+pub fn mutants<S: AsRef<str>>(program: &Program, lines: &[S]) -> Vec<Mutant> {
+    let mut mutants = Vec::new();
+    for (idx, line) in program.lines.iter().enumerate() {
+        let Some(original) = &line.instruction else {
+            continue;
+        };
+        let mut replacements: Vec<(String, Option<Instruction>)> = Vec::new();
+        for instruction in flipped(original).into_iter().chain(perturbed(original)) {
+            replacements.push((format!("{original} -> {instruction}"), Some(instruction)));
+        }
+        replacements.push((format!("delete {original}"), None));
+        for (description, instruction) in replacements {
+            let mut mutated = line.clone();
+            mutated.instruction = instruction;
+            let mut lines: Vec<String> = lines.iter().map(|l| l.as_ref().to_string()).collect();
+            lines[idx] = emitter::emit_line(&mutated);
+            mutants.push(Mutant {
+                line: idx + 1,
+                description,
+                lines,
+            });
+        }
+    }
+    mutants
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn descriptions(source: &[&str]) -> Vec<String> {
+        let program = parser::parse(source).unwrap();
+        mutants(&program, source)
+            .into_iter()
+            .map(|m| m.description)
+            .collect()
+    }
+
+    #[test]
+    fn perturbs_immediates_both_ways() {
+        assert_eq!(
+            descriptions(&["add 5"]),
+            ["add 5 -> add 4", "add 5 -> add 6", "delete add 5"]
+        );
+    }
+
+    #[test]
+    fn skips_perturbations_that_overflow() {
+        let max = Instruction::Add(Operand::Imm(i32::MAX));
+        assert_eq!(
+            perturbed(&max),
+            [Instruction::Add(Operand::Imm(i32::MAX - 1))]
+        );
+        let min = Instruction::Out(Operand::Imm(i32::MIN));
+        assert_eq!(
+            perturbed(&min),
+            [Instruction::Out(Operand::Imm(i32::MIN + 1))]
+        );
+        assert_eq!(descriptions(&["add 2147483647"]).len(), 2);
+    }
+}
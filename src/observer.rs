@@ -0,0 +1,60 @@
+// Callbacks for watching a running emulator without changing the executor
+
+use std::fmt;
+
+use crate::emulator::RuntimeError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Acc,
+    Bak,
+}
+
+impl Register {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::Acc => "acc",
+            Register::Bak => "bak",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Register> {
+        [Register::Acc, Register::Bak]
+            .into_iter()
+            .find(|register| register.name() == name)
+    }
+}
+
+// Every method defaults to doing nothing, so an observer only implements the
+// events it cares about. Line indexes are 0-based, like `Emulator::pc`.
+pub trait Observer: Send {
+    // The instruction at line index `pc` starts executing at `cycle`
+    fn step(&mut self, _pc: usize, _cycle: u64) {}
+
+    // The instruction at line index `pc` wrote `new` to `register`
+    fn register_write(&mut self, _pc: usize, _register: Register, _old: i32, _new: i32) {}
+
+    // A jump at line index `from` was taken to line index `to`
+    fn jump(&mut self, _from: usize, _to: usize) {}
+
+    // The timer interrupted execution before line index `from` and entered
+    // the handler at line index `to`
+    fn interrupt(&mut self, _from: usize, _to: usize) {}
+
+    // A `reti` at line index `from` resumed execution at line index `to`
+    fn interrupt_return(&mut self, _from: usize, _to: usize) {}
+
+    // Execution stopped after `cycles` cycles, normally or with `error`
+    fn halt(&mut self, _cycles: u64, _error: Option<&RuntimeError>) {}
+}
+
+#[derive(Default)]
+pub(crate) struct Observers(pub(crate) Vec<Box<dyn Observer>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
@@ -0,0 +1,1115 @@
+// Parser for TIS assembly. Every line of the file is parsed and all errors
+// are collected, each with the span of the offending source text.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::thread;
+
+use crate::json;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Acc,
+    Bak,
+    Imm(i32),
+    // The cycle count, including the current instruction, modulo 1000
+    Clk,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Mov(Operand, Operand),
+    Swp,
+    Save,
+    Add(Operand),
+    Jmp(String),
+    Jez(String),
+    Jnz(String),
+    Jgz(String),
+    Jlz(String),
+    Ret,
+    Reti,
+    Dbg,
+    Out(Operand),
+    In(Operand),
+    Outc(Operand),
+    Slp(Operand),
+    // Replace acc with the entry of a table at index acc
+    Lookup(String),
+}
+
+impl Operand {
+    // JSON object members describing the operand, without the braces
+    fn json_fields(self) -> String {
+        match self {
+            Operand::Acc => "\"register\":\"acc\"".to_string(),
+            Operand::Bak => "\"register\":\"bak\"".to_string(),
+            Operand::Imm(value) => format!("\"immediate\":{value}"),
+            Operand::Clk => "\"register\":\"clk\"".to_string(),
+        }
+    }
+}
+
+impl Instruction {
+    #[must_use]
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Mov(..) => "mov",
+            Instruction::Swp => "swp",
+            Instruction::Save => "save",
+            Instruction::Add(_) => "add",
+            Instruction::Jmp(_) => "jmp",
+            Instruction::Jez(_) => "jez",
+            Instruction::Jnz(_) => "jnz",
+            Instruction::Jgz(_) => "jgz",
+            Instruction::Jlz(_) => "jlz",
+            Instruction::Ret => "ret",
+            Instruction::Reti => "reti",
+            Instruction::Dbg => "dbg",
+            Instruction::Out(_) => "out",
+            Instruction::In(_) => "in",
+            Instruction::Outc(_) => "outc",
+            Instruction::Slp(_) => "slp",
+            Instruction::Lookup(_) => "lookup",
+        }
+    }
+
+    fn operands_json_fields(&self) -> Vec<String> {
+        match self {
+            Instruction::Mov(src, dst) => vec![src.json_fields(), dst.json_fields()],
+            Instruction::Add(src)
+            | Instruction::Out(src)
+            | Instruction::Outc(src)
+            | Instruction::Slp(src) => vec![src.json_fields()],
+            Instruction::In(dst) => vec![dst.json_fields()],
+            Instruction::Jmp(label)
+            | Instruction::Jez(label)
+            | Instruction::Jnz(label)
+            | Instruction::Jgz(label)
+            | Instruction::Jlz(label) => vec![format!("\"label\":{}", json::string(label))],
+            Instruction::Lookup(table) => vec![format!("\"table\":{}", json::string(table))],
+            Instruction::Swp
+            | Instruction::Save
+            | Instruction::Ret
+            | Instruction::Reti
+            | Instruction::Dbg => Vec::new(),
+        }
+    }
+}
+
+// A region of a single source line: 1-based line and column, length in chars
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// A problem found while loading a program. `code` names the kind of
+// problem (for warnings it is the lint name) and `suggestion` optionally says
+// how to fix it.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    pub suggestion: Option<String>,
+}
+
+// A `.table name: 1, 2, 3` declaration of read-only values for `lookup`.
+// `span` covers the name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    pub name: String,
+    pub span: Span,
+    pub values: Vec<i32>,
+}
+
+// One source line: an optional `label:` followed by an optional instruction,
+// a `#` comment, a table, or an `.entry <label>` naming where execution
+// starts. `span` covers the whole instruction and `operands` holds one span
+// per operand.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Line {
+    pub label: Option<(String, Span)>,
+    pub instruction: Option<Instruction>,
+    pub span: Option<Span>,
+    pub operands: Vec<Span>,
+    pub comment: Option<String>,
+    pub table: Option<Table>,
+    pub entry: Option<(String, Span)>,
+}
+
+// `labels` and `tables` map names to the line index defining them, and
+// `entry` is the line index of the `.entry`, if there is one
+#[derive(Debug, Default, PartialEq)]
+pub struct Program {
+    pub lines: Vec<Line>,
+    pub labels: HashMap<String, usize>,
+    pub tables: HashMap<String, usize>,
+    pub entry: Option<usize>,
+}
+
+impl Severity {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+impl Diagnostic {
+    #[must_use]
+    pub fn error(code: &'static str, message: String, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            span,
+            suggestion: None,
+        }
+    }
+
+    fn new(code: &'static str, message: String, span: Span) -> Self {
+        Diagnostic::error(code, message, Some(span))
+    }
+
+    #[must_use]
+    pub fn with_suggestion(mut self, suggestion: &str) -> Self {
+        self.suggestion = Some(suggestion.to_string());
+        self
+    }
+
+    // Render as `error[code]: ...` followed by the source excerpt with the
+    // span underlined by carets and any suggestion
+    pub fn render<S: AsRef<str>>(&self, filename: &str, source: &[S]) -> String {
+        let mut out = format!(
+            "{}[{}]: {}\n",
+            self.severity.name(),
+            self.code,
+            self.message
+        );
+        let help = self
+            .suggestion
+            .as_ref()
+            .map(|s| format!(" = help: {s}\n"))
+            .unwrap_or_default();
+        let Some(span) = self.span else {
+            let _ = writeln!(out, " --> {filename}");
+            out.push_str(&help);
+            return out;
+        };
+        let text = source.get(span.line - 1).map_or("", AsRef::as_ref);
+        let gutter = " ".repeat(span.line.to_string().len());
+        let _ = writeln!(out, "{}--> {}:{}:{}", gutter, filename, span.line, span.col);
+        let _ = writeln!(out, "{gutter} |");
+        let _ = writeln!(out, "{} | {}", span.line, text);
+        let _ = writeln!(
+            out,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(span.col - 1),
+            "^".repeat(span.len.max(1))
+        );
+        if !help.is_empty() {
+            let _ = write!(out, "{gutter}{help}");
+        }
+        out
+    }
+
+    // One JSON object on a single line, for editors and CI tools
+    pub fn to_json(&self, filename: &str) -> String {
+        let span = self.span.map_or("null".to_string(), span_json);
+        format!(
+            "{{\"file\":{},\"span\":{},\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"suggestion\":{}}}",
+            json::string(filename),
+            span,
+            self.severity.name(),
+            self.code,
+            json::string(&self.message),
+            self.suggestion
+                .as_deref()
+                .map_or("null".to_string(), json::string)
+        )
+    }
+}
+
+fn span_json(span: Span) -> String {
+    format!(
+        "{{\"line\":{},\"column\":{},\"length\":{}}}",
+        span.line, span.col, span.len
+    )
+}
+
+impl Program {
+    // The values of the table called `name`
+    #[must_use]
+    pub fn table(&self, name: &str) -> Option<&[i32]> {
+        let table = self.lines[*self.tables.get(name)?].table.as_ref()?;
+        Some(&table.values)
+    }
+
+    // The label named by the `.entry`, if there is one
+    #[must_use]
+    pub fn entry_label(&self) -> Option<&str> {
+        let (label, _) = self.lines[self.entry?].entry.as_ref()?;
+        Some(label)
+    }
+
+    // Record the `.entry` on line index `idx`, or the error if there already
+    // is one
+    fn declare_entry(&mut self, idx: usize, span: Span) -> Option<Diagnostic> {
+        if let Some(prev) = self.entry {
+            return Some(Diagnostic::new(
+                "duplicate-entry",
+                format!("Duplicate .entry (first on line {})", prev + 1),
+                span,
+            ));
+        }
+        self.entry = Some(idx);
+        None
+    }
+
+    // Record the table declared on line index `idx`, or the error if the
+    // name is already taken
+    fn declare_table(&mut self, idx: usize, table: &Table) -> Option<Diagnostic> {
+        if let Some(&prev) = self.tables.get(&table.name) {
+            return Some(Diagnostic::new(
+                "duplicate-table",
+                format!(
+                    "Duplicate table: {} (first defined on line {})",
+                    table.name,
+                    prev + 1
+                ),
+                table.span,
+            ));
+        }
+        self.tables.insert(table.name.clone(), idx);
+        None
+    }
+
+    // Replace lines `start..end` (0-based, end exclusive) with `lines`,
+    // parsing only the new lines and patching the label table, so editors can
+    // re-parse after each change without re-reading the whole file. A line
+    // that fails to parse is kept as an empty line and its diagnostics are
+    // returned, leaving a usable program while the user is mid-edit.
+    pub fn apply_edit<S: AsRef<str>>(
+        &mut self,
+        start: usize,
+        end: usize,
+        lines: &[S],
+    ) -> Vec<Diagnostic> {
+        let end = end.min(self.lines.len());
+        let start = start.min(end);
+        let (removed, added) = (end - start, lines.len());
+        self.labels.retain(|_, idx| !(start..end).contains(idx));
+        self.tables.retain(|_, idx| !(start..end).contains(idx));
+        self.entry = self.entry.filter(|idx| !(start..end).contains(idx));
+        let indexes = self
+            .labels
+            .values_mut()
+            .chain(self.tables.values_mut())
+            .chain(self.entry.as_mut());
+        for idx in indexes {
+            if *idx >= end {
+                *idx = *idx + added - removed;
+            }
+        }
+        let mut diagnostics = Vec::new();
+        let mut parsed = Vec::with_capacity(added);
+        for (offset, text) in lines.iter().enumerate() {
+            let idx = start + offset;
+            let (mut line, line_diagnostics) = parse_line(idx, text.as_ref());
+            if !line_diagnostics.is_empty() {
+                diagnostics.extend(line_diagnostics);
+                line = Line::default();
+            } else if let Some((name, label_span)) = &line.label {
+                if let Some(&prev) = self.labels.get(name) {
+                    diagnostics.push(Diagnostic::new(
+                        "duplicate-label",
+                        format!(
+                            "Duplicate label: {} (first defined on line {})",
+                            name,
+                            prev + 1
+                        ),
+                        *label_span,
+                    ));
+                } else {
+                    self.labels.insert(name.clone(), idx);
+                }
+            } else if let Some(table) = &line.table {
+                diagnostics.extend(self.declare_table(idx, table));
+            } else if let Some((_, span)) = &line.entry {
+                diagnostics.extend(self.declare_entry(idx, *span));
+            }
+            parsed.push(line);
+        }
+        self.lines.splice(start..end, parsed);
+        if added != removed {
+            for line in &mut self.lines[start + added..] {
+                let spans = line
+                    .label
+                    .iter_mut()
+                    .map(|(_, span)| span)
+                    .chain(line.span.as_mut())
+                    .chain(line.operands.iter_mut())
+                    .chain(line.table.as_mut().map(|table| &mut table.span))
+                    .chain(line.entry.as_mut().map(|(_, span)| span));
+                for span in spans {
+                    span.line = span.line + added - removed;
+                }
+            }
+        }
+        diagnostics
+    }
+
+    // The parsed program as JSON: every line holding a label or instruction,
+    // with its source line number and spans, plus the labels, tables and
+    // entry label
+    pub fn to_json(&self, filename: &str) -> String {
+        let mut lines = Vec::new();
+        for (idx, line) in self.lines.iter().enumerate() {
+            if line.label.is_none() && line.instruction.is_none() {
+                continue;
+            }
+            let label = line
+                .label
+                .as_ref()
+                .map_or("null".to_string(), |(name, span)| {
+                    format!(
+                        "{{\"name\":{},\"span\":{}}}",
+                        json::string(name),
+                        span_json(*span)
+                    )
+                });
+            let instruction = match (&line.instruction, line.span) {
+                (Some(instruction), Some(span)) => {
+                    let operands: Vec<String> = instruction
+                        .operands_json_fields()
+                        .iter()
+                        .zip(&line.operands)
+                        .map(|(operand, span)| {
+                            format!("{{{},\"span\":{}}}", operand, span_json(*span))
+                        })
+                        .collect();
+                    format!(
+                        "{{\"mnemonic\":\"{}\",\"operands\":[{}],\"span\":{}}}",
+                        instruction.mnemonic(),
+                        operands.join(","),
+                        span_json(span)
+                    )
+                }
+                _ => "null".to_string(),
+            };
+            lines.push(format!(
+                "{{\"line\":{},\"label\":{},\"instruction\":{}}}",
+                idx + 1,
+                label,
+                instruction
+            ));
+        }
+        let mut labels: Vec<(&String, &usize)> = self.labels.iter().collect();
+        labels.sort_by_key(|&(_, &idx)| idx);
+        let labels: Vec<String> = labels
+            .into_iter()
+            .map(|(name, idx)| format!("{}:{}", json::string(name), idx + 1))
+            .collect();
+        let mut tables: Vec<&Table> = self.lines.iter().filter_map(|l| l.table.as_ref()).collect();
+        tables.sort_by_key(|table| table.span.line);
+        let tables: Vec<String> = tables
+            .into_iter()
+            .map(|table| {
+                let values: Vec<String> = table.values.iter().map(i32::to_string).collect();
+                format!("{}:[{}]", json::string(&table.name), values.join(","))
+            })
+            .collect();
+        format!(
+            "{{\"file\":{},\"labels\":{{{}}},\"tables\":{{{}}},\"entry\":{},\"lines\":[{}]}}",
+            json::string(filename),
+            labels.join(","),
+            tables.join(","),
+            self.entry_label().map_or("null".to_string(), json::string),
+            lines.join(",")
+        )
+    }
+}
+
+// Split a line into tokens separated by whitespace and commas, keeping the
+// span of each token
+fn tokenize(text: &str, line: usize) -> Vec<(&str, Span)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut col = 0;
+    for (idx, ch) in text.char_indices() {
+        col += 1;
+        if ch.is_whitespace() || ch == ',' {
+            if let Some((begin, begin_col)) = start.take() {
+                tokens.push((&text[begin..idx], span(line, begin_col, &text[begin..idx])));
+            }
+        } else if start.is_none() {
+            start = Some((idx, col));
+        }
+    }
+    if let Some((begin, begin_col)) = start {
+        tokens.push((&text[begin..], span(line, begin_col, &text[begin..])));
+    }
+    tokens
+}
+
+fn span(line: usize, col: usize, token: &str) -> Span {
+    Span {
+        line,
+        col,
+        len: token.chars().count(),
+    }
+}
+
+pub const MNEMONICS: [&str; 17] = [
+    "mov", "swp", "save", "add", "jmp", "jez", "jnz", "jgz", "jlz", "ret", "reti", "dbg", "out",
+    "in", "outc", "slp", "lookup",
+];
+
+// Levenshtein distance between two strings, counted in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// The candidate closest to `name`, if it is close enough to be a likely typo
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(distance, _)| distance <= limit)
+        .min()
+        .map(|(_, c)| c)
+}
+
+fn parse_operand(token: &str, span: Span) -> Result<Operand, Diagnostic> {
+    match token.to_lowercase().as_str() {
+        "acc" => Ok(Operand::Acc),
+        "bak" => Ok(Operand::Bak),
+        "clk" => Ok(Operand::Clk),
+        lower => token.parse::<i32>().map(Operand::Imm).map_err(|_| {
+            let diagnostic =
+                Diagnostic::new("invalid-source", format!("Invalid source: {token}"), span);
+            if token.starts_with("${") {
+                return diagnostic.with_suggestion("load with --env-subst to substitute it");
+            }
+            match suggest(lower, ["acc", "bak", "clk"]) {
+                Some(register) => {
+                    diagnostic.with_suggestion(&format!("did you mean `{register}`?"))
+                }
+                None => diagnostic.with_suggestion("sources are acc, bak, clk or an integer"),
+            }
+        }),
+    }
+}
+
+fn parse_destination(token: &str, span: Span) -> Result<Operand, Diagnostic> {
+    match token.to_lowercase().as_str() {
+        "acc" => Ok(Operand::Acc),
+        "bak" => Err(Diagnostic::new(
+            "write-to-bak",
+            "Cannot write directly to bak".to_string(),
+            span,
+        )
+        .with_suggestion("use `save` or `swp` to change bak")),
+        "clk" => Err(
+            Diagnostic::new("write-to-clk", "clk is read-only".to_string(), span)
+                .with_suggestion("the only destination is acc"),
+        ),
+        _ => Err(Diagnostic::new(
+            "invalid-destination",
+            format!("Invalid destination: {token}"),
+            span,
+        )
+        .with_suggestion("the only destination is acc")),
+    }
+}
+
+// Span from the first token to the end of the last one
+fn whole_span(tokens: &[(&str, Span)]) -> Span {
+    let first = tokens[0].1;
+    let last = tokens[tokens.len() - 1].1;
+    Span {
+        len: last.col + last.len - first.col,
+        ..first
+    }
+}
+
+fn parse_instruction(tokens: &[(&str, Span)]) -> Result<Instruction, Diagnostic> {
+    let (mnemonic, mnemonic_span) = tokens[0];
+    let operands = &tokens[1..];
+    let mnemonic = mnemonic.to_lowercase();
+    let arity = match mnemonic.as_str() {
+        "swp" | "save" | "sav" | "ret" | "reti" | "dbg" => 0,
+        "add" | "out" | "outc" | "slp" | "in" | "lookup" | "jmp" | "jez" | "jnz" | "jgz"
+        | "jlz" => 1,
+        "mov" => 2,
+        _ => {
+            let diagnostic = Diagnostic::new(
+                "unknown-instruction",
+                format!("Unknown instruction: {}", tokens[0].0),
+                mnemonic_span,
+            );
+            return Err(match suggest(&mnemonic, MNEMONICS) {
+                Some(m) => diagnostic.with_suggestion(&format!("did you mean `{m}`?")),
+                None => diagnostic,
+            });
+        }
+    };
+    if operands.len() != arity {
+        let usage = match mnemonic.as_str() {
+            "mov" => "mov <src>, <dst>".to_string(),
+            "add" | "out" | "outc" | "slp" => format!("{mnemonic} <src>"),
+            "in" => "in <dst>".to_string(),
+            "lookup" => "lookup <table>".to_string(),
+            m if arity == 1 => format!("{m} <label>"),
+            m => m.to_string(),
+        };
+        return Err(Diagnostic::new(
+            "invalid-syntax",
+            format!(
+                "Invalid {} syntax: expected {} operand{}, found {}",
+                mnemonic,
+                arity,
+                if arity == 1 { "" } else { "s" },
+                operands.len()
+            ),
+            whole_span(tokens),
+        )
+        .with_suggestion(&format!("expected `{usage}`")));
+    }
+    let label = || operands[0].0.to_string();
+    let instruction = match mnemonic.as_str() {
+        "mov" => Instruction::Mov(
+            parse_operand(operands[0].0, operands[0].1)?,
+            parse_destination(operands[1].0, operands[1].1)?,
+        ),
+        "swp" => Instruction::Swp,
+        // `sav` is how the original game spells it
+        "save" | "sav" => Instruction::Save,
+        "add" => Instruction::Add(parse_operand(operands[0].0, operands[0].1)?),
+        "out" => Instruction::Out(parse_operand(operands[0].0, operands[0].1)?),
+        "outc" => Instruction::Outc(parse_operand(operands[0].0, operands[0].1)?),
+        "slp" => Instruction::Slp(parse_operand(operands[0].0, operands[0].1)?),
+        "in" => Instruction::In(parse_destination(operands[0].0, operands[0].1)?),
+        "jmp" => Instruction::Jmp(label()),
+        "jez" => Instruction::Jez(label()),
+        "jnz" => Instruction::Jnz(label()),
+        "jgz" => Instruction::Jgz(label()),
+        "jlz" => Instruction::Jlz(label()),
+        "lookup" => Instruction::Lookup(label()),
+        "ret" => Instruction::Ret,
+        "reti" => Instruction::Reti,
+        "dbg" => Instruction::Dbg,
+        _ => unreachable!("arity table covers every mnemonic"),
+    };
+    Ok(instruction)
+}
+
+const TABLE_USAGE: &str = "expected `.table <name>: <value>, ...`";
+
+// A `.table` line, split into tokens including the `.table` itself
+fn parse_table(tokens: &[(&str, Span)]) -> Result<Table, Diagnostic> {
+    let Some(&(name, name_span)) = tokens.get(1) else {
+        return Err(Diagnostic::new(
+            "invalid-syntax",
+            "Invalid .table syntax: missing name".to_string(),
+            tokens[0].1,
+        )
+        .with_suggestion(TABLE_USAGE));
+    };
+    let name = match name.strip_suffix(':') {
+        Some(name) if !name.is_empty() => name,
+        _ => {
+            return Err(Diagnostic::new(
+                "invalid-syntax",
+                format!("Invalid table name: {name}"),
+                name_span,
+            )
+            .with_suggestion(TABLE_USAGE));
+        }
+    };
+    let values = &tokens[2..];
+    if values.is_empty() {
+        return Err(Diagnostic::new(
+            "empty-table",
+            format!("Table {name} has no values"),
+            whole_span(tokens),
+        )
+        .with_suggestion(TABLE_USAGE));
+    }
+    let values = values
+        .iter()
+        .map(|&(token, span)| {
+            token.parse::<i32>().map_err(|_| {
+                Diagnostic::new(
+                    "invalid-table-value",
+                    format!("Invalid table value: {token}"),
+                    span,
+                )
+                .with_suggestion("table values are integers")
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Table {
+        name: name.to_string(),
+        span: Span {
+            len: name_span.len - 1,
+            ..name_span
+        },
+        values,
+    })
+}
+
+// Line index `idx` with each `${NAME}` replaced by the value of environment
+// variable NAME, which must be an integer, so parameters can be injected into
+// immediates and table values at load time. Comment lines are left alone.
+pub fn substitute_env(idx: usize, text: &str) -> Result<String, Diagnostic> {
+    if text.trim().starts_with('#') || !text.contains("${") {
+        return Ok(text.to_string());
+    }
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let col = text[..text.len() - rest.len() + start].chars().count() + 1;
+        let Some(len) = rest[start..].find('}') else {
+            return Err(Diagnostic::new(
+                "invalid-syntax",
+                "Unterminated ${".to_string(),
+                Span {
+                    line: idx + 1,
+                    col,
+                    len: 2,
+                },
+            )
+            .with_suggestion("expected `${NAME}`"));
+        };
+        let name = &rest[start + 2..start + len];
+        let span = Span {
+            line: idx + 1,
+            col,
+            len: name.chars().count() + 3,
+        };
+        let value = env::var(name).map_err(|_| {
+            Diagnostic::new(
+                "undefined-variable",
+                format!("Environment variable {name} is not set"),
+                span,
+            )
+        })?;
+        let value = value.trim();
+        if value.parse::<i32>().is_err() {
+            return Err(Diagnostic::new(
+                "invalid-variable",
+                format!("Environment variable {name} is {value:?}, not an integer"),
+                span,
+            ));
+        }
+        out.push_str(value);
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Programs with at least this many lines are parsed on several threads
+const PARALLEL_THRESHOLD: usize = 100_000;
+
+// Parse line index `idx`. Labels are checked against each other separately,
+// once every line is parsed.
+pub(crate) fn parse_line(idx: usize, text: &str) -> (Line, Vec<Diagnostic>) {
+    let mut line = Line::default();
+    let mut diagnostics = Vec::new();
+    if let Some(comment) = text.trim().strip_prefix('#') {
+        line.comment = Some(comment.to_string());
+        return (line, diagnostics);
+    }
+    let mut tokens = tokenize(text, idx + 1);
+    if tokens
+        .first()
+        .is_some_and(|(first, _)| first.eq_ignore_ascii_case(".table"))
+    {
+        match parse_table(&tokens) {
+            Ok(table) => line.table = Some(table),
+            Err(d) => diagnostics.push(d),
+        }
+        return (line, diagnostics);
+    }
+    if tokens
+        .first()
+        .is_some_and(|(first, _)| first.eq_ignore_ascii_case(".entry"))
+    {
+        match tokens[1..] {
+            [(label, span)] => line.entry = Some((label.to_string(), span)),
+            _ => diagnostics.push(
+                Diagnostic::new(
+                    "invalid-syntax",
+                    format!(
+                        "Invalid .entry syntax: expected 1 operand, found {}",
+                        tokens.len() - 1
+                    ),
+                    whole_span(&tokens),
+                )
+                .with_suggestion("expected `.entry <label>`"),
+            ),
+        }
+        return (line, diagnostics);
+    }
+    if let Some(&(first, first_span)) = tokens.first()
+        && let Some(name) = first.strip_suffix(':')
+    {
+        if name.is_empty() {
+            diagnostics.push(Diagnostic::new(
+                "empty-label",
+                "Empty label".to_string(),
+                first_span,
+            ));
+        }
+        let label_span = Span {
+            len: first_span.len - 1,
+            ..first_span
+        };
+        line.label = Some((name.to_string(), label_span));
+        tokens.remove(0);
+    }
+    if !tokens.is_empty() {
+        match parse_instruction(&tokens) {
+            Ok(instruction) => {
+                line.instruction = Some(instruction);
+                line.span = Some(whole_span(&tokens));
+                line.operands = tokens[1..].iter().map(|&(_, span)| span).collect();
+            }
+            Err(d) => diagnostics.push(d),
+        }
+    }
+    (line, diagnostics)
+}
+
+// Parse every line, splitting large programs into one chunk per thread
+fn parse_lines(source: &[&str]) -> Vec<(Line, Vec<Diagnostic>)> {
+    let threads = thread::available_parallelism().map_or(1, std::num::NonZero::get);
+    if source.len() < PARALLEL_THRESHOLD || threads == 1 {
+        return source
+            .iter()
+            .enumerate()
+            .map(|(idx, text)| parse_line(idx, text))
+            .collect();
+    }
+    let chunk_len = source.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let chunks: Vec<_> = source
+            .chunks(chunk_len)
+            .enumerate()
+            .map(|(chunk, lines)| {
+                scope.spawn(move || {
+                    let first = chunk * chunk_len;
+                    lines
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, text)| parse_line(first + idx, text))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        chunks
+            .into_iter()
+            .flat_map(|chunk| {
+                chunk
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+// Parse a whole program, returning every diagnostic if any line is invalid.
+// Lines are borrowed, so they can be slices of one buffer holding the file.
+pub fn parse<S: AsRef<str>>(source: &[S]) -> Result<Program, Vec<Diagnostic>> {
+    let source: Vec<&str> = source.iter().map(AsRef::as_ref).collect();
+    let mut program = Program::default();
+    let mut diagnostics = Vec::new();
+    for (idx, (line, line_diagnostics)) in parse_lines(&source).into_iter().enumerate() {
+        if let Some((name, label_span)) = &line.label
+            && !name.is_empty()
+        {
+            if let Some(&prev) = program.labels.get(name) {
+                diagnostics.push(Diagnostic::new(
+                    "duplicate-label",
+                    format!(
+                        "Duplicate label: {} (first defined on line {})",
+                        name,
+                        prev + 1
+                    ),
+                    *label_span,
+                ));
+            } else {
+                program.labels.insert(name.clone(), idx);
+            }
+        }
+        if let Some(table) = &line.table {
+            diagnostics.extend(program.declare_table(idx, table));
+        }
+        if let Some((_, span)) = &line.entry {
+            diagnostics.extend(program.declare_entry(idx, *span));
+        }
+        diagnostics.extend(line_diagnostics);
+        program.lines.push(line);
+    }
+    if diagnostics.is_empty() {
+        Ok(program)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_every_error_in_the_file() {
+        let source = ["mov 1, acc", "frob acc", "add", "l: swp", "l: swp"];
+        let diagnostics = parse(&source).unwrap_err();
+        let found: Vec<(&str, Option<Span>)> =
+            diagnostics.iter().map(|d| (d.code, d.span)).collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    "unknown-instruction",
+                    Some(Span {
+                        line: 2,
+                        col: 1,
+                        len: 4
+                    })
+                ),
+                (
+                    "invalid-syntax",
+                    Some(Span {
+                        line: 3,
+                        col: 1,
+                        len: 3
+                    })
+                ),
+                (
+                    "duplicate-label",
+                    Some(Span {
+                        line: 5,
+                        col: 1,
+                        len: 1
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_going_after_a_bad_line() {
+        let diagnostics = parse(&["jmp", "mov 1, acc", "jmp"]).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+        assert_eq!(diagnostics[1].span.map(|s| s.line), Some(3));
+    }
+
+    #[test]
+    fn renders_a_caret_under_the_span() {
+        let source = ["mov 1, acc", "frob acc"];
+        let diagnostics = parse(&source).unwrap_err();
+        let rendered = diagnostics[0].render("x.tis", &source);
+        assert!(rendered.contains(" --> x.tis:2:1"), "{}", rendered);
+        assert!(rendered.contains("2 | frob acc\n  | ^^^^"), "{}", rendered);
+    }
+
+    // Apply each edit to both the parsed program and the source, checking
+    // the program still equals a parse of the edited source from scratch
+    fn check_edits(edits: &[(usize, usize, &[&str])]) {
+        let mut source = vec![
+            "# counts down",
+            ".entry start",
+            ".table squares: 0, 1, 4",
+            "start: mov 3, acc",
+            "loop: add -1",
+            "jnz loop",
+            "end:",
+            "ret",
+        ];
+        let mut program = parse(&source).unwrap();
+        for &(start, end, lines) in edits {
+            assert!(program.apply_edit(start, end, lines).is_empty());
+            source.splice(start..end, lines.iter().copied());
+            assert_eq!(program, parse(&source).unwrap(), "after editing {source:?}");
+        }
+    }
+
+    #[test]
+    fn inserting_lines_matches_a_reparse() {
+        check_edits(&[
+            (0, 0, &["swp"]),
+            (5, 5, &["before: save", "mov 1, acc"]),
+            (7, 7, &["dbg"]),
+            (11, 11, &["after: ret"]),
+        ]);
+    }
+
+    #[test]
+    fn deleting_lines_matches_a_reparse() {
+        check_edits(&[(0, 1, &[]), (3, 4, &[]), (3, 4, &[]), (4, 5, &[])]);
+    }
+
+    #[test]
+    fn replacing_lines_matches_a_reparse() {
+        check_edits(&[
+            (3, 4, &["start: mov 5, acc"]),
+            (4, 6, &["loop: add -2", "jgz loop", "extra: swp"]),
+            (0, 3, &[".entry extra"]),
+            (4, 5, &["jmp loop"]),
+            (2, 3, &["renamed: add -2"]),
+        ]);
+    }
+
+    #[test]
+    fn parses_tables_and_lookups() {
+        let program = parse(&["mov 2, acc", "lookup squares", ".TABLE squares: 0, 1, -4"]).unwrap();
+        assert_eq!(program.table("squares"), Some(&[0, 1, -4][..]));
+        assert_eq!(program.table("cubes"), None);
+        assert_eq!(program.tables["squares"], 2);
+        assert_eq!(
+            program.lines[2].table.as_ref().unwrap().span,
+            Span {
+                line: 3,
+                col: 8,
+                len: 7
+            }
+        );
+        assert_eq!(
+            program.lines[1].instruction,
+            Some(Instruction::Lookup("squares".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        let cases: [(&[&str], &str); 6] = [
+            (&[".table"], "invalid-syntax"),
+            (&[".table squares 1, 2"], "invalid-syntax"),
+            (&[".table squares:"], "empty-table"),
+            (&[".table squares: 1, x"], "invalid-table-value"),
+            (&[".table t: 1", ".table t: 2"], "duplicate-table"),
+            (&["lookup"], "invalid-syntax"),
+        ];
+        for (source, code) in cases {
+            let diagnostics = parse(source).unwrap_err();
+            let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+            assert_eq!(codes, [code], "{source:?}");
+        }
+    }
+
+    #[test]
+    fn parses_the_entry() {
+        let program = parse(&["swp", ".ENTRY start", "start: save"]).unwrap();
+        assert_eq!(program.entry, Some(1));
+        assert_eq!(program.entry_label(), Some("start"));
+        assert_eq!(parse(&["start: save"]).unwrap().entry_label(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        let cases: [(&[&str], &str); 3] = [
+            (&[".entry"], "invalid-syntax"),
+            (&[".entry a b", "a: swp", "b: swp"], "invalid-syntax"),
+            (&[".entry a", "a: swp", ".entry a"], "duplicate-entry"),
+        ];
+        for (source, code) in cases {
+            let diagnostics = parse(source).unwrap_err();
+            let codes: Vec<&str> = diagnostics.iter().map(|d| d.code).collect();
+            assert_eq!(codes, [code], "{source:?}");
+        }
+    }
+
+    // Cargo sets the package's version numbers in the environment of the
+    // tests it runs, so these need no `env::set_var`
+    const MINOR: &str = "CARGO_PKG_VERSION_MINOR";
+    const NAME: &str = "CARGO_PKG_NAME";
+    const UNSET: &str = "TIS31337_TEST_UNSET";
+
+    #[test]
+    fn substitutes_environment_variables() {
+        let minor = env!("CARGO_PKG_VERSION_MINOR");
+        assert_eq!(
+            substitute_env(0, &format!("mov ${{{MINOR}}}, acc")).unwrap(),
+            format!("mov {minor}, acc")
+        );
+        assert_eq!(
+            substitute_env(0, &format!(".table t: ${{{MINOR}}}, 2, ${{{MINOR}}}")).unwrap(),
+            format!(".table t: {minor}, 2, {minor}")
+        );
+        for text in ["mov 1, acc", &format!("# ${{{UNSET}}}")] {
+            assert_eq!(substitute_env(0, text).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_substitutions() {
+        let error = |text: &str| {
+            let diagnostic = substitute_env(4, text).unwrap_err();
+            (
+                diagnostic.code,
+                diagnostic.message,
+                diagnostic.span.unwrap(),
+            )
+        };
+        assert_eq!(
+            error("mov ${X, acc"),
+            (
+                "invalid-syntax",
+                "Unterminated ${".to_string(),
+                Span {
+                    line: 5,
+                    col: 5,
+                    len: 2
+                }
+            )
+        );
+        assert_eq!(
+            error(&format!("add ${{{UNSET}}}")),
+            (
+                "undefined-variable",
+                format!("Environment variable {UNSET} is not set"),
+                Span {
+                    line: 5,
+                    col: 5,
+                    len: UNSET.len() + 3
+                }
+            )
+        );
+        assert_eq!(
+            error(&format!("add ${{{NAME}}}")).1,
+            format!("Environment variable {NAME} is \"tis31337\", not an integer")
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Register plots. `Plot` samples one register every cycle and, when the
+// program halts, writes the samples as an SVG line chart: cycles along the
+// x axis and the register's value up the y axis. Values only change when an
+// instruction finishes, so the samples are kept as the cycles where the value
+// changed and drawn as steps.
+
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::emulator::RuntimeError;
+use crate::observer::{Observer, Register};
+
+const WIDTH: f64 = 800.0;
+const HEIGHT: f64 = 300.0;
+// Room for the axis labels
+const MARGIN: f64 = 50.0;
+
+// Observer that plots `register` and writes the chart to `out` on halt
+pub struct Plot<W: Write + Send> {
+    out: W,
+    register: Register,
+    // (cycle, value) for each change of value, starting at cycle 0
+    samples: Vec<(u64, i32)>,
+    // A value written by the instruction executing, which holds from the
+    // cycle the next instruction starts at
+    pending: Option<i32>,
+}
+
+impl<W: Write + Send> Plot<W> {
+    // `initial` is the register's value before the run
+    pub fn new(register: Register, initial: i32, out: W) -> Self {
+        Plot {
+            out,
+            register,
+            samples: vec![(0, initial)],
+            pending: None,
+        }
+    }
+
+    fn settle(&mut self, cycle: u64) {
+        let Some(value) = self.pending.take() else {
+            return;
+        };
+        if self.samples.last().is_some_and(|&(_, last)| last == value) {
+            return;
+        }
+        match self.samples.last_mut() {
+            Some(last) if last.0 == cycle => last.1 = value,
+            _ => self.samples.push((cycle, value)),
+        }
+    }
+
+    // The chart of the samples up to `end` cycles
+    fn render(&self, end: u64) -> String {
+        let lo = self.samples.iter().map(|&(_, v)| v).min().unwrap_or(0);
+        let hi = self.samples.iter().map(|&(_, v)| v).max().unwrap_or(0);
+        // A constant register is drawn across the middle
+        let (lo, hi) = if lo == hi { (lo - 1, hi + 1) } else { (lo, hi) };
+        let end = end.max(1);
+        // Rounding cycle counts past 2^52 moves a point by far less than a
+        // pixel
+        #[allow(clippy::cast_precision_loss)]
+        let x = |cycle: u64| MARGIN + cycle as f64 / end as f64 * (WIDTH - 2.0 * MARGIN);
+        let y = |value: i32| {
+            HEIGHT - MARGIN - f64::from(value - lo) / f64::from(hi - lo) * (HEIGHT - 2.0 * MARGIN)
+        };
+        let mut points = Vec::new();
+        for (idx, &(cycle, value)) in self.samples.iter().enumerate() {
+            let until = self.samples.get(idx + 1).map_or(end, |&(next, _)| next);
+            points.push(format!("{:.1},{:.1}", x(cycle), y(value)));
+            points.push(format!("{:.1},{:.1}", x(until), y(value)));
+        }
+        let (left, right) = (MARGIN, WIDTH - MARGIN);
+        let (top, bottom) = (MARGIN, HEIGHT - MARGIN);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{HEIGHT}\" viewBox=\"0 0 {WIDTH} {HEIGHT}\" font-family=\"monospace\" font-size=\"12\">\n"
+        );
+        svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n");
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" font-size=\"14\">{}</text>",
+            WIDTH / 2.0,
+            MARGIN / 2.0,
+            self.register.name()
+        );
+        let _ = writeln!(
+            svg,
+            "<polyline points=\"{left},{top} {left},{bottom} {right},{bottom}\" fill=\"none\" stroke=\"black\"/>"
+        );
+        for (value, anchor) in [(hi, top), (lo, bottom)] {
+            let _ = writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"end\" dominant-baseline=\"middle\">{}</text>",
+                left - 5.0,
+                anchor,
+                value
+            );
+        }
+        for (cycle, anchor) in [(0, left), (end, right)] {
+            let _ = writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>",
+                anchor,
+                bottom + 15.0,
+                cycle
+            );
+        }
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">cycles</text>",
+            WIDTH / 2.0,
+            bottom + 30.0
+        );
+        let _ = writeln!(
+            svg,
+            "<polyline points=\"{}\" fill=\"none\" stroke=\"steelblue\" stroke-width=\"1.5\"/>",
+            points.join(" ")
+        );
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+impl<W: Write + Send> Observer for Plot<W> {
+    fn step(&mut self, _pc: usize, cycle: u64) {
+        self.settle(cycle);
+    }
+
+    fn register_write(&mut self, _pc: usize, register: Register, _old: i32, new: i32) {
+        if register == self.register {
+            self.pending = Some(new);
+        }
+    }
+
+    fn halt(&mut self, cycles: u64, _error: Option<&RuntimeError>) {
+        self.settle(cycles);
+        let svg = self.render(cycles);
+        if let Err(e) = self
+            .out
+            .write_all(svg.as_bytes())
+            .and_then(|()| self.out.flush())
+        {
+            eprintln!("Could not write plot: {e}");
+        }
+    }
+}
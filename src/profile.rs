@@ -0,0 +1,194 @@
+// Compatibility profiles. The default extended profile accepts everything
+// this emulator implements; `tis100` restricts programs to what the original
+// game accepts, so solutions checked here also run there. That includes
+// forbidding direct reads of `bak`, which can also be enabled on its own.
+
+use crate::parser::{Diagnostic, Instruction, Line, Operand, Program, Span};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    #[default]
+    Extended,
+    Tis100,
+}
+
+// Errors for each instruction in `program` that reads `bak` directly, which
+// the original machine only allows through `swp` and `save`
+#[must_use]
+pub fn bak_reads(program: &Program) -> Vec<Diagnostic> {
+    program
+        .lines
+        .iter()
+        .filter(|line| {
+            matches!(
+                line.instruction,
+                Some(
+                    Instruction::Mov(Operand::Bak, _)
+                        | Instruction::Add(Operand::Bak)
+                        | Instruction::Out(Operand::Bak)
+                        | Instruction::Outc(Operand::Bak)
+                        | Instruction::Slp(Operand::Bak)
+                )
+            )
+        })
+        .map(|line| {
+            Diagnostic::error(
+                "bak-read",
+                "Cannot read bak directly".to_string(),
+                line.operands.first().copied(),
+            )
+            .with_suggestion("use `swp` to bring bak into acc")
+        })
+        .collect()
+}
+
+// Limits of a TIS-100 node
+const TIS100_MAX_LINES: usize = 15;
+const TIS100_MAX_COLUMNS: usize = 18;
+
+impl Profile {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Extended => "extended",
+            Profile::Tis100 => "tis100",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Profile> {
+        [Profile::Extended, Profile::Tis100]
+            .into_iter()
+            .find(|profile| profile.name() == name)
+    }
+
+    // Errors for everything in `program`, parsed from `lines`, that the
+    // profile does not allow
+    pub fn check<S: AsRef<str>>(self, lines: &[S], program: &Program) -> Vec<Diagnostic> {
+        let mut errors = Vec::new();
+        if self == Profile::Extended {
+            return errors;
+        }
+        // The game does not count blank lines at the end
+        let used = lines
+            .iter()
+            .rposition(|l| !l.as_ref().trim().is_empty())
+            .map_or(0, |idx| idx + 1);
+        if used > TIS100_MAX_LINES {
+            errors.push(Diagnostic::error(
+                "profile",
+                format!("Program has {used} lines, tis100 allows {TIS100_MAX_LINES}"),
+                None,
+            ));
+        }
+        for (idx, (text, line)) in lines.iter().zip(&program.lines).enumerate() {
+            let columns = text.as_ref().chars().count();
+            if columns > TIS100_MAX_COLUMNS {
+                errors.push(Diagnostic::error(
+                    "profile",
+                    format!("Line is {columns} characters, tis100 allows {TIS100_MAX_COLUMNS}"),
+                    Some(Span {
+                        line: idx + 1,
+                        col: TIS100_MAX_COLUMNS + 1,
+                        len: columns - TIS100_MAX_COLUMNS,
+                    }),
+                ));
+            }
+            if let Some(table) = &line.table {
+                errors.push(Diagnostic::error(
+                    "profile",
+                    "tis100 has no tables".to_string(),
+                    Some(table.span),
+                ));
+            }
+            if let Some((_, span)) = &line.entry {
+                errors.push(Diagnostic::error(
+                    "profile",
+                    "tis100 programs start at their first line".to_string(),
+                    Some(*span),
+                ));
+            }
+            errors.extend(instruction_errors(text.as_ref(), line));
+        }
+        errors
+    }
+}
+
+// Errors for the instruction on `line`, whose source is `text`, that tis100
+// does not allow
+fn instruction_errors(text: &str, line: &Line) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    let (Some(instruction), Some(span)) = (&line.instruction, line.span) else {
+        return errors;
+    };
+    if matches!(
+        instruction,
+        Instruction::Ret
+            | Instruction::Reti
+            | Instruction::Out(_)
+            | Instruction::Outc(_)
+            | Instruction::Slp(_)
+            | Instruction::In(_)
+            | Instruction::Lookup(_)
+    ) {
+        errors.push(Diagnostic::error(
+            "profile",
+            format!("{} is not a tis100 instruction", instruction.mnemonic()),
+            Some(span),
+        ));
+    }
+    if let Instruction::Mov(Operand::Clk, _)
+    | Instruction::Add(Operand::Clk)
+    | Instruction::Out(Operand::Clk)
+    | Instruction::Outc(Operand::Clk)
+    | Instruction::Slp(Operand::Clk) = instruction
+    {
+        errors.push(Diagnostic::error(
+            "profile",
+            "clk is not a tis100 register".to_string(),
+            line.operands.first().copied(),
+        ));
+    }
+    let mnemonic: String = text
+        .chars()
+        .skip(span.col - 1)
+        .take_while(|c| !c.is_whitespace())
+        .collect();
+    if matches!(instruction, Instruction::Save) && mnemonic.to_lowercase() != "sav" {
+        errors.push(
+            Diagnostic::error(
+                "profile",
+                format!("{mnemonic} is not a tis100 instruction"),
+                Some(Span {
+                    len: mnemonic.chars().count(),
+                    ..span
+                }),
+            )
+            .with_suggestion("tis100 spells it `sav`"),
+        );
+    }
+    if matches!(instruction, Instruction::Dbg) {
+        errors.push(
+            Diagnostic::error(
+                "profile",
+                "dbg is not a tis100 instruction".to_string(),
+                Some(span),
+            )
+            .with_suggestion("remove it, or load with --strip-dbg"),
+        );
+    }
+    if let Instruction::Mov(Operand::Imm(value), _) | Instruction::Add(Operand::Imm(value)) =
+        instruction
+        && !(-999..=999).contains(value)
+    {
+        errors.push(
+            Diagnostic::error(
+                "profile",
+                format!("Immediate {value} is outside tis100's -999..999"),
+                line.operands.first().copied(),
+            )
+            .with_suggestion(&format!("use `{}`", (*value).clamp(-999, 999))),
+        );
+    }
+    errors
+}
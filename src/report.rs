@@ -0,0 +1,179 @@
+// Final machine state after a run, in formats for people and for tools
+
+use std::fmt::Write;
+
+use crate::emulator::{Emulator, HaltReason, RuntimeError};
+use crate::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+    Toml,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::Table, Format::Json, Format::Csv, Format::Toml];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Table => "table",
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Toml => "toml",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Format> {
+        Format::ALL.into_iter().find(|format| format.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub acc: i32,
+    pub bak: i32,
+    pub cycles: u64,
+    pub instructions: u64,
+    // The energy score, if a cost model was set
+    pub energy: Option<f64>,
+    // Why the run stopped, or `None` if it is still running
+    pub halt_reason: Option<HaltReason>,
+    // The runtime error that stopped the program, if it did not halt normally
+    pub error: Option<RuntimeError>,
+}
+
+impl Report {
+    #[must_use]
+    pub fn new(emu: &Emulator) -> Self {
+        let halt_reason = emu.halt_reason();
+        let error = match &halt_reason {
+            Some(HaltReason::Error(error)) => Some(error.clone()),
+            _ => None,
+        };
+        Report {
+            acc: emu.acc,
+            bak: emu.bak,
+            cycles: emu.cycles,
+            instructions: emu.instructions,
+            energy: emu.cost_model.as_ref().map(|_| emu.energy),
+            halt_reason,
+            error,
+        }
+    }
+
+    // The name of the halt reason
+    pub fn termination(&self) -> &'static str {
+        self.halt_reason
+            .as_ref()
+            .map_or("running", HaltReason::name)
+    }
+
+    #[must_use]
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Table => self.table(),
+            Format::Json => self.json(),
+            Format::Csv => self.csv(),
+            Format::Toml => self.toml(),
+        }
+    }
+
+    fn table(&self) -> String {
+        let mut out = format!(
+            "{:<14}{}\n{:<14}{}\n{:<14}{}\n{:<14}{}\n{:<14}{}\n",
+            "acc",
+            self.acc,
+            "bak",
+            self.bak,
+            "cycles",
+            self.cycles,
+            "instructions",
+            self.instructions,
+            "termination",
+            self.termination()
+        );
+        if let Some(energy) = self.energy {
+            let _ = writeln!(out, "{:<14}{}", "energy", energy);
+        }
+        if let Some(e) = &self.error {
+            let _ = writeln!(
+                out,
+                "{:<14}{}\n{:<14}{}",
+                "error line", e.line, "error", e.message
+            );
+        }
+        out
+    }
+
+    fn json(&self) -> String {
+        let error = match &self.error {
+            Some(e) => format!(
+                "{{\"line\":{},\"message\":{}}}",
+                e.line,
+                json::string(&e.message)
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"acc\":{},\"bak\":{},\"cycles\":{},\"instructions\":{},\"energy\":{},\"termination\":{},\"error\":{}}}\n",
+            self.acc,
+            self.bak,
+            self.cycles,
+            self.instructions,
+            self.energy.map_or("null".to_string(), |e| e.to_string()),
+            json::string(self.termination()),
+            error
+        )
+    }
+
+    // One header row and one value row; the energy column is empty without a
+    // cost model, and the error columns when the program halted normally
+    fn csv(&self) -> String {
+        let (line, message) = match &self.error {
+            Some(e) => (
+                e.line.to_string(),
+                format!("\"{}\"", e.message.replace('"', "\"\"")),
+            ),
+            None => (String::new(), String::new()),
+        };
+        format!(
+            "acc,bak,cycles,instructions,energy,termination,error_line,error_message\n{},{},{},{},{},{},{},{}\n",
+            self.acc,
+            self.bak,
+            self.cycles,
+            self.instructions,
+            self.energy.map_or(String::new(), |e| e.to_string()),
+            self.termination(),
+            line,
+            message
+        )
+    }
+
+    fn toml(&self) -> String {
+        let mut out = format!(
+            "acc = {}\nbak = {}\ncycles = {}\ninstructions = {}\ntermination = {}\n",
+            self.acc,
+            self.bak,
+            self.cycles,
+            self.instructions,
+            json::string(self.termination())
+        );
+        if let Some(energy) = self.energy {
+            let _ = writeln!(out, "energy = {energy:?}");
+        }
+        // TOML basic strings use the same escapes as JSON strings
+        if let Some(e) = &self.error {
+            let _ = writeln!(
+                out,
+                "\n[error]\nline = {}\nmessage = {}",
+                e.line,
+                json::string(&e.message)
+            );
+        }
+        out
+    }
+}
@@ -0,0 +1,179 @@
+// A handle for running an emulator on one thread while other threads pause,
+// inspect and resume it
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::emulator::{Emulator, RunOutcome, RuntimeError};
+
+// Cycles run between checks for pause and cancel requests. The emulator is
+// locked for the duration of each slice.
+const SLICE: u64 = 1024;
+
+#[derive(Debug, Default)]
+struct Control {
+    paused: bool,
+    cancelled: bool,
+}
+
+#[derive(Debug)]
+struct Inner {
+    emu: Mutex<Emulator>,
+    control: Mutex<Control>,
+    changed: Condvar,
+}
+
+// Cloning gives another handle to the same emulator. One thread calls
+// `run`; any other thread can call the remaining methods at any time.
+#[derive(Debug, Clone)]
+pub struct SharedEmulator {
+    inner: Arc<Inner>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl SharedEmulator {
+    #[must_use]
+    pub fn new(emu: Emulator) -> Self {
+        SharedEmulator {
+            inner: Arc::new(Inner {
+                emu: Mutex::new(emu),
+                control: Mutex::default(),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    // Run until the program halts or `cancel` is called. While paused this
+    // blocks without holding the emulator lock.
+    pub fn run(&self) -> Result<RunOutcome, RuntimeError> {
+        loop {
+            {
+                let mut control = lock(&self.inner.control);
+                while control.paused && !control.cancelled {
+                    control = self
+                        .inner
+                        .changed
+                        .wait(control)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                if control.cancelled {
+                    return Ok(RunOutcome::Cancelled);
+                }
+            }
+            match lock(&self.inner.emu).run_for(SLICE)? {
+                RunOutcome::BudgetExhausted => {}
+                outcome => return Ok(outcome),
+            }
+        }
+    }
+
+    // Stop `run` at the end of the current slice until `resume` is called
+    pub fn pause(&self) {
+        lock(&self.inner.control).paused = true;
+    }
+
+    pub fn resume(&self) {
+        lock(&self.inner.control).paused = false;
+        self.inner.changed.notify_all();
+    }
+
+    // Make `run` return `RunOutcome::Cancelled`, even if it is paused
+    pub fn cancel(&self) {
+        lock(&self.inner.control).cancelled = true;
+        self.inner.changed.notify_all();
+    }
+
+    #[must_use]
+    pub fn is_paused(&self) -> bool {
+        lock(&self.inner.control).paused
+    }
+
+    // Look at the emulator between slices. Pause first for a view that stays
+    // consistent across several calls.
+    pub fn inspect<R>(&self, f: impl FnOnce(&Emulator) -> R) -> R {
+        f(&lock(&self.inner.emu))
+    }
+
+    // Modify the emulator between slices, e.g. to patch registers while
+    // paused
+    pub fn with<R>(&self, f: impl FnOnce(&mut Emulator) -> R) -> R {
+        f(&mut lock(&self.inner.emu))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::observer::Register;
+
+    fn shared(source: &[&str]) -> SharedEmulator {
+        let mut emu = Emulator::new();
+        emu.load_program(source).unwrap();
+        SharedEmulator::new(emu)
+    }
+
+    // Wait until `f` holds of the emulator, failing after a few seconds
+    fn wait_for(handle: &SharedEmulator, f: impl Fn(&Emulator) -> bool) {
+        let start = Instant::now();
+        while !handle.inspect(&f) {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn other_threads_pause_patch_and_cancel_a_run() {
+        let handle = shared(&["l: jmp l"]);
+        handle.pause();
+        let runner = {
+            let handle = handle.clone();
+            thread::spawn(move || handle.run())
+        };
+        // Paused before it started, so nothing has run
+        thread::sleep(Duration::from_millis(20));
+        assert!(handle.is_paused());
+        assert_eq!(handle.inspect(|emu| emu.cycles), 0);
+        handle.with(|emu| emu.set_register(Register::Bak, 7));
+
+        handle.resume();
+        assert!(!handle.is_paused());
+        wait_for(&handle, |emu| emu.cycles >= 4 * SLICE);
+        handle.cancel();
+        assert_eq!(runner.join().unwrap().unwrap(), RunOutcome::Cancelled);
+        let (cycles, bak) = handle.inspect(|emu| (emu.cycles, emu.bak));
+        assert_eq!(cycles % SLICE, 0);
+        assert_eq!(bak, 7);
+    }
+
+    #[test]
+    fn cancel_ends_a_paused_run() {
+        let handle = shared(&["l: jmp l"]);
+        handle.pause();
+        let runner = {
+            let handle = handle.clone();
+            thread::spawn(move || handle.run())
+        };
+        thread::sleep(Duration::from_millis(10));
+        handle.cancel();
+        assert_eq!(runner.join().unwrap().unwrap(), RunOutcome::Cancelled);
+        assert_eq!(handle.inspect(|emu| emu.cycles), 0);
+    }
+
+    #[test]
+    fn a_run_ends_when_the_program_halts() {
+        let handle = shared(&["mov 999, acc", "l: add -1", "jnz l"]);
+        let runner = {
+            let handle = handle.clone();
+            thread::spawn(move || handle.run())
+        };
+        assert_eq!(runner.join().unwrap().unwrap(), RunOutcome::Halted);
+        assert_eq!(handle.inspect(|emu| (emu.acc, emu.cycles)), (0, 1999));
+        let error = shared(&["jmp nowhere"]).run().unwrap_err();
+        assert_eq!(error.line, 1);
+    }
+}
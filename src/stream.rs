@@ -0,0 +1,327 @@
+// Sources and sinks of values for programs that read input or write output.
+// Implementations cover in-memory collections, files, channels and closures,
+// so callers can inject data and capture results without touching the
+// filesystem.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender};
+
+pub trait InputSource {
+    // The next value, or `None` once the input is exhausted. An error is
+    // input that could not be read, not the end of it.
+    fn read(&mut self) -> io::Result<Option<i32>>;
+}
+
+pub trait OutputSink {
+    fn write(&mut self, value: i32) -> io::Result<()>;
+}
+
+impl InputSource for VecDeque<i32> {
+    fn read(&mut self) -> io::Result<Option<i32>> {
+        Ok(self.pop_front())
+    }
+}
+
+// Blocks until a value arrives; ends when every sender is dropped
+impl InputSource for Receiver<i32> {
+    fn read(&mut self) -> io::Result<Option<i32>> {
+        Ok(self.recv().ok())
+    }
+}
+
+// Input from a closure, called once per value
+pub struct FnSource<F>(pub F);
+
+impl<F: FnMut() -> Option<i32>> InputSource for FnSource<F> {
+    fn read(&mut self) -> io::Result<Option<i32>> {
+        Ok((self.0)())
+    }
+}
+
+// Integers separated by whitespace or commas, read lazily from a reader. A
+// token that is not an integer is an error giving its line and column.
+pub struct ReaderSource<R> {
+    reader: R,
+    // Tokens of the current line not read yet, with their 1-based columns
+    pending: VecDeque<(String, usize)>,
+    // 1-based number of the line the pending tokens came from
+    line: usize,
+}
+
+impl<R: BufRead> ReaderSource<R> {
+    pub fn new(reader: R) -> Self {
+        ReaderSource {
+            reader,
+            pending: VecDeque::new(),
+            line: 0,
+        }
+    }
+}
+
+impl ReaderSource<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(ReaderSource::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> InputSource for ReaderSource<R> {
+    fn read(&mut self) -> io::Result<Option<i32>> {
+        while self.pending.is_empty() {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            let mut col = 1;
+            for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+                if !token.is_empty() {
+                    self.pending.push_back((token.to_string(), col));
+                }
+                col += token.chars().count() + 1;
+            }
+        }
+        let Some((token, col)) = self.pending.pop_front() else {
+            return Ok(None);
+        };
+        token.parse().map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "`{}` on line {}, column {} of the input is not an integer",
+                    token, self.line, col
+                ),
+            )
+        })
+    }
+}
+
+// Values typed in answer to a prompt: `prompt` is written to `terminal`
+// before each line is read, and a line holds one or more integers like the
+// input of `ReaderSource`. A line that is not all integers is reported and
+// asked for again instead of failing the run; end of file ends the input.
+pub struct PromptSource<R, W> {
+    reader: R,
+    terminal: W,
+    prompt: String,
+    pending: VecDeque<i32>,
+}
+
+impl<R: BufRead, W: Write> PromptSource<R, W> {
+    pub fn new(reader: R, terminal: W, prompt: &str) -> Self {
+        PromptSource {
+            reader,
+            terminal,
+            prompt: prompt.to_string(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> InputSource for PromptSource<R, W> {
+    fn read(&mut self) -> io::Result<Option<i32>> {
+        while self.pending.is_empty() {
+            write!(self.terminal, "{}", self.prompt)?;
+            self.terminal.flush()?;
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                writeln!(self.terminal)?;
+                return Ok(None);
+            }
+            let values: Result<Vec<i32>, &str> = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|token| !token.is_empty())
+                .map(|token| token.parse().map_err(|_| token))
+                .collect();
+            match values {
+                Ok(values) => self.pending.extend(values),
+                Err(token) => writeln!(self.terminal, "`{token}` is not an integer")?,
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+}
+
+impl OutputSink for Vec<i32> {
+    fn write(&mut self, value: i32) -> io::Result<()> {
+        self.push(value);
+        Ok(())
+    }
+}
+
+impl OutputSink for Sender<i32> {
+    fn write(&mut self, value: i32) -> io::Result<()> {
+        self.send(value)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "output receiver dropped"))
+    }
+}
+
+// Output to a closure, called once per value
+pub struct FnSink<F>(pub F);
+
+impl<F: FnMut(i32)> OutputSink for FnSink<F> {
+    fn write(&mut self, value: i32) -> io::Result<()> {
+        (self.0)(value);
+        Ok(())
+    }
+}
+
+// Where a machine's input comes from, if anywhere
+#[derive(Default)]
+pub(crate) struct Input {
+    pub(crate) source: Option<Box<dyn InputSource + Send>>,
+}
+
+impl Input {
+    pub(crate) fn read(&mut self) -> io::Result<Option<i32>> {
+        match &mut self.source {
+            Some(source) => source.read(),
+            None => Ok(None),
+        }
+    }
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Some(_) => write!(f, "Input(source)"),
+            None => write!(f, "Input(none)"),
+        }
+    }
+}
+
+// How `outc` turns a value into bytes of text
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    // The value is a Unicode code point, written as UTF-8
+    #[default]
+    Utf8,
+    // One byte, 0..=127
+    Ascii,
+    // One byte, 0..=255
+    Latin1,
+}
+
+impl Encoding {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf8",
+            Encoding::Ascii => "ascii",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        [Encoding::Utf8, Encoding::Ascii, Encoding::Latin1]
+            .into_iter()
+            .find(|e| e.name() == name)
+    }
+
+    // The bytes for `value`, or `None` if it is not a character in this
+    // encoding
+    pub fn encode(self, value: i32) -> Option<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => {
+                let c = char::from_u32(u32::try_from(value).ok()?)?;
+                Some(c.to_string().into_bytes())
+            }
+            Encoding::Ascii => Some(vec![u8::try_from(value).ok().filter(u8::is_ascii)?]),
+            Encoding::Latin1 => Some(vec![u8::try_from(value).ok()?]),
+        }
+    }
+}
+
+// Where a machine's output goes: the attached sinks, or else buffers. Values
+// from `out` and text from `outc` are kept apart.
+#[derive(Default)]
+pub(crate) struct Output {
+    pub(crate) sink: Option<Box<dyn OutputSink + Send>>,
+    pub(crate) buffer: Vec<i32>,
+    pub(crate) text_sink: Option<Box<dyn Write + Send>>,
+    pub(crate) text: Vec<u8>,
+    // Values and characters written so far, for `Limits::max_output`
+    pub(crate) written: usize,
+}
+
+impl Output {
+    pub(crate) fn write(&mut self, value: i32) -> io::Result<()> {
+        self.written += 1;
+        if let Some(sink) = &mut self.sink {
+            sink.write(value)
+        } else {
+            self.buffer.push(value);
+            Ok(())
+        }
+    }
+
+    pub(crate) fn write_text(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.written += 1;
+        if let Some(sink) = &mut self.text_sink {
+            sink.write_all(bytes).and_then(|()| sink.flush())
+        } else {
+            self.text.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.sink {
+            Some(_) => write!(f, "Output(sink)"),
+            None => write!(f, "Output({:?})", self.buffer),
+        }
+    }
+}
+
+// One value per line to a writer
+pub struct WriterSink<W: Write>(pub W);
+
+impl WriterSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(WriterSink(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> OutputSink for WriterSink<W> {
+    fn write(&mut self, value: i32) -> io::Result<()> {
+        writeln!(self.0, "{value}")
+    }
+}
+
+// Both directions of a Unix socket connection, so another process can stand
+// in for a neighbor: it sends the values for `in` and receives each `out`
+// value on its own line as soon as it is written
+#[cfg(unix)]
+pub fn connect(
+    path: impl AsRef<Path>,
+) -> io::Result<(ReaderSource<BufReader<UnixStream>>, WriterSink<UnixStream>)> {
+    let stream = UnixStream::connect(path)?;
+    let reader = ReaderSource::new(BufReader::new(stream.try_clone()?));
+    Ok((reader, WriterSink(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompts_until_a_line_of_integers() {
+        let typed = io::Cursor::new("abc\n\n1, 2\n3\n");
+        let mut terminal = Vec::new();
+        let mut source = PromptSource::new(typed, &mut terminal, "input> ");
+        let values: Vec<Option<i32>> = (0..4).map(|_| source.read().unwrap()).collect();
+        assert_eq!(values, [Some(1), Some(2), Some(3), None]);
+        assert_eq!(
+            String::from_utf8(terminal).unwrap(),
+            "input> `abc` is not an integer\ninput> input> input> input> \n"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// Input taint tracking: run a program on an input stream, marking each
+// register with the input indices its value was computed from, and report
+// which of those reach each value written by `out` and `outc` and each
+// conditional jump. Only data flow is tracked: a value written under a
+// branch that depends on an input is not marked with that input, which the
+// branch report shows instead.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::emulator::{Emulator, InputEnd};
+use crate::parser::{Diagnostic, Instruction, Operand};
+
+// Input indices, in order
+pub type Inputs = BTreeSet<usize>;
+
+// A value written by `out` or `outc`, with the inputs it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Write {
+    // The 1-based line of the instruction that wrote it
+    pub line: usize,
+    pub value: i32,
+    pub inputs: Inputs,
+}
+
+// Every evaluation of one conditional jump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Branch {
+    pub evaluated: usize,
+    pub taken: usize,
+    // The inputs acc was computed from, over all the evaluations
+    pub inputs: Inputs,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub outputs: Vec<Write>,
+    pub text: Vec<Write>,
+    // Conditional jumps by 1-based line
+    pub branches: BTreeMap<usize, Branch>,
+    // How the run ended: `None` if it halted, else why it was cut short
+    pub stopped: Option<String>,
+}
+
+#[derive(Default)]
+struct Registers {
+    acc: Inputs,
+    bak: Inputs,
+}
+
+impl Registers {
+    fn of(&self, operand: Operand) -> Inputs {
+        match operand {
+            Operand::Acc => self.acc.clone(),
+            Operand::Bak => self.bak.clone(),
+            Operand::Imm(_) | Operand::Clk => Inputs::new(),
+        }
+    }
+}
+
+// Run `lines` on `inputs` for at most `budget` cycles, tracking where every
+// value came from. Reading past the end of the inputs ends the run.
+pub fn track<S: AsRef<str>>(
+    lines: &[S],
+    inputs: &[i32],
+    budget: u64,
+) -> Result<Report, Vec<Diagnostic>> {
+    let mut emu = Emulator::new();
+    emu.load_program(lines)?;
+    emu.on_input_end = InputEnd::Halt;
+    emu.set_input(inputs.iter().copied().collect::<VecDeque<i32>>());
+    let mut report = Report::default();
+    let mut taint = Registers::default();
+    let mut read = 0;
+    // The cycle count before the current instruction, for the value of clk
+    let mut before = 0;
+    for state in emu.iter_states() {
+        let state = match state {
+            Ok(state) => state,
+            Err(e) => {
+                report.stopped = Some(e.to_string());
+                return Ok(report);
+            }
+        };
+        let value = |operand| match operand {
+            Operand::Acc => state.acc,
+            Operand::Bak => state.bak,
+            Operand::Clk => i32::try_from(before % 1000).unwrap_or_default(),
+            Operand::Imm(value) => value.clamp(-999, 999),
+        };
+        let line = state.pc + 1;
+        match &state.instruction {
+            Instruction::Mov(src, _) => taint.acc = taint.of(*src),
+            Instruction::Add(src) => taint.acc.extend(taint.of(*src)),
+            Instruction::Swp => std::mem::swap(&mut taint.acc, &mut taint.bak),
+            Instruction::Save => taint.bak = taint.acc.clone(),
+            // An `in` past the end of the input ends the run, so whatever it
+            // marks acc with is never used
+            Instruction::In(_) => {
+                taint.acc = Inputs::from([read]);
+                read += 1;
+            }
+            Instruction::Out(src) => report.outputs.push(Write {
+                line,
+                value: value(*src),
+                inputs: taint.of(*src),
+            }),
+            Instruction::Outc(src) => report.text.push(Write {
+                line,
+                value: value(*src),
+                inputs: taint.of(*src),
+            }),
+            Instruction::Jez(_)
+            | Instruction::Jnz(_)
+            | Instruction::Jgz(_)
+            | Instruction::Jlz(_) => {
+                let taken = match &state.instruction {
+                    Instruction::Jez(_) => state.acc == 0,
+                    Instruction::Jnz(_) => state.acc != 0,
+                    Instruction::Jgz(_) => state.acc > 0,
+                    _ => state.acc < 0,
+                };
+                let branch = report.branches.entry(line).or_insert(Branch {
+                    evaluated: 0,
+                    taken: 0,
+                    inputs: Inputs::new(),
+                });
+                branch.evaluated += 1;
+                branch.taken += usize::from(taken);
+                branch.inputs.extend(taint.acc.iter().copied());
+            }
+            // A table value depends on the index in acc, so a lookup keeps
+            // its inputs
+            Instruction::Lookup(_)
+            | Instruction::Jmp(_)
+            | Instruction::Ret
+            | Instruction::Reti
+            | Instruction::Dbg
+            | Instruction::Slp(_) => {}
+        }
+        before = state.cycle;
+        if state.cycle >= budget {
+            report.stopped = Some(format!("still running after {budget} cycles"));
+            return Ok(report);
+        }
+    }
+    Ok(report)
+}
+
+// `inputs` as `x0, x2`, or `-` if there are none
+#[must_use]
+pub fn names(inputs: &Inputs) -> String {
+    if inputs.is_empty() {
+        return "-".to_string();
+    }
+    inputs
+        .iter()
+        .map(|idx| format!("x{idx}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(indices: &[usize]) -> Inputs {
+        indices.iter().copied().collect()
+    }
+
+    #[test]
+    fn follows_inputs_through_registers() {
+        let program = [
+            "in acc",
+            "save",
+            "in acc",
+            "swp",
+            "add bak",
+            "out acc",
+            "out bak",
+            "mov 7, acc",
+            "out acc",
+        ];
+        let report = track(&program, &[2, 3], 100).unwrap();
+        let outputs: Vec<(i32, Inputs)> = report
+            .outputs
+            .iter()
+            .map(|w| (w.value, w.inputs.clone()))
+            .collect();
+        assert_eq!(
+            outputs,
+            [(5, inputs(&[0, 1])), (3, inputs(&[1])), (7, inputs(&[]))]
+        );
+        assert_eq!(report.stopped, None);
+    }
+
+    #[test]
+    fn reports_branches_by_line() {
+        let program = ["top: in acc", "jgz top", "outc 65"];
+        let report = track(&program, &[1, 2, -1], 100).unwrap();
+        assert_eq!(
+            report.branches[&2],
+            Branch {
+                evaluated: 3,
+                taken: 2,
+                inputs: inputs(&[0, 1, 2]),
+            }
+        );
+        assert_eq!(report.text[0].inputs, inputs(&[]));
+        assert_eq!(names(&report.branches[&2].inputs), "x0, x1, x2");
+        assert_eq!(names(&Inputs::new()), "-");
+    }
+
+    #[test]
+    fn reports_why_a_run_was_cut_short() {
+        let report = track(&["top: jmp top"], &[], 10).unwrap();
+        assert_eq!(
+            report.stopped.as_deref(),
+            Some("still running after 10 cycles")
+        );
+        let report = track(&["jmp nowhere"], &[], 10).unwrap();
+        assert!(report.stopped.unwrap().contains("nowhere"));
+        assert!(track(&["nop"], &[], 10).is_err());
+    }
+}
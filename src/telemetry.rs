@@ -0,0 +1,244 @@
+// Traces of the requests a daemon handles, written as OpenTelemetry spans.
+// Each trace is one line of OTLP/JSON, the format of the collector's
+// `otlpjsonfile` receiver, so the file can be shipped to any OTLP backend
+// without this crate depending on an exporter. A request has a root span
+// named after its command, with child spans for the phases it went through:
+// `parse`, `validate` and `run`. Counts and error codes are attributes.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache;
+use crate::json;
+
+// Span kinds and status codes, as numbered by OTLP
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Str(String),
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Value {
+        Value::Int(i64::try_from(value).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Int(i64::try_from(value).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::Str(value.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub name: String,
+    id: u64,
+    start: u64,
+    end: Option<u64>,
+    pub attributes: Vec<(String, Value)>,
+    // Set once the span has finished, with the error message if it failed
+    pub status: Option<Result<(), String>>,
+}
+
+impl Span {
+    fn new(name: &str) -> Span {
+        Span {
+            name: name.to_string(),
+            id: new_id(),
+            start: now(),
+            end: None,
+            attributes: Vec::new(),
+            status: None,
+        }
+    }
+
+    pub fn attribute(&mut self, key: &str, value: impl Into<Value>) {
+        self.attributes.push((key.to_string(), value.into()));
+    }
+
+    // End the span with the outcome of its phase
+    pub fn end<T>(&mut self, result: &Result<T, String>) {
+        self.end = Some(now());
+        self.status = Some(result.as_ref().map(|_| ()).map_err(Clone::clone));
+    }
+}
+
+pub struct Trace {
+    id: u128,
+    pub root: Span,
+    pub children: Vec<Span>,
+}
+
+impl Trace {
+    // Start a trace whose root span is `name`
+    #[must_use]
+    pub fn new(name: &str) -> Trace {
+        Trace {
+            id: u128::from(new_id()) << 64 | u128::from(new_id()),
+            root: Span::new(name),
+            children: Vec::new(),
+        }
+    }
+
+    // Start a phase of the request
+    pub fn child(&mut self, name: &str) -> &mut Span {
+        let idx = self.children.len();
+        self.children.push(Span::new(name));
+        &mut self.children[idx]
+    }
+
+    // The trace as one line of OTLP/JSON, ending the root span if it has not
+    // ended yet
+    pub fn to_json(&self) -> String {
+        let end = self.root.end.unwrap_or_else(now);
+        let mut spans = vec![self.span_json(&self.root, None, KIND_SERVER, end)];
+        for child in &self.children {
+            spans.push(self.span_json(child, Some(self.root.id), KIND_INTERNAL, end));
+        }
+        format!(
+            "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"tis31337\",\"version\":\"{}\"}},\"spans\":[{}]}}]}}]}}",
+            attribute_json("service.name", &Value::from("tis31337")),
+            env!("CARGO_PKG_VERSION"),
+            spans.join(",")
+        )
+    }
+
+    fn span_json(&self, span: &Span, parent: Option<u64>, kind: u8, end: u64) -> String {
+        let parent = parent.map_or(String::new(), |id| {
+            format!(",\"parentSpanId\":\"{id:016x}\"")
+        });
+        let status = match &span.status {
+            Some(Ok(())) => format!("{{\"code\":{STATUS_OK}}}"),
+            Some(Err(message)) => format!(
+                "{{\"code\":{},\"message\":{}}}",
+                STATUS_ERROR,
+                json::string(message)
+            ),
+            None => "{}".to_string(),
+        };
+        let attributes: Vec<String> = span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute_json(key, value))
+            .collect();
+        format!(
+            "{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\"{},\"name\":{},\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}],\"status\":{}}}",
+            self.id,
+            span.id,
+            parent,
+            json::string(&span.name),
+            kind,
+            span.start,
+            span.end.unwrap_or(end),
+            attributes.join(","),
+            status
+        )
+    }
+}
+
+fn attribute_json(key: &str, value: &Value) -> String {
+    let value = match value {
+        // OTLP/JSON writes 64-bit integers as strings
+        Value::Int(n) => format!("{{\"intValue\":\"{n}\"}}"),
+        Value::Str(s) => format!("{{\"stringValue\":{}}}", json::string(s)),
+    };
+    format!("{{\"key\":{},\"value\":{}}}", json::string(key), value)
+}
+
+// Nanoseconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_nanos()).unwrap_or(u64::MAX))
+}
+
+// A new nonzero id. Ids only need to be unique, not unpredictable, so they
+// hash the process, the time and a counter rather than needing a random
+// number generator.
+fn new_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut seed = Vec::with_capacity(20);
+    seed.extend_from_slice(&process::id().to_le_bytes());
+    seed.extend_from_slice(&now().to_le_bytes());
+    seed.extend_from_slice(&count.to_le_bytes());
+    cache::hash(&seed).max(1)
+}
+
+// A file that traces are appended to, one per line
+pub struct SpanLog(File);
+
+impl SpanLog {
+    pub fn open(path: &Path) -> io::Result<SpanLog> {
+        Ok(SpanLog(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+
+    pub fn export(&mut self, trace: &Trace) -> io::Result<()> {
+        writeln!(self.0, "{}", trace.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_trace_as_otlp_json() {
+        let mut trace = Trace::new("load");
+        trace.root.attribute("tis.session", "a");
+        let span = trace.child("parse");
+        span.attribute("tis.lines", 3_usize);
+        span.end(&Err::<(), _>("Unknown instruction: foo".to_string()));
+        let root = format!("{:016x}", trace.root.id);
+        let json = trace.to_json();
+        assert!(json.starts_with(
+            "{\"resourceSpans\":[{\"resource\":{\"attributes\":[{\"key\":\"service.name\",\"value\":{\"stringValue\":\"tis31337\"}}]}"
+        ));
+        assert_eq!(
+            json.matches(&format!("\"traceId\":\"{:032x}\"", trace.id))
+                .count(),
+            2
+        );
+        assert_eq!(
+            json.matches(&format!("\"parentSpanId\":\"{root}\""))
+                .count(),
+            1
+        );
+        assert!(json.contains("\"name\":\"load\",\"kind\":2,"));
+        assert!(json.contains(
+            "\"attributes\":[{\"key\":\"tis.session\",\"value\":{\"stringValue\":\"a\"}}],\"status\":{}}"
+        ));
+        assert!(json.contains(
+            "\"attributes\":[{\"key\":\"tis.lines\",\"value\":{\"intValue\":\"3\"}}],\"status\":{\"code\":2,\"message\":\"Unknown instruction: foo\"}}"
+        ));
+        assert!(!json.contains('\n'));
+    }
+
+    #[test]
+    fn ids_are_unique_and_nonzero() {
+        let ids: Vec<u64> = (0..100).map(|_| new_id()).collect();
+        assert!(ids.iter().all(|&id| id != 0));
+        let mut unique = ids.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+    }
+}
@@ -0,0 +1,562 @@
+// Execution traces, written when the program halts. Time is measured in
+// cycles. Code is grouped into label regions: a region runs from a label to
+// the next label in the source, and code before the first label belongs to
+// "(start)".
+//
+// - `ChromeTrace`: the Chrome trace event format, viewable in Perfetto or
+//   chrome://tracing. Each stretch of execution inside one region becomes a
+//   duration event, and each taken jump an instant event.
+// - `FoldedStacks`: cycles per stack of regions in the folded format read by
+//   inferno and flamegraph.pl. A timer handler is stacked on top of the
+//   region it interrupted.
+// - `BinaryTrace`: every instruction and register write in a compact binary
+//   format, written as the program runs, with a keyframe of the whole state
+//   every `KEYFRAME_INTERVAL` cycles and an index of the keyframes at the
+//   end. `TraceReader` uses the index to seek to any cycle by replaying from
+//   the nearest keyframe before it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::emulator::RuntimeError;
+use crate::json;
+use crate::observer::{Observer, Register};
+
+// Maps line indexes to label regions
+struct Regions {
+    // (line index, label) sorted by line index
+    labels: Vec<(usize, String)>,
+}
+
+impl Regions {
+    fn new(labels: &HashMap<String, usize>) -> Self {
+        let mut labels: Vec<(usize, String)> = labels
+            .iter()
+            .map(|(name, &idx)| (idx, name.clone()))
+            .collect();
+        labels.sort();
+        Regions { labels }
+    }
+
+    // Index into `labels` of the region containing `pc`, or `labels.len()`
+    // for code before the first label
+    fn of(&self, pc: usize) -> usize {
+        match self.labels.partition_point(|&(idx, _)| idx <= pc) {
+            0 => self.labels.len(),
+            n => n - 1,
+        }
+    }
+
+    fn name(&self, region: usize) -> &str {
+        self.labels.get(region).map_or("(start)", |(_, name)| name)
+    }
+}
+
+fn write_out(out: &mut impl Write, contents: &str) {
+    if let Err(e) = out
+        .write_all(contents.as_bytes())
+        .and_then(|()| out.flush())
+    {
+        eprintln!("Could not write trace: {e}");
+    }
+}
+
+// Observer that collects a Chrome trace and writes it to `out` on halt
+pub struct ChromeTrace<W: Write + Send> {
+    out: W,
+    regions: Regions,
+    events: Vec<String>,
+    // The region currently executing and the cycle it was entered at
+    current: Option<(usize, u64)>,
+    cycle: u64,
+}
+
+impl<W: Write + Send> ChromeTrace<W> {
+    pub fn new(labels: &HashMap<String, usize>, out: W) -> Self {
+        ChromeTrace {
+            out,
+            regions: Regions::new(labels),
+            events: Vec::new(),
+            current: None,
+            cycle: 0,
+        }
+    }
+
+    fn close_region(&mut self, end: u64) {
+        if let Some((region, start)) = self.current.take() {
+            self.events.push(format!(
+                "{{\"name\":{},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":1}}",
+                json::string(self.regions.name(region)),
+                start,
+                end - start
+            ));
+        }
+    }
+}
+
+impl<W: Write + Send> Observer for ChromeTrace<W> {
+    fn step(&mut self, pc: usize, cycle: u64) {
+        self.cycle = cycle;
+        let region = self.regions.of(pc);
+        if self.current.is_some_and(|(r, _)| r == region) {
+            return;
+        }
+        self.close_region(cycle);
+        self.current = Some((region, cycle));
+    }
+
+    fn jump(&mut self, from: usize, to: usize) {
+        let name = format!("jump to {}", self.regions.name(self.regions.of(to)));
+        self.events.push(format!(
+            "{{\"name\":{},\"ph\":\"i\",\"s\":\"t\",\"ts\":{},\"pid\":1,\"tid\":1,\"args\":{{\"from\":{},\"to\":{}}}}}",
+            json::string(&name),
+            self.cycle,
+            from + 1,
+            to + 1
+        ));
+    }
+
+    fn halt(&mut self, cycles: u64, _error: Option<&RuntimeError>) {
+        self.close_region(cycles);
+        let trace = format!(
+            "{{\"traceEvents\":[{}],\"displayTimeUnit\":\"ns\"}}\n",
+            self.events.join(",")
+        );
+        write_out(&mut self.out, &trace);
+    }
+}
+
+// Observer that collects folded stacks and writes them to `out` on halt
+pub struct FoldedStacks<W: Write + Send> {
+    out: W,
+    regions: Regions,
+    // Regions from the outermost to the one executing, one per nested
+    // interrupt
+    stack: Vec<usize>,
+    // The stack of the instruction executing and the cycle it started at
+    current: Option<(String, u64)>,
+    counts: BTreeMap<String, u64>,
+}
+
+impl<W: Write + Send> FoldedStacks<W> {
+    pub fn new(labels: &HashMap<String, usize>, out: W) -> Self {
+        FoldedStacks {
+            out,
+            regions: Regions::new(labels),
+            stack: Vec::new(),
+            current: None,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    fn finish_instruction(&mut self, end: u64) {
+        if let Some((stack, start)) = self.current.take() {
+            *self.counts.entry(stack).or_default() += end - start;
+        }
+    }
+}
+
+impl<W: Write + Send> Observer for FoldedStacks<W> {
+    fn step(&mut self, pc: usize, cycle: u64) {
+        self.finish_instruction(cycle);
+        let region = self.regions.of(pc);
+        match self.stack.last_mut() {
+            Some(top) => *top = region,
+            None => self.stack.push(region),
+        }
+        let names: Vec<&str> = self.stack.iter().map(|&r| self.regions.name(r)).collect();
+        self.current = Some((names.join(";"), cycle));
+    }
+
+    fn interrupt(&mut self, _from: usize, to: usize) {
+        self.stack.push(self.regions.of(to));
+    }
+
+    fn interrupt_return(&mut self, _from: usize, _to: usize) {
+        if self.stack.len() > 1 {
+            self.stack.pop();
+        }
+    }
+
+    fn halt(&mut self, cycles: u64, _error: Option<&RuntimeError>) {
+        self.finish_instruction(cycles);
+        let folded: String = self.counts.iter().filter(|&(_, &count)| count > 0).fold(
+            String::new(),
+            |mut out, (stack, count)| {
+                let _ = writeln!(out, "{stack} {count}");
+                out
+            },
+        );
+        write_out(&mut self.out, &folded);
+    }
+}
+
+// Binary trace layout, all integers little endian:
+//
+//   header    "TIST", format version (u32)
+//   records   one tag byte each, then varints:
+//               step      pc, cycles since the previous step
+//               keyframe  pc, cycle, acc, bak; also a step
+//               acc, bak  the value written, zigzag encoded
+//               end       no fields, after the last record
+//   index     (cycle, offset) of every keyframe, as u64 pairs
+//   footer    index offset (u64), total cycles (u64), "TISI"
+const TRACE_MAGIC: &[u8; 4] = b"TIST";
+const INDEX_MAGIC: &[u8; 4] = b"TISI";
+const TRACE_VERSION: u32 = 1;
+const FOOTER_LEN: usize = 20;
+
+const TAG_STEP: u8 = 0;
+const TAG_KEYFRAME: u8 = 1;
+const TAG_ACC: u8 = 2;
+const TAG_BAK: u8 = 3;
+const TAG_END: u8 = 4;
+
+// Cycles between keyframes; seeking replays at most this many cycles
+pub const KEYFRAME_INTERVAL: u64 = 4096;
+
+// Observer that streams a binary trace to `out`. Write errors are reported
+// once, on halt.
+pub struct BinaryTrace<W: Write + Send> {
+    out: W,
+    // Bytes written so far, the offset of the next record
+    offset: u64,
+    error: Option<io::Error>,
+    acc: i32,
+    bak: i32,
+    last_cycle: u64,
+    next_keyframe: u64,
+    index: Vec<(u64, u64)>,
+}
+
+impl<W: Write + Send> BinaryTrace<W> {
+    // `acc` and `bak` are the registers before the run
+    pub fn new(acc: i32, bak: i32, out: W) -> Self {
+        let mut trace = BinaryTrace {
+            out,
+            offset: 0,
+            error: None,
+            acc,
+            bak,
+            last_cycle: 0,
+            next_keyframe: 0,
+            index: Vec::new(),
+        };
+        trace.write(TRACE_MAGIC);
+        trace.write(&TRACE_VERSION.to_le_bytes());
+        trace
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.out.write_all(bytes) {
+            Ok(()) => self.offset += bytes.len() as u64,
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    fn record(&mut self, tag: u8, fields: &[u64]) {
+        let mut bytes = vec![tag];
+        for &field in fields {
+            varint(&mut bytes, field);
+        }
+        self.write(&bytes);
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value.to_le_bytes()[0] | 0x80);
+        value >>= 7;
+    }
+    out.push(value.to_le_bytes()[0]);
+}
+
+fn zigzag(value: i32) -> u64 {
+    u64::from(((value << 1) ^ (value >> 31)).cast_unsigned())
+}
+
+// Values too large to have come from `zigzag` saturate
+fn unzigzag(value: u64) -> i32 {
+    let value = u32::try_from(value).unwrap_or(u32::MAX);
+    (value >> 1).cast_signed() ^ -(value & 1).cast_signed()
+}
+
+impl<W: Write + Send> Observer for BinaryTrace<W> {
+    fn step(&mut self, pc: usize, cycle: u64) {
+        if cycle >= self.next_keyframe {
+            self.index.push((cycle, self.offset));
+            self.next_keyframe = cycle + KEYFRAME_INTERVAL;
+            let fields = [pc as u64, cycle, zigzag(self.acc), zigzag(self.bak)];
+            self.record(TAG_KEYFRAME, &fields);
+        } else {
+            self.record(TAG_STEP, &[pc as u64, cycle - self.last_cycle]);
+        }
+        self.last_cycle = cycle;
+    }
+
+    fn register_write(&mut self, _pc: usize, register: Register, _old: i32, new: i32) {
+        match register {
+            // Writes that leave the register as it was need no record
+            Register::Acc if new == self.acc => {}
+            Register::Bak if new == self.bak => {}
+            Register::Acc => {
+                self.acc = new;
+                self.record(TAG_ACC, &[zigzag(new)]);
+            }
+            Register::Bak => {
+                self.bak = new;
+                self.record(TAG_BAK, &[zigzag(new)]);
+            }
+        }
+    }
+
+    fn halt(&mut self, cycles: u64, _error: Option<&RuntimeError>) {
+        self.record(TAG_END, &[]);
+        let index_offset = self.offset;
+        let mut bytes = Vec::with_capacity(self.index.len() * 16 + FOOTER_LEN);
+        for &(cycle, offset) in &self.index {
+            bytes.extend_from_slice(&cycle.to_le_bytes());
+            bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+        bytes.extend_from_slice(&index_offset.to_le_bytes());
+        bytes.extend_from_slice(&cycles.to_le_bytes());
+        bytes.extend_from_slice(INDEX_MAGIC);
+        self.write(&bytes);
+        if let Some(e) = self.error.take().or_else(|| self.out.flush().err()) {
+            eprintln!("Could not write trace: {e}");
+        }
+    }
+}
+
+// The machine as an instruction in a binary trace started: its line index,
+// the cycle it started at and the registers before it ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub cycle: u64,
+    pub pc: usize,
+    pub acc: i32,
+    pub bak: i32,
+}
+
+// A binary trace read back for seeking
+pub struct TraceReader {
+    data: Vec<u8>,
+    // (cycle, offset) of each keyframe, in cycle order
+    index: Vec<(u64, u64)>,
+    // Where the records end and the index starts
+    records_end: usize,
+    pub cycles: u64,
+}
+
+fn u64_at(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+impl TraceReader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<TraceReader> {
+        TraceReader::from_bytes(fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<TraceReader, String> {
+        let invalid = || "Not a binary trace, or it was cut short".to_string();
+        if data.len() < 8 + FOOTER_LEN
+            || &data[..4] != TRACE_MAGIC
+            || data[data.len() - 4..] != INDEX_MAGIC[..]
+        {
+            return Err(invalid());
+        }
+        let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        if version != TRACE_VERSION {
+            return Err(format!("Unsupported trace version {version}"));
+        }
+        let footer = data.len() - FOOTER_LEN;
+        let records_end = u64_at(&data, footer)
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or_else(invalid)?;
+        let cycles = u64_at(&data, footer + 8).ok_or_else(invalid)?;
+        if records_end > footer || !(footer - records_end).is_multiple_of(16) {
+            return Err(invalid());
+        }
+        let index = (records_end..footer)
+            .step_by(16)
+            .map(|pos| Some((u64_at(&data, pos)?, u64_at(&data, pos + 8)?)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        Ok(TraceReader {
+            data,
+            index,
+            records_end,
+            cycles,
+        })
+    }
+
+    // The instruction running at `cycle`: the last one that started at or
+    // before it. `None` if the trace has no instructions.
+    pub fn seek(&self, cycle: u64) -> Result<Option<Snapshot>, String> {
+        let Some(keyframe) = self
+            .index
+            .partition_point(|&(c, _)| c <= cycle)
+            .checked_sub(1)
+        else {
+            return Ok(None);
+        };
+        let malformed = || "Trace record is malformed".to_string();
+        let int = |value: u64| usize::try_from(value).map_err(|_| malformed());
+        let mut pos = int(self.index[keyframe].1)?;
+        let mut varint = || -> Result<u64, String> {
+            let mut value = 0u64;
+            for shift in (0..64).step_by(7) {
+                let Some(&byte) = self.data[..self.records_end].get(pos) else {
+                    return Err("Trace record is cut short".to_string());
+                };
+                pos += 1;
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte & 0x80 == 0 {
+                    return Ok(value);
+                }
+            }
+            Err(malformed())
+        };
+        let mut current: Option<Snapshot> = None;
+        let (mut acc, mut bak, mut last_cycle) = (0, 0, 0);
+        loop {
+            let tag = varint()?;
+            let (pc, start) = match u8::try_from(tag).unwrap_or(u8::MAX) {
+                TAG_STEP => {
+                    let pc = int(varint()?)?;
+                    (pc, last_cycle + varint()?)
+                }
+                TAG_KEYFRAME => {
+                    let pc = int(varint()?)?;
+                    let start = varint()?;
+                    acc = unzigzag(varint()?);
+                    bak = unzigzag(varint()?);
+                    (pc, start)
+                }
+                TAG_ACC => {
+                    acc = unzigzag(varint()?);
+                    continue;
+                }
+                TAG_BAK => {
+                    bak = unzigzag(varint()?);
+                    continue;
+                }
+                TAG_END => return Ok(current),
+                _ => return Err(format!("Unknown trace record {tag}")),
+            };
+            if start > cycle {
+                return Ok(current);
+            }
+            last_cycle = start;
+            current = Some(Snapshot {
+                cycle: start,
+                pc,
+                acc,
+                bak,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::emulator::Emulator;
+
+    // A writer whose bytes can be read after the emulator owning it is done
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Counts acc down twice, saving to bak on the way, for more cycles than
+    // one keyframe interval covers
+    const PROGRAM: &[&str] = &[
+        "mov 999, acc",
+        "a: save",
+        "add -1",
+        "jgz a",
+        "",
+        "mov 999, acc",
+        "b: add -1",
+        "jgz b",
+    ];
+
+    // The program traced, and the snapshot taken as each instruction started
+    fn traced(source: &[&str]) -> (Vec<u8>, Vec<Snapshot>) {
+        let buffer = Buffer::default();
+        let mut emu = Emulator::new();
+        emu.load_program(source).unwrap();
+        emu.add_observer(Box::new(BinaryTrace::new(0, 0, buffer.clone())));
+        let mut snapshots = Vec::new();
+        loop {
+            let (cycle, acc, bak) = (emu.cycles, emu.acc, emu.bak);
+            match emu.step().unwrap() {
+                Some(pc) => snapshots.push(Snapshot {
+                    cycle,
+                    pc,
+                    acc,
+                    bak,
+                }),
+                None => break,
+            }
+        }
+        let bytes = buffer.0.lock().unwrap().clone();
+        (bytes, snapshots)
+    }
+
+    #[test]
+    fn seeks_to_every_cycle() {
+        let (bytes, snapshots) = traced(PROGRAM);
+        let last = *snapshots.last().unwrap();
+        assert!(last.cycle > KEYFRAME_INTERVAL);
+        let reader = TraceReader::from_bytes(bytes).unwrap();
+        assert_eq!(reader.cycles, last.cycle + 1);
+        for snapshot in &snapshots {
+            assert_eq!(reader.seek(snapshot.cycle).unwrap(), Some(*snapshot));
+        }
+        assert_eq!(reader.seek(u64::MAX).unwrap(), Some(last));
+    }
+
+    #[test]
+    fn an_empty_run_has_nothing_to_seek() {
+        let (bytes, snapshots) = traced(&["# nothing to run"]);
+        assert!(snapshots.is_empty());
+        let reader = TraceReader::from_bytes(bytes).unwrap();
+        assert_eq!(reader.cycles, 0);
+        assert_eq!(reader.seek(0).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_a_cut_short_trace() {
+        let (mut bytes, _) = traced(PROGRAM);
+        bytes.pop();
+        assert!(TraceReader::from_bytes(bytes).is_err());
+        assert!(TraceReader::from_bytes(b"TIST".to_vec()).is_err());
+    }
+
+    #[test]
+    fn zigzag_round_trips() {
+        for value in [0, 1, -1, 999, -999, i32::MAX, i32::MIN] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+}
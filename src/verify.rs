@@ -0,0 +1,435 @@
+// Exhaustive verification. For programs whose input is small enough to
+// enumerate, every input stream of the given lengths over a range of values
+// is run and its output compared against a reference: a comma-separated list
+// of integer expressions, one per expected output value.
+//
+// Expressions are built from integers, the inputs `x0`, `x1`, ..., the input
+// length `n` and their `sum`, with `+ - * / %`, parentheses and the
+// functions `abs(e)`, `min(a, b)` and `max(a, b)`. Division truncates toward
+// zero, as in Rust.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::emulator::{Emulator, HaltReason, InputEnd};
+use crate::parser::Diagnostic;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Input(usize),
+    Length,
+    Sum,
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Abs,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "abs" => Some(Function::Abs),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            _ => None,
+        }
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Function::Abs => 1,
+            Function::Min | Function::Max => 2,
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, inputs: &[i32]) -> Result<i64, String> {
+        let overflow = || "Reference overflows".to_string();
+        Ok(match self {
+            Expr::Number(value) => *value,
+            Expr::Input(idx) => match inputs.get(*idx) {
+                Some(&value) => i64::from(value),
+                None => {
+                    return Err(format!(
+                        "Reference reads x{} but there are only {} input(s)",
+                        idx,
+                        inputs.len()
+                    ));
+                }
+            },
+            Expr::Length => i64::try_from(inputs.len()).map_err(|_| overflow())?,
+            Expr::Sum => inputs.iter().map(|&v| i64::from(v)).sum(),
+            Expr::Neg(e) => e.eval(inputs)?.checked_neg().ok_or_else(overflow)?,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(inputs)?, b.eval(inputs)?);
+                match op {
+                    '+' => a.checked_add(b),
+                    '-' => a.checked_sub(b),
+                    '*' => a.checked_mul(b),
+                    '/' | '%' if b == 0 => return Err("Reference divides by zero".to_string()),
+                    '/' => a.checked_div(b),
+                    _ => a.checked_rem(b),
+                }
+                .ok_or_else(overflow)?
+            }
+            Expr::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|e| e.eval(inputs))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match function {
+                    Function::Abs => args[0].checked_abs().ok_or_else(overflow)?,
+                    Function::Min => args[0].min(args[1]),
+                    Function::Max => args[0].max(args[1]),
+                }
+            }
+        })
+    }
+
+    // The highest input this reads, if any
+    fn max_input(&self) -> Option<usize> {
+        match self {
+            Expr::Input(idx) => Some(*idx),
+            Expr::Number(_) | Expr::Length | Expr::Sum => None,
+            Expr::Neg(e) => e.max_input(),
+            Expr::Binary(_, a, b) => a.max_input().max(b.max_input()),
+            Expr::Call(_, args) => args.iter().filter_map(Expr::max_input).max(),
+        }
+    }
+}
+
+// Recursive descent over the reference text
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().find(|c| !c.is_whitespace())
+    }
+
+    // Take the next character, skipping whitespace, if it is `c`
+    fn eat(&mut self, c: char) -> bool {
+        let rest = &self.text[self.pos..];
+        let trimmed = rest.trim_start();
+        if trimmed.starts_with(c) {
+            self.pos += rest.len() - trimmed.len() + c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("{} at column {}", message, self.pos + 1))
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.eat(op);
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.eat(op);
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat('(') {
+            let expr = self.expr()?;
+            if !self.eat(')') {
+                return self.error("Expected `)`");
+            }
+            return Ok(expr);
+        }
+        let rest = self.text[self.pos..].trim_start();
+        self.pos = self.text.len() - rest.len();
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        if word.is_empty() {
+            return self.error("Expected a value");
+        }
+        let start = self.pos;
+        self.pos += len;
+        if let Ok(value) = word.parse() {
+            return Ok(Expr::Number(value));
+        }
+        if let Some(idx) = word.strip_prefix('x').and_then(|d| d.parse().ok()) {
+            return Ok(Expr::Input(idx));
+        }
+        match word {
+            "n" => return Ok(Expr::Length),
+            "sum" => return Ok(Expr::Sum),
+            _ => {}
+        }
+        let Some(function) = Function::from_name(word) else {
+            self.pos = start;
+            return self.error(&format!("Unknown name `{word}`"));
+        };
+        if !self.eat('(') {
+            return self.error(&format!("Expected `(` after {word}"));
+        }
+        let mut args = vec![self.expr()?];
+        while self.eat(',') {
+            args.push(self.expr()?);
+        }
+        if !self.eat(')') {
+            return self.error("Expected `)`");
+        }
+        if args.len() != function.arity() {
+            return self.error(&format!(
+                "{} takes {} argument(s), not {}",
+                word,
+                function.arity(),
+                args.len()
+            ));
+        }
+        Ok(Expr::Call(function, args))
+    }
+}
+
+// The expected output of a program, as one expression per value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    outputs: Vec<Expr>,
+}
+
+impl Reference {
+    // Parse a comma-separated list of expressions; an empty or blank `text`
+    // expects no output
+    pub fn parse(text: &str) -> Result<Reference, String> {
+        let mut parser = ExprParser { text, pos: 0 };
+        let mut outputs = Vec::new();
+        if parser.peek().is_some() {
+            outputs.push(parser.expr()?);
+            while parser.eat(',') {
+                outputs.push(parser.expr()?);
+            }
+        }
+        if parser.peek().is_some() {
+            return parser.error("Expected `,` or the end of the reference");
+        }
+        Ok(Reference { outputs })
+    }
+
+    // How many inputs every stream needs for the reference to be defined
+    pub fn inputs_needed(&self) -> usize {
+        self.outputs
+            .iter()
+            .filter_map(Expr::max_input)
+            .max()
+            .map_or(0, |idx| idx + 1)
+    }
+
+    // The outputs expected for `inputs`
+    pub fn expected(&self, inputs: &[i32]) -> Result<Vec<i64>, String> {
+        self.outputs.iter().map(|e| e.eval(inputs)).collect()
+    }
+}
+
+// How a run went wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    // The program's output differs from the reference's
+    Output(Vec<i32>),
+    // The program hit a runtime error
+    Error(String),
+    // The program did not finish within the cycle budget
+    Budget,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub inputs: Vec<i32>,
+    pub expected: Vec<i64>,
+    pub mismatch: Mismatch,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "input {:?}: expected {:?}, ", self.inputs, self.expected)?;
+        match &self.mismatch {
+            Mismatch::Output(output) => write!(f, "got {output:?}"),
+            Mismatch::Error(message) => write!(f, "got error: {message}"),
+            Mismatch::Budget => write!(f, "did not finish"),
+        }
+    }
+}
+
+// The outcome of checking every input stream
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub runs: u64,
+    pub failures: Vec<Failure>,
+}
+
+// Run the program `lines` on every stream of `lengths` values from `domain`
+// and compare each output against `reference`. The program is loaded once
+// and the machine reset between streams. A run reading past the end of
+// its stream halts there; runs over `budget` cycles fail. Fails if the
+// program does not load or the reference is undefined for some stream.
+pub fn exhaustive<S: AsRef<str>>(
+    lines: &[S],
+    domain: &[i32],
+    lengths: &[usize],
+    reference: &Reference,
+    budget: u64,
+) -> Result<Verification, Vec<Diagnostic>> {
+    let mut verification = Verification::default();
+    let mut emu = Emulator::new();
+    emu.limits.max_cycles = Some(budget);
+    emu.on_input_end = InputEnd::Halt;
+    emu.load_program(lines)?;
+    for &length in lengths {
+        // Every stream of this length, the last value varying fastest
+        let mut digits = vec![0; length];
+        'streams: loop {
+            if !domain.is_empty() || length == 0 {
+                let inputs: Vec<i32> = digits.iter().map(|&d| domain[d]).collect();
+                let expected = reference.expected(&inputs).map_err(|e| {
+                    let message = format!("{e} for input {inputs:?}");
+                    vec![Diagnostic::error("reference", message, None)]
+                })?;
+                emu.reset();
+                emu.set_input(inputs.iter().copied().collect::<VecDeque<i32>>());
+                let mismatch = match emu.run() {
+                    Err(e) => Some(Mismatch::Error(format!("line {}: {}", e.line, e.message))),
+                    Ok(()) if emu.halt_reason() == Some(HaltReason::CycleLimit) => {
+                        Some(Mismatch::Budget)
+                    }
+                    Ok(()) => {
+                        let output = emu.output();
+                        let matches = output.len() == expected.len()
+                            && output
+                                .iter()
+                                .zip(&expected)
+                                .all(|(&a, &e)| i64::from(a) == e);
+                        (!matches).then(|| Mismatch::Output(output.to_vec()))
+                    }
+                };
+                verification.runs += 1;
+                if let Some(mismatch) = mismatch {
+                    verification.failures.push(Failure {
+                        inputs,
+                        expected,
+                        mismatch,
+                    });
+                }
+            }
+            for pos in (0..length).rev() {
+                digits[pos] += 1;
+                if digits[pos] < domain.len() {
+                    continue 'streams;
+                }
+                digits[pos] = 0;
+            }
+            break;
+        }
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, inputs: &[i32]) -> Vec<i64> {
+        Reference::parse(text).unwrap().expected(inputs).unwrap()
+    }
+
+    #[test]
+    fn follows_precedence_and_associativity() {
+        assert_eq!(eval("1 + 2 * 3", &[]), [7]);
+        assert_eq!(eval("(1 + 2) * 3", &[]), [9]);
+        assert_eq!(eval("10 - 4 - 3", &[]), [3]);
+        assert_eq!(eval("-2 * -3, --4", &[]), [6, 4]);
+    }
+
+    #[test]
+    fn division_truncates_toward_zero() {
+        assert_eq!(eval("-7 / 2, -7 % 2, 7 / -2", &[]), [-3, -1, -3]);
+    }
+
+    #[test]
+    fn reads_inputs_and_functions() {
+        let inputs = [4, -9, 2];
+        assert_eq!(
+            eval(
+                "x0 + x2, n, sum, abs(x1), min(x0, x1), max(x0, x1 * -1)",
+                &inputs
+            ),
+            [6, 3, -3, 9, -9, 9]
+        );
+        assert_eq!(Reference::parse("x2 - x0, sum").unwrap().inputs_needed(), 3);
+        assert_eq!(Reference::parse(" ").unwrap().inputs_needed(), 0);
+        assert_eq!(eval("", &inputs), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn reports_errors_with_a_column() {
+        let error = |text| Reference::parse(text).unwrap_err();
+        assert_eq!(error("1 +"), "Expected a value at column 4");
+        assert_eq!(error("(1 + 2"), "Expected `)` at column 7");
+        assert_eq!(error("2 * y"), "Unknown name `y` at column 5");
+        assert_eq!(
+            error("min(1)"),
+            "min takes 2 argument(s), not 1 at column 7"
+        );
+        assert_eq!(
+            error("1 2"),
+            "Expected `,` or the end of the reference at column 2"
+        );
+    }
+
+    #[test]
+    fn evaluation_errors_are_reported() {
+        let reference = Reference::parse("x0 / x1").unwrap();
+        assert_eq!(
+            reference.expected(&[1, 0]).unwrap_err(),
+            "Reference divides by zero"
+        );
+        assert_eq!(
+            reference.expected(&[1]).unwrap_err(),
+            "Reference reads x1 but there are only 1 input(s)"
+        );
+    }
+
+    #[test]
+    fn checks_every_stream() {
+        let program = ["in acc", "save", "in acc", "add bak", "out acc"];
+        let sum = Reference::parse("x0 + x1").unwrap();
+        let verification = exhaustive(&program, &[-1, 0, 1], &[2], &sum, 100).unwrap();
+        assert_eq!(verification.runs, 9);
+        assert!(verification.failures.is_empty());
+        let product = Reference::parse("x0 * x1").unwrap();
+        let verification = exhaustive(&program, &[-1, 0, 1], &[2], &product, 100).unwrap();
+        assert_eq!(verification.failures.len(), 8);
+        assert_eq!(verification.failures[0].inputs, [-1, -1]);
+        assert_eq!(
+            verification.failures[0].mismatch,
+            Mismatch::Output(vec![-2])
+        );
+    }
+}
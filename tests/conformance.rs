@@ -0,0 +1,28 @@
+// Runs the conformance suite as part of `cargo test`, so a change in the
+// interpreter's semantics fails the build until the cases are re-blessed with
+// `tis31337 conformance --bless`
+
+use std::path::Path;
+
+use tis31337::corpus::{self, Status};
+
+#[test]
+fn conformance_suite_passes() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("conformance");
+    let results = corpus::run(&dir, false, true).unwrap();
+    assert!(!results.is_empty(), "no cases in {}", dir.display());
+    let failures: Vec<String> = results
+        .iter()
+        .filter(|r| r.failed())
+        .map(|r| match &r.status {
+            Status::Fail { expected, actual } => format!(
+                "{}\nexpected:\n{}actual:\n{}",
+                r.path.display(),
+                expected,
+                actual
+            ),
+            _ => format!("{}: no .expected file", r.path.display()),
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}